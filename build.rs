@@ -1,7 +1,25 @@
-fn main() {
-    let output = std::process::Command::new("git")
+use std::process::Command;
+
+// Figure out which commit we're being built from. Builds from a release tarball, or on a machine
+// without git, can't ask git, so fall back to a '.git-hash' file (which packagers can create),
+// and failing that, just call it "unknown".
+fn git_hash() -> String {
+    let from_git = Command::new("git")
         .args(["rev-parse", "HEAD"])
         .output()
-        .expect("failed to run 'git rev-parse HEAD'");
-    println!("cargo:rustc-env=GIT_HASH={}", std::str::from_utf8(&output.stdout).unwrap());
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok());
+
+    let from_file = || std::fs::read_to_string(".git-hash").ok();
+
+    from_git
+        .or_else(from_file)
+        .map(|hash| hash.trim().to_owned())
+        .filter(|hash| !hash.is_empty())
+        .unwrap_or_else(|| "unknown".to_owned())
+}
+
+fn main() {
+    println!("cargo:rustc-env=GIT_HASH={}", git_hash());
 }
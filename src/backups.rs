@@ -4,15 +4,34 @@
 //
 
 use std::collections::btree_map::{BTreeMap, Entry, IterMut};
+use std::path::PathBuf;
 
+// With the 'serde' feature, these serialize with their field names as-is. Unset snapshots are
+// written as null, and may be either null or left out when reading.
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Backup {
     pub filename_base: String,
     pub volume: String,
+    #[cfg_attr(feature = "serde", serde(default))]
     pub start_snapshot: Option<String>,
+    #[cfg_attr(feature = "serde", serde(default))]
     pub end_snapshot: Option<String>,
+
+    /// Encrypt this backup to this gpg key (a key ID, fingerprint, or email address) rather than
+    /// with the passphrase used for the rest of the batch.
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub recipient: Option<String>,
+
+    /// Read the passphrase for this backup from the first line of this file, rather than using
+    /// the one used for the rest of the batch.
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub passphrase_file: Option<PathBuf>,
 }
 
+/// Serializes as a map from volume name to `Backup`.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
 pub struct Backups {
     backups_by_volume: BTreeMap<String, Backup>,
 }
@@ -59,6 +78,8 @@ impl Backups {
                     volume,
                     start_snapshot,
                     end_snapshot: None,
+                    recipient: None,
+                    passphrase_file: None,
                 });
             }
         }
@@ -79,3 +100,29 @@ impl Backups {
         BackupsIterMut { iter_mut: self.backups_by_volume.iter_mut() }
     }
 }
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde() {
+    let backup: Backup = serde_json::from_str(
+        r#"{"filename_base": "tank_data", "volume": "tank/data", "end_snapshot": "2021-01-02"}"#)
+        .unwrap();
+    assert_eq!(backup.start_snapshot, None);
+    assert_eq!(backup.end_snapshot.as_deref(), Some("2021-01-02"));
+
+    let json = serde_json::to_string(&backup).unwrap();
+    assert_eq!(json, r#"{"filename_base":"tank_data","volume":"tank/data","start_snapshot":null,"end_snapshot":"2021-01-02"}"#);
+
+    let mut backups = Backups::new();
+    backups.insert("tank_data".to_owned(), "tank/data".to_owned(), Some("2021-01-01".to_owned()));
+    let json = serde_json::to_string(&backups).unwrap();
+    assert_eq!(json, r#"{"tank/data":{"filename_base":"tank_data","volume":"tank/data","start_snapshot":"2021-01-01","end_snapshot":null}}"#);
+    let backups: Backups = serde_json::from_str(&json).unwrap();
+    assert_eq!(backups.backups_by_volume["tank/data"].start_snapshot.as_deref(), Some("2021-01-01"));
+
+    let backup: Backup = serde_json::from_str(
+        r#"{"filename_base": "tank_data", "volume": "tank/data", "recipient": "ops@example.com"}"#)
+        .unwrap();
+    assert_eq!(backup.recipient.as_deref(), Some("ops@example.com"));
+    assert_eq!(backup.passphrase_file, None);
+}
@@ -1,8 +1,10 @@
 use ring::digest::*;
 
+use std::cmp;
 use std::fs::File;
 use std::io::{self, Read, Write};
-use std::path::Path;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
 
 #[cfg(target_pointer_width = "64")]
@@ -32,90 +34,401 @@ impl AtomicU64 {
 #[cfg(not(target_pointer_width = "64"))]
 pub type AtomicU64 = !; // until the proper AtomicU64 type is stable, this needs to be compiled 64-bit.
 
+/// The default size of the buffer used to copy a stream into a file.
+pub const DEFAULT_BUFFER_SIZE: usize = 8192;
+
+/// A writer which passes everything through to an inner writer, hashing it on the way with any
+/// number of algorithms. With none, it's just a pass-through.
 pub struct HashingWrite<T: Write> {
-    ctx: Context,
+    ctxs: Vec<(&'static Algorithm, Context)>,
     inner: T,
 }
 
 impl<T: Write> HashingWrite<T> {
     pub fn new(inner: T, algo: &'static Algorithm) -> HashingWrite<T> {
+        Self::with_algorithms(inner, &[algo])
+    }
+
+    pub fn with_algorithms(inner: T, algos: &[&'static Algorithm]) -> HashingWrite<T> {
         HashingWrite {
-            ctx: Context::new(algo),
+            ctxs: algos.iter().map(|algo| (*algo, Context::new(algo))).collect(),
             inner,
         }
     }
 
+    /// The digest from the first algorithm. Panics if there were none.
     pub fn finish(self) -> Vec<u8> {
-        self.ctx.finish().as_ref().to_vec()
+        self.finish_all().swap_remove(0)
+    }
+
+    /// The digests from every algorithm, in the order they were given.
+    pub fn finish_all(self) -> Vec<Vec<u8>> {
+        self.ctxs.into_iter()
+            .map(|(_, ctx)| ctx.finish().as_ref().to_vec())
+            .collect()
     }
 }
 
-pub fn write_file_and_sidecar<R: Read>(
+/// Copy everything from `input` to `output`, returning the hex digest of what was copied.
+pub fn copy_hashed<R: Read, W: Write>(
     input: &mut R,
-    path: &Path,
-    sidecar_path: &Path,
+    output: W,
     algo: &'static Algorithm,
+    buffer_size: usize,
     progress: &AtomicU64,
-    ) -> Result<(), String>
+    ) -> Result<String, String>
 {
-    /*
-    let mut hash_filename = path.file_name().unwrap().to_os_string();
-    hash_filename.push(suffix);
-    let hash_file_path = path.with_file_name(hash_filename);
-    */
+    Ok(copy_hashed_multi(input, output, &[algo], buffer_size, progress)?.swap_remove(0))
+}
 
-    let out = File::create(path).map_err(|e| format!("failed to create {:?}: {}", path, e))?;
-    let mut hash_out = HashingWrite::new(out, algo);
+/// Like `copy_hashed`, but computes a digest with each of the given algorithms in the same pass,
+/// returning them in the same order.
+pub fn copy_hashed_multi<R: Read, W: Write>(
+    input: &mut R,
+    output: W,
+    algos: &[&'static Algorithm],
+    buffer_size: usize,
+    progress: &AtomicU64,
+    ) -> Result<Vec<String>, String>
+{
+    let mut hash_out = HashingWrite::with_algorithms(output, algos);
 
-    let mut buf = [0u8; 8192];
+    let mut buf = vec![0u8; buffer_size];
     loop {
         match input.read(&mut buf) {
             Ok(0) => break,
             Ok(nread) => {
                 progress.fetch_add(nread as u64, Ordering::Relaxed);
-                match hash_out.write(&buf[0..nread]) {
-                    Ok(nwritten) => {
-                        if nwritten != nread {
-                            let msg = format!("nwritten({}) != nread({})", nwritten, nread);
-                            return Err(msg);
-                        }
-                    },
-                    Err(e) => {
-                        return Err(format!("write error: {}", e));
-                    }
+                if let Err(e) = hash_out.write_all(&buf[0..nread]) {
+                    return Err(format!("write error: {}", e));
                 }
             },
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
             Err(e) => {
                 return Err(format!("read error: {}", e));
             }
         }
     }
 
-    let hash: String = hash_out.finish().iter()
-        .fold(String::new(), |s, byte| s + &format!("{:02x}", byte));
+    Ok(hash_out.finish_all().iter().map(|hash| to_hex(hash)).collect())
+}
 
-    let mut sidecar_file = match File::create(sidecar_path) {
-        Ok(f) => f,
-        Err(e) => {
-            let msg = format!("failed to create sidecar {:?}: {}", sidecar_path, e);
-            return Err(msg);
-        }
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter()
+        .fold(String::new(), |s, byte| s + &format!("{:02x}", byte))
+}
+
+/// Hash a file which was just written, with each of the given algorithms. It's flushed to disk
+/// and dropped from the page cache first, so that (as far as the OS allows) what's hashed is what
+/// actually landed on the disk, not what's still in memory.
+pub fn hash_file_from_disk(path: &Path, algos: &[&'static Algorithm], buffer_size: usize)
+    -> Result<Vec<String>, String>
+{
+    let mut file = File::open(path).map_err(|e| format!("failed to open {:?}: {}", path, e))?;
+    file.sync_all().map_err(|e| format!("failed to sync {:?}: {}", path, e))?;
+    // This is only advice, so if it fails, the hash just comes from the cache.
+    unsafe {
+        libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED);
+    }
+    copy_hashed_multi(&mut file, io::sink(), algos, buffer_size, &AtomicU64::new(0))
+}
+
+/// Files which `write_files_and_sidecars` gave up on: their index, and what went wrong.
+pub type FailedFiles = Vec<(usize, String)>;
+
+/// Copy `input` to each of the given files, hashing it with each of the given algorithms in one
+/// pass, and write each hex digest to the file's corresponding sidecar path. Returns the digests
+/// in the same order as the algorithms. With no algorithms, the files are just copied.
+///
+/// If `keep_going` is set, a file which can't be written is given up on (and gets no sidecars)
+/// as long as there's still another one going, and is returned along with the digests.
+/// Otherwise, or if they all fail, the whole thing fails.
+pub fn write_files_and_sidecars<R: Read>(
+    input: &mut R,
+    files: &[(&Path, Vec<&Path>)],
+    algos: &[&'static Algorithm],
+    keep_going: bool,
+    buffer_size: usize,
+    progress: &AtomicU64,
+    ) -> Result<(Vec<String>, FailedFiles), String>
+{
+    let mut tee = Tee {
+        outputs: vec![],
+        keep_going,
+        failed: vec![],
     };
+    for (path, _) in files {
+        let file = File::create(path).map_err(|e| format!("failed to create {:?}: {}", path, e))?;
+        tee.outputs.push(Some((path.to_path_buf(), file)));
+    }
+    let hashes = copy_hashed_multi(input, &mut tee, algos, buffer_size, progress)?;
+
+    for (i, (_, sidecar_paths)) in files.iter().enumerate() {
+        if tee.outputs[i].is_none() {
+            continue;
+        }
+        for (sidecar_path, hash) in sidecar_paths.iter().zip(&hashes) {
+            let mut sidecar_file = match File::create(sidecar_path) {
+                Ok(f) => f,
+                Err(e) => {
+                    let msg = format!("failed to create sidecar {:?}: {}", sidecar_path, e);
+                    return Err(msg);
+                }
+            };
+
+            if let Err(e) = sidecar_file.write_all(hash.as_bytes()) {
+                return Err(format!("failed to write hash sidecar {:?}: {}", sidecar_path, e));
+            }
+        }
+    }
+
+    Ok((hashes, tee.failed))
+}
+
+// Writes everything to each of several files.
+struct Tee {
+    outputs: Vec<Option<(PathBuf, File)>>,
+    keep_going: bool,
+    failed: FailedFiles,
+}
+
+impl Write for Tee {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for (i, output) in self.outputs.iter_mut().enumerate() {
+            if let Some((path, file)) = output {
+                if let Err(e) = file.write_all(buf) {
+                    let msg = format!("failed to write to {:?}: {}", path, e);
+                    if !self.keep_going {
+                        return Err(io::Error::new(e.kind(), msg));
+                    }
+                    self.failed.push((i, msg));
+                    *output = None;
+                }
+            }
+        }
+        if self.outputs.iter().all(Option::is_none) {
+            let msgs: Vec<&str> = self.failed.iter().map(|(_, msg)| msg.as_str()).collect();
+            return Err(io::Error::new(io::ErrorKind::Other, msgs.join("; ")));
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for (path, file) in self.outputs.iter_mut().flatten() {
+            file.flush().map_err(|e| {
+                io::Error::new(e.kind(), format!("failed to write to {:?}: {}", path, e))
+            })?;
+        }
+        Ok(())
+    }
+}
 
-    if let Err(e) = sidecar_file.write_all(hash.as_bytes()) {
-        return Err(format!("failed to write hash sidecar {:?}: {}", sidecar_path, e));
+/// The path and hex digest of each chunk written by a `SplitWriter`.
+pub type ChunkHashes = Vec<(PathBuf, String)>;
+
+/// A writer which splits its output over numbered chunk files of at most `chunk_size` bytes,
+/// hashing each one separately.
+pub struct SplitWriter<F: Fn(usize) -> PathBuf> {
+    chunk_path: F,
+    chunk_size: u64,
+    algo: &'static Algorithm,
+    current: Option<(PathBuf, HashingWrite<File>, u64)>,
+    chunks: ChunkHashes,
+}
+
+impl<F: Fn(usize) -> PathBuf> SplitWriter<F> {
+    /// `chunk_path` gives the path for the chunk with the given (zero-based) index.
+    pub fn new(chunk_path: F, chunk_size: u64, algo: &'static Algorithm) -> Self {
+        assert!(chunk_size > 0);
+        Self {
+            chunk_path,
+            chunk_size,
+            algo,
+            current: None,
+            chunks: vec![],
+        }
+    }
+
+    fn finish_chunk(&mut self) {
+        if let Some((path, hash_out, _)) = self.current.take() {
+            self.chunks.push((path, to_hex(&hash_out.finish())));
+        }
+    }
+
+    fn next_chunk(&mut self) -> io::Result<()> {
+        self.finish_chunk();
+        let path = (self.chunk_path)(self.chunks.len());
+        let file = File::create(&path)?;
+        self.current = Some((path, HashingWrite::new(file, self.algo), 0));
+        Ok(())
+    }
+
+    /// Finish writing, returning the path and hex digest of each chunk. There is always at least
+    /// one chunk, even if nothing was written.
+    pub fn finish(mut self) -> io::Result<ChunkHashes> {
+        if self.current.is_none() && self.chunks.is_empty() {
+            self.next_chunk()?;
+        }
+        self.finish_chunk();
+        Ok(self.chunks)
+    }
+}
+
+impl<F: Fn(usize) -> PathBuf> Write for SplitWriter<F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let chunk_full = match self.current {
+            Some((_, _, written)) => written >= self.chunk_size,
+            None => true,
+        };
+        if chunk_full {
+            self.next_chunk()?;
+        }
+        let (_, hash_out, written) = self.current.as_mut().unwrap();
+        let len = cmp::min(buf.len() as u64, self.chunk_size - *written) as usize;
+        let nwritten = hash_out.write(&buf[0..len])?;
+        *written += nwritten as u64;
+        Ok(nwritten)
     }
 
-    Ok(())
+    fn flush(&mut self) -> io::Result<()> {
+        match self.current {
+            Some((_, ref mut hash_out, _)) => hash_out.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Copy `input` into chunk files (see `SplitWriter`). Returns the hex digests of the whole stream
+/// (one per algorithm given), and the path and digest of each chunk (with the first algorithm).
+pub fn write_split_files<R: Read, F: Fn(usize) -> PathBuf>(
+    input: &mut R,
+    chunk_path: F,
+    chunk_size: u64,
+    algos: &[&'static Algorithm],
+    buffer_size: usize,
+    progress: &AtomicU64,
+    ) -> Result<(Vec<String>, ChunkHashes), String>
+{
+    let mut split = SplitWriter::new(chunk_path, chunk_size, algos[0]);
+    let hashes = copy_hashed_multi(input, &mut split, algos, buffer_size, progress)?;
+    let chunks = split.finish().map_err(|e| format!("failed to write chunk: {}", e))?;
+    Ok((hashes, chunks))
 }
 
 impl<T: Write> Write for HashingWrite<T> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.ctx.update(buf);
-        self.inner.write(buf)
+        // Only hash what was actually written; the caller will retry the rest.
+        let nwritten = self.inner.write(buf)?;
+        for (_, ctx) in &mut self.ctxs {
+            ctx.update(&buf[0..nwritten]);
+        }
+        Ok(nwritten)
     }
 
     fn flush(&mut self) -> io::Result<()> {
         self.inner.flush()
     }
 }
+
+#[test]
+fn test_hashing_write_short_writes() {
+    // A writer which only accepts a few bytes at a time.
+    struct Trickle(Vec<u8>);
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = buf.len().min(3);
+            self.0.extend_from_slice(&buf[0..n]);
+            Ok(n)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let data = b"the quick brown fox jumps over the lazy dog";
+    let mut hash_out = HashingWrite::new(Trickle(vec![]), &SHA256);
+    hash_out.write_all(data).unwrap();
+    assert_eq!(&hash_out.inner.0[..], &data[..]);
+    assert_eq!(hash_out.finish(), digest(&SHA256, data).as_ref().to_vec());
+}
+
+#[test]
+fn test_write_files_and_sidecars() {
+    let dir = crate::TestDir::new("sidecars");
+    let path = dir.join("out");
+    let sha256_path = dir.join("out.sha256sum");
+    let sha512_path = dir.join("out.sha512sum");
+
+    let data = b"the quick brown fox jumps over the lazy dog";
+    let (hashes, failed) = write_files_and_sidecars(
+        &mut &data[..],
+        &[(&path, vec![&sha256_path, &sha512_path])],
+        &[&SHA256, &SHA512],
+        false,
+        16,
+        &AtomicU64::new(0)).unwrap();
+    assert!(failed.is_empty());
+
+    let sha256 = to_hex(digest(&SHA256, data).as_ref());
+    let sha512 = to_hex(digest(&SHA512, data).as_ref());
+    assert_eq!(hashes, vec![sha256.clone(), sha512.clone()]);
+    assert_eq!(std::fs::read(&path).unwrap(), &data[..]);
+    assert_eq!(std::fs::read_to_string(&sha256_path).unwrap(), sha256);
+    assert_eq!(std::fs::read_to_string(&sha512_path).unwrap(), sha512);
+
+    // Without any sidecars, it's just a copy.
+    let unhashed_path = dir.join("unhashed");
+    let (hashes, _) = write_files_and_sidecars(&mut &data[..], &[(&unhashed_path, vec![])], &[],
+                                               false, 16, &AtomicU64::new(0)).unwrap();
+    assert!(hashes.is_empty());
+    assert_eq!(std::fs::read(&unhashed_path).unwrap(), &data[..]);
+
+    // Writing to several files at once, one of which can't be written to.
+    let copies = [dir.join("a"), dir.join("b"), PathBuf::from("/dev/full")];
+    let sidecars = [dir.join("a.sha256sum"), dir.join("b.sha256sum"), dir.join("full.sha256sum")];
+    let files: Vec<(&Path, Vec<&Path>)> = copies.iter()
+        .zip(&sidecars)
+        .map(|(copy, sidecar)| (copy.as_path(), vec![sidecar.as_path()]))
+        .collect();
+    assert!(write_files_and_sidecars(&mut &data[..], &files, &[&SHA256], false, 16,
+                                     &AtomicU64::new(0)).is_err());
+    let (hashes, failed) = write_files_and_sidecars(&mut &data[..], &files, &[&SHA256], true, 16,
+                                                    &AtomicU64::new(0)).unwrap();
+    assert_eq!(hashes, vec![sha256.clone()]);
+    assert_eq!(failed.iter().map(|(i, _)| *i).collect::<Vec<_>>(), vec![2]);
+    for i in 0..2 {
+        assert_eq!(std::fs::read(&copies[i]).unwrap(), &data[..]);
+        assert_eq!(std::fs::read_to_string(&sidecars[i]).unwrap(), sha256);
+    }
+    assert!(!sidecars[2].exists());
+    assert_eq!(hash_file_from_disk(&copies[0], &[&SHA256], 16).unwrap(), vec![sha256.clone()]);
+}
+
+#[test]
+fn test_split_writer() {
+    let dir = crate::TestDir::new("split");
+    let chunk_dir = dir.to_path_buf();
+
+    let data: Vec<u8> = (0..2500u32).map(|i| i as u8).collect();
+    let (hashes, chunks) = write_split_files(
+        &mut &data[..],
+        move |i| chunk_dir.join(format!("out.{:03}", i)),
+        1000,
+        &[&SHA256],
+        64,
+        &AtomicU64::new(0)).unwrap();
+
+    assert_eq!(hashes, vec![to_hex(digest(&SHA256, &data).as_ref())]);
+    assert_eq!(chunks.len(), 3);
+    let mut joined = vec![];
+    for (i, (path, chunk_hash)) in chunks.iter().enumerate() {
+        assert_eq!(path, &dir.join(format!("out.{:03}", i)));
+        let contents = std::fs::read(path).unwrap();
+        assert_eq!(chunk_hash, &to_hex(digest(&SHA256, &contents).as_ref()));
+        joined.extend(contents);
+    }
+    assert_eq!(joined, data);
+}
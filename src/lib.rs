@@ -4,101 +4,1749 @@
 //
 
 use std::collections::btree_map::*;
-use std::ffi::OsString;
-use std::io::Write;
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CString, OsStr, OsString};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use chrono::prelude::*;
 use chrono::IsoWeek;
+use libzfs::DatasetType;
 
 #[macro_use] mod zfs_error; // include this first because its macros are used in other modules.
 mod hash_stream;
 mod inheritable_pipe;
+mod manifest;
+mod output;
+mod status;
 mod zfs;
 
 use inheritable_pipe::InheritablePipe;
-use zfs::Zfs;
-use zfs_error::ZfsError;
+use zfs::{shell_quote, write_sidecar, PropertySource, Zfs};
+
+pub use manifest::{manifest_path, Manifest};
+pub use output::{Event, Output, RetentionAction, RetentionDecision};
+pub use status::{json_string, BackupState, BatchProgress, Progress, StatusInfo,
+    BATCH_PROGRESS_FILE};
+pub use zfs_error::ZfsError;
+pub use zfs::{dataset_depth, human_number, DiffChange, DiffEntry, MetadataWriter, PoolState,
+              SendOptions, WriteMetadata};
+
+// A directory for a test to put files in, which is deleted afterwards even if the test fails.
+#[cfg(test)]
+struct TestDir(PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+    fn new(name: &str) -> TestDir {
+        let path = std::env::temp_dir()
+            .join(format!("zsnapmgr-test-{}-{}", name, std::process::id()));
+        // Start empty, even if an earlier run by a process with the same ID left things behind.
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TestDir(path)
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TestDir {
+    type Target = Path;
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
 
 pub struct ZSnapMgr {
     zfs: Zfs,
+    output: Arc<Output>,
+}
+
+/// An overview of the managed (date-named) snapshots of a volume.
+#[derive(Debug, Clone)]
+pub struct VolumeSummary {
+    pub volume: String,
+    /// The volume is a zvol rather than a filesystem, so it can't be mounted to look at files.
+    pub zvol: bool,
+    pub snapshot_count: usize,
+    pub latest: Option<NaiveDate>,
+}
+
+/// Options controlling how `ZSnapMgr::backup` compresses, encrypts, and writes a backup.
+#[derive(Debug, Clone, Default)]
+pub struct BackupOptions {
+    pub send: SendOptions,
+
+    /// Let gpg compress the stream too. By default this is disabled, because the stream has
+    /// already been compressed with zstd and compressing it again just burns CPU.
+    pub gpg_compression: bool,
+
+    /// Don't compress the stream with zstd before encrypting it. This is mostly useful along with
+    /// `send.use_zfs_compression`, when the blocks are already compressed. Backup files are then
+    /// named with the extension `zfs.gpg` rather than `zfs.zst.gpg`, and the manifest records
+    /// `zstd=false` so restoring knows not to decompress them.
+    pub skip_zstd: bool,
+
+    /// Encrypt to this gpg public key (a key ID, fingerprint, or email address), instead of with a
+    /// passphrase. The key must be in the keyring of the user running gpg, and trusted. It's
+    /// recorded in the manifest as `recipient`.
+    pub recipient: Option<String>,
+
+    /// The symmetric cipher gpg should encrypt with (`--cipher-algo`), which must be one of
+    /// `GPG_CIPHERS`. By default gpg picks one itself. It is recorded in the manifest as `cipher`.
+    pub cipher: Option<String>,
+
+    /// Back up even if the backup directory is on the same pool as the snapshot. Normally this is
+    /// refused, because the backup would just grow the pool it's meant to protect, and be lost
+    /// along with it.
+    pub allow_same_pool: bool,
+
+    /// How to name the backup file, relative to the backup directory (see `backup_file_path`).
+    /// Defaults to `DEFAULT_NAME_TEMPLATE`.
+    pub name_template: Option<String>,
+
+    /// More backup directories to write copies of the backup to, from the same 'zfs send' (see
+    /// `SendOptions::extra_destinations`). The copies are named the same way as the main one.
+    pub extra_dirs: Vec<PathBuf>,
+
+    /// Encrypt the hash sidecars and manifest the same way as the backup itself, so they don't
+    /// give away what's backed up (beyond what the file name says) or how big it is. They're
+    /// named with `.gpg` on the end (see `encrypted_metadata_path`), and the manifest then has to
+    /// be decrypted with `read_backup_manifest`.
+    pub encrypt_metadata: bool,
+}
+
+/// A backup received into a temporary read-only dataset so its files can be looked at. The
+/// dataset is destroyed when this is dropped.
+pub struct BrowseDataset<'a> {
+    zfs: &'a Zfs,
+    output: &'a Output,
+    pub dataset: String,
+    pub mountpoint: PathBuf,
+}
+
+impl Drop for BrowseDataset<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.zfs.destroy_dataset(&self.dataset) {
+            (self.output)(&Event::Warning(&format!(
+                "failed to clean up temporary dataset {}: {}", self.dataset, e)));
+        }
+    }
+}
+
+/// Where `ZSnapMgr::restore` should put the restored dataset.
+#[derive(Debug, Clone, Copy)]
+pub enum RestoreTarget<'a> {
+    /// Receive it as exactly this dataset, which must not exist yet, though its parent must.
+    Dataset(&'a str),
+    /// Receive it under this existing dataset, keeping its original name minus the pool name, so
+    /// `tank/a/b` restored under `backup/restored` becomes `backup/restored/a/b`
+    /// ('zfs receive -d').
+    UnderWithPath(&'a str),
+    /// Receive it under this existing dataset, keeping just the last part of its original name,
+    /// so `tank/a/b` restored under `backup/restored` becomes `backup/restored/b`
+    /// ('zfs receive -e').
+    UnderWithName(&'a str),
+}
+
+/// What happened when creating a batch of snapshots.
+#[derive(Debug, Default)]
+pub struct SnapshotCreation {
+    pub created: Vec<String>,
+    /// Snapshots which already existed, so weren't created again.
+    pub skipped: Vec<String>,
+    pub failed: Vec<(String, ZfsError)>,
+}
+
+/// What `ZSnapMgr::snapshot_automanage` did.
+#[derive(Debug, Default)]
+pub struct AutomanageResult {
+    pub creation: SnapshotCreation,
+    pub deleted: Vec<String>,
+    /// Snapshots which were due to be deleted, but couldn't be.
+    pub delete_failed: Vec<String>,
+    /// How many existing snapshots were kept, including ones only kept because of a hold.
+    pub kept: usize,
+    /// Snapshots which were due to be created or deleted, but which `AutomanageOptions::confirm`
+    /// said not to.
+    pub declined: Vec<String>,
+}
+
+/// Options for `ZSnapMgr::snapshot_automanage`.
+#[derive(Debug, Clone)]
+pub struct AutomanageOptions {
+    /// Snapshots are only managed (created and pruned) if their name is this prefix followed by a
+    /// date, like `auto-2021-01-02`. Everything else is left alone. With the default empty prefix,
+    /// any snapshot named as just a date is managed.
+    pub prefix: String,
+
+    /// A shell command to run after each snapshot is created. The snapshot's full name is passed
+    /// as `$1` and in the `ZSNAPMGR_SNAPSHOT` environment variable. If it fails, it's only
+    /// reported as a warning.
+    pub post_snapshot_cmd: Option<String>,
+
+    /// Warn about any volume left with more than this many snapshots (managed or not), since ZFS
+    /// slows down when there are very many.
+    pub snapshot_count_warning: Option<usize>,
+
+    /// Never delete a snapshot less than this many days old, whatever the retention rules say.
+    /// Defaults to `DEFAULT_MIN_AGE_DAYS`.
+    pub min_age_days: i64,
+
+    /// Don't create a new snapshot of a volume if nothing has been written to it since its last
+    /// snapshot, since it would just be identical to that one.
+    pub skip_unchanged: bool,
+
+    /// Thin out snapshots by age using these rules (see `parse_schedule`), instead of the
+    /// standard rules: keep everything from the last 30 snapshots, then the first of each week
+    /// and month for the next 30, then the first of each month.
+    pub schedule: Option<Vec<RetentionRule>>,
+
+    /// Which filesystems get new snapshots, going by whether they can be or are mounted. Zvols
+    /// always do.
+    pub mount_filter: MountFilter,
+
+    /// Ask this about each snapshot before creating or deleting it, and leave it alone unless it
+    /// says yes. It's asked about every deletion, and then every creation, before any are done.
+    pub confirm: Option<Confirmation>,
+
+    /// Never delete a snapshot which a backup in this directory was made from, as with
+    /// `ZSnapMgr::destroy_guards`.
+    pub backups_dir: Option<PathBuf>,
+}
+
+/// A change `snapshot_automanage` is about to make, for `AutomanageOptions::confirm` to approve.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AutomanageChange<'a> {
+    Create(&'a str),
+    Delete(&'a str),
+}
+
+/// The type of function which approves changes; see `AutomanageOptions::confirm`.
+pub type ConfirmChange = dyn Fn(AutomanageChange) -> bool + Send + Sync;
+
+#[derive(Clone)]
+pub struct Confirmation(pub Arc<ConfirmChange>);
+
+impl fmt::Debug for Confirmation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Confirmation")
+    }
+}
+
+impl AutomanageOptions {
+    // Split snapshots which are due to be created or deleted into the ones `confirm` approves
+    // and the ones it doesn't.
+    fn confirm_changes(&self, snapshots: Vec<String>, change: fn(&str) -> AutomanageChange<'_>)
+        -> (Vec<String>, Vec<String>)
+    {
+        match self.confirm {
+            Some(Confirmation(ref confirm)) => {
+                snapshots.into_iter().partition(|snap| confirm(change(snap)))
+            }
+            None => (snapshots, vec![]),
+        }
+    }
+}
+
+#[test]
+fn test_confirm_changes() {
+    let snapshots = || vec!["tank@2021-01-01".to_owned(), "tank@2021-01-02".to_owned()];
+    let mut options = AutomanageOptions::default();
+    assert_eq!(options.confirm_changes(snapshots(), |snap| AutomanageChange::Delete(snap)),
+               (snapshots(), vec![]));
+
+    options.confirm = Some(Confirmation(Arc::new(|change| {
+        change == AutomanageChange::Delete("tank@2021-01-02")
+    })));
+    assert_eq!(options.confirm_changes(snapshots(), |snap| AutomanageChange::Delete(snap)),
+               (vec!["tank@2021-01-02".to_owned()], vec!["tank@2021-01-01".to_owned()]));
+    assert_eq!(options.confirm_changes(snapshots(), |snap| AutomanageChange::Create(snap)),
+               (vec![], snapshots()));
+}
+
+/// Which filesystems `snapshot_automanage` creates new snapshots of. Existing snapshots are
+/// thinned out either way.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum MountFilter {
+    #[default]
+    All,
+    /// Skip filesystems with `canmount=off`, which are usually just there to organize (and pass
+    /// properties down to) their children, and never have anything written to them.
+    SkipCanmountOff,
+    /// Only filesystems which are mounted right now.
+    MountedOnly,
+}
+
+impl MountFilter {
+    // The property and value which mark a filesystem as not wanting new snapshots.
+    fn excluded_value(self) -> Option<(&'static str, &'static str)> {
+        match self {
+            MountFilter::All => None,
+            MountFilter::SkipCanmountOff => Some(("canmount", "off")),
+            MountFilter::MountedOnly => Some(("mounted", "no")),
+        }
+    }
+}
+
+/// The default for `AutomanageOptions::min_age_days`.
+pub const DEFAULT_MIN_AGE_DAYS: i64 = 7;
+
+impl Default for AutomanageOptions {
+    fn default() -> Self {
+        Self {
+            prefix: String::new(),
+            post_snapshot_cmd: None,
+            snapshot_count_warning: None,
+            min_age_days: DEFAULT_MIN_AGE_DAYS,
+            skip_unchanged: false,
+            schedule: None,
+            mount_filter: MountFilter::All,
+            confirm: None,
+            backups_dir: None,
+        }
+    }
+}
+
+fn date_from_snapshot(snap: &str) -> Option<NaiveDate> {
+    managed_snapshot_date(snap, "")
+}
+
+// Get the date from a snapshot name, if it is one which is managed with the given prefix.
+fn managed_snapshot_date(snap: &str, prefix: &str) -> Option<NaiveDate> {
+    let datepart = match snap.splitn(2, '@').last() {
+        Some(s) => s,
+        None => return None,
+    };
+    let datepart = datepart.strip_prefix(prefix)?;
+
+    let dateparts: Vec<i32> = datepart.splitn(3, '-')
+                                      .filter_map(|part| {
+                                          part.parse::<i32>()
+                                              .ok()
+                                      })
+                                      .collect();
+
+    if dateparts.len() != 3 {
+        return None;
+    }
+
+    NaiveDate::from_ymd_opt(dateparts[0], dateparts[1] as u32, dateparts[2] as u32)
+}
+
+#[test]
+fn test_managed_snapshot_date() {
+    let date = NaiveDate::from_ymd_opt(2021, 1, 2);
+    assert_eq!(managed_snapshot_date("tank@2021-01-02", ""), date);
+    assert_eq!(managed_snapshot_date("tank@auto-2021-01-02", ""), None);
+    assert_eq!(managed_snapshot_date("tank@auto-2021-01-02", "auto-"), date);
+    assert_eq!(managed_snapshot_date("tank@2021-01-02", "auto-"), None);
+    assert_eq!(managed_snapshot_date("tank@manual", "auto-"), None);
+    assert_eq!(managed_snapshot_date("tank@2021-13-02", ""), None);
+}
+
+/// The date in a snapshot's name, wherever it is in the name: managed snapshots can have a prefix
+/// (see `AutomanageOptions::prefix`), and others may have something after the date too.
+pub fn snapshot_name_date(snap_name: &str) -> Option<NaiveDate> {
+    snap_name.char_indices()
+        .filter_map(|(i, _)| snap_name.get(i .. i + 10))
+        .find_map(|candidate| managed_snapshot_date(candidate, ""))
+}
+
+#[test]
+fn test_snapshot_name_date() {
+    let date = NaiveDate::from_ymd_opt(2021, 1, 2);
+    assert_eq!(snapshot_name_date("2021-01-02"), date);
+    assert_eq!(snapshot_name_date("auto-2021-01-02"), date);
+    assert_eq!(snapshot_name_date("2021-01-02-2"), date);
+    assert_eq!(snapshot_name_date("before-upgrade"), None);
+    assert_eq!(snapshot_name_date("2021-13-02"), None);
+}
+
+/// The default backup file name template, giving names like `tank_data@2021-01-02.zfs.zst.gpg`.
+pub const DEFAULT_NAME_TEMPLATE: &str = "{volume_base}@{snapshot}.{ext}";
+
+/// The backup file name template for the `date-tree` layout, which puts each volume's backups in
+/// its own directory, by year and month: `tank_data/2021/01/2021-01-02.zfs.zst.gpg`.
+pub const DATE_TREE_TEMPLATE: &str = "{volume_base}/{year}/{month}/{snapshot}.{ext}";
+
+/// The path of the file `ZSnapMgr::backup` writes a backup of the given snapshot to.
+///
+/// The name comes from a template, in which these placeholders are replaced:
+///  * `{volume}`: the volume name, like `tank/data`. Slashes in it make subdirectories.
+///  * `{volume_base}`: the volume name with slashes replaced by underscores, like `tank_data`.
+///  * `{snapshot}`: the snapshot name, without the volume.
+///  * `{date}`: today's date, as YYYY-MM-DD.
+///  * `{year}`, `{month}`: the year and month (two digits) of the YYYY-MM-DD date in the
+///    snapshot's name (which may have a prefix, like `auto-2021-01-02`), or else of today.
+///  * `{ext}`: the usual file extension, `zfs.zst.gpg`.
+///
+/// The result must stay within the backup directory.
+pub fn backup_file_path(dir: &Path, snapshot: &str, template: Option<&str>)
+    -> Result<PathBuf, ZfsError>
+{
+    render_backup_file_path(dir, snapshot, template, "zfs.zst.gpg")
+}
+
+impl BackupOptions {
+    /// The path `ZSnapMgr::backup` writes a backup of the given snapshot to with these options.
+    /// This is like `backup_file_path`, but the extension reflects `skip_zstd`.
+    pub fn file_path(&self, dir: &Path, snapshot: &str) -> Result<PathBuf, ZfsError> {
+        let ext = if self.skip_zstd { "zfs.gpg" } else { "zfs.zst.gpg" };
+        render_backup_file_path(dir, snapshot, self.name_template.as_deref(), ext)
+    }
+}
+
+fn render_backup_file_path(dir: &Path, snapshot: &str, template: Option<&str>, ext: &str)
+    -> Result<PathBuf, ZfsError>
+{
+    let (volume, snap_name) = match snapshot.split_once('@') {
+        Some(parts) => parts,
+        None => return Err(ZfsError::from(format!("{:?} is not a snapshot name", snapshot))),
+    };
+    let today = Local::now().date_naive();
+    let snapshot_date = snapshot_name_date(snap_name).unwrap_or(today);
+    let name = template.unwrap_or(DEFAULT_NAME_TEMPLATE)
+        .replace("{volume_base}", &volume.replace('/', "_"))
+        .replace("{volume}", volume)
+        .replace("{snapshot}", snap_name)
+        .replace("{date}", &today.format("%Y-%m-%d").to_string())
+        .replace("{year}", &snapshot_date.format("%Y").to_string())
+        .replace("{month}", &snapshot_date.format("%m").to_string())
+        .replace("{ext}", ext);
+
+    let relative = Path::new(&name);
+    let escapes = relative.components().any(|c| !matches!(c, std::path::Component::Normal(_)));
+    if escapes || name.ends_with('/') {
+        return Err(ZfsError::from(format!(
+            "backup file name {:?} must be a relative path within the backup directory", name)));
+    }
+    Ok(dir.join(relative))
+}
+
+#[test]
+fn test_backup_file_path() {
+    let dir = Path::new("/backups");
+    assert_eq!(backup_file_path(dir, "tank/data@2021-01-02", None).unwrap(),
+               Path::new("/backups/tank_data@2021-01-02.zfs.zst.gpg"));
+    assert_eq!(backup_file_path(dir, "tank/data@2021-01-02", Some("{volume}/{snapshot}.{ext}"))
+                   .unwrap(),
+               Path::new("/backups/tank/data/2021-01-02.zfs.zst.gpg"));
+    assert!(backup_file_path(dir, "tank/data@2021-01-02", Some("../{snapshot}")).is_err());
+    assert!(backup_file_path(dir, "tank/data@2021-01-02", Some("/{snapshot}")).is_err());
+    assert!(backup_file_path(dir, "tank/data", None).is_err());
+
+    let options = BackupOptions { skip_zstd: true, ..BackupOptions::default() };
+    assert_eq!(options.file_path(dir, "tank/data@2021-01-02").unwrap(),
+               Path::new("/backups/tank_data@2021-01-02.zfs.gpg"));
+}
+
+/// If a backup file's path looks like it was made with `DATE_TREE_TEMPLATE`, give the name it
+/// would have had with `DEFAULT_NAME_TEMPLATE` instead (`tank_data@2021-01-02.zfs.zst.gpg`), so
+/// it can be matched up with its volume the same way.
+pub fn date_tree_flat_name(path: &Path) -> Option<String> {
+    let mut components = path.iter().rev().map(|c| c.to_str());
+    let name = components.next()??;
+    let month = components.next()??;
+    let year = components.next()??;
+    let volume_base = components.next()??;
+    let is_number = |s: &str, len| s.len() == len && s.bytes().all(|b| b.is_ascii_digit());
+    if !is_number(year, 4) || !is_number(month, 2) || !name.contains(".zfs") {
+        return None;
+    }
+    Some(format!("{}@{}", volume_base, name))
+}
+
+#[test]
+fn test_date_tree_flat_name() {
+    let path = backup_file_path(Path::new("/backups"), "tank/data@2021-01-02",
+                                Some(DATE_TREE_TEMPLATE)).unwrap();
+    assert_eq!(path, Path::new("/backups/tank_data/2021/01/2021-01-02.zfs.zst.gpg"));
+    assert_eq!(date_tree_flat_name(&path).as_deref(), Some("tank_data@2021-01-02.zfs.zst.gpg"));
+    assert_eq!(backup_file_path(Path::new("/backups"), "tank/data@auto-2021-01-02",
+                                Some(DATE_TREE_TEMPLATE)).unwrap(),
+               Path::new("/backups/tank_data/2021/01/auto-2021-01-02.zfs.zst.gpg"));
+    assert_eq!(date_tree_flat_name(Path::new("tank_data/2021/01/2021-01-02.zfs.zst.gpg"))
+                   .as_deref(),
+               Some("tank_data@2021-01-02.zfs.zst.gpg"));
+    assert_eq!(date_tree_flat_name(Path::new("/backups/tank_data@2021-01-02.zfs.zst.gpg")), None);
+    assert_eq!(date_tree_flat_name(Path::new("/backups/tank/data/2021-01-02.zfs.zst.gpg")), None);
+    assert_eq!(date_tree_flat_name(Path::new("/backups/a/2021/01/notes.txt")), None);
+}
+
+/// Whether a file in a backup directory is (part of) a finished backup: a backup file or one of
+/// its chunks, as opposed to a sidecar, manifest, or leftover of an unfinished backup.
+pub fn is_backup_data_file(name: &str) -> bool {
+    let metadata = name.strip_suffix(ENCRYPTED_METADATA_SUFFIX).unwrap_or(name);
+    name.contains(".zfs")
+        && ![".sha256sum", ".sha512sum", ".manifest", ".tmp", ".json", "_partial"].iter()
+            .any(|suffix| metadata.ends_with(suffix) || name.ends_with(suffix))
+}
+
+#[test]
+fn test_is_backup_data_file() {
+    assert!(is_backup_data_file("tank_data@2021-01-02.zfs.zst.gpg"));
+    assert!(is_backup_data_file("tank_data@2021-01-02.zfs.zst.gpg.003"));
+    assert!(!is_backup_data_file("tank_data@2021-01-02.zfs.zst.gpg.sha256sum"));
+    assert!(!is_backup_data_file("tank_data@2021-01-02.zfs.zst.gpg.manifest"));
+    assert!(!is_backup_data_file("tank_data@2021-01-02.zfs.zst.gpg.manifest.gpg"));
+    assert!(!is_backup_data_file("tank_data@2021-01-02.zfs.zst.gpg.003.sha256sum.gpg"));
+    assert!(!is_backup_data_file("tank_data@2021-01-02.zfs.zst.gpg_partial"));
+    assert!(!is_backup_data_file("notes.txt"));
+}
+
+/// Make a snapshot name from `base` which isn't `taken`, by adding `-2`, `-3`, and so on if
+/// needed. Note that a date with a suffix added is no longer recognized as a dated snapshot, so
+/// `snapshot_automanage` leaves those alone.
+pub fn unique_snapshot_name(base: &str, taken: &dyn Fn(&str) -> bool) -> String {
+    if !taken(base) {
+        return base.to_owned();
+    }
+    (2..)
+        .map(|n| format!("{}-{}", base, n))
+        .find(|name| !taken(name))
+        .unwrap()
+}
+
+#[test]
+fn test_unique_snapshot_name() {
+    let existing = ["2021-01-02", "2021-01-02-2", "manual"];
+    let taken = |name: &str| existing.contains(&name);
+    assert_eq!(unique_snapshot_name("2021-01-03", &taken), "2021-01-03");
+    assert_eq!(unique_snapshot_name("2021-01-02", &taken), "2021-01-02-3");
+    assert_eq!(unique_snapshot_name("manual", &taken), "manual-2");
+}
+
+/// A snapshot whose name isn't managed by `snapshot_automanage`, and what it could be renamed to
+/// so that it is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Adoption {
+    pub snapshot: String,
+    /// The day it was made, which its new name is based on.
+    pub created: NaiveDate,
+    /// The new name (without the volume), or `None` if there's already a managed snapshot of
+    /// the volume from that day.
+    pub new_name: Option<String>,
+}
+
+// Work out how to rename a volume's unmanaged snapshots (given with the day each was made, oldest
+// first) into the managed naming scheme with the given prefix. Where there's more than one from
+// the same day, the oldest gets the name.
+fn plan_adoption(snapshots: &[(String, NaiveDate)], prefix: &str) -> Vec<Adoption> {
+    let mut taken: HashSet<String> = snapshots.iter()
+        .filter(|(snap, _)| managed_snapshot_date(snap, prefix).is_some())
+        .filter_map(|(snap, _)| snap.split_once('@').map(|(_, name)| name.to_owned()))
+        .collect();
+    snapshots.iter()
+        .filter(|(snap, _)| managed_snapshot_date(snap, prefix).is_none())
+        .map(|(snap, created)| {
+            let name = format!("{}{}", prefix, created.format("%Y-%m-%d"));
+            Adoption {
+                snapshot: snap.clone(),
+                created: *created,
+                new_name: Some(name.clone()).filter(|_| taken.insert(name)),
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn test_plan_adoption() {
+    let date = |s: &str| NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap();
+    let snapshots = vec![
+        ("tank@before-upgrade".to_owned(), date("2021-01-01")),
+        ("tank@2021-01-02".to_owned(), date("2021-01-02")),
+        ("tank@oops".to_owned(), date("2021-01-02")),
+        ("tank@first".to_owned(), date("2021-01-03")),
+        ("tank@second".to_owned(), date("2021-01-03")),
+    ];
+    let adoption = plan_adoption(&snapshots, "");
+    let names: Vec<(&str, Option<&str>)> = adoption.iter()
+        .map(|a| (a.snapshot.as_str(), a.new_name.as_deref()))
+        .collect();
+    assert_eq!(names, vec![("tank@before-upgrade", Some("2021-01-01")),
+                           ("tank@oops", None),
+                           ("tank@first", Some("2021-01-03")),
+                           ("tank@second", None)]);
+}
+
+/// The compressors `ZSnapMgr::benchmark_compression` tries if it isn't given any. The first is
+/// what backups are compressed with.
+pub const BENCHMARK_COMPRESSORS: &[&str] = &[
+    "zstd -T0", "zstd -T0 -1", "zstd -T0 -9", "zstd -T0 -19", "gzip", "xz -T0",
+];
+
+/// How one compressor did on the sample `ZSnapMgr::benchmark_compression` took.
+#[derive(Debug)]
+pub struct CompressionBenchmark {
+    pub compressor: String,
+    /// The compressed size of the sample and how long compressing it took, or why the compressor
+    /// couldn't be run.
+    pub result: Result<(u64, Duration), ZfsError>,
+}
+
+// Run some data through a compressor (a shell command line), returning how big its output was
+// and how long it took.
+fn run_compressor(compressor: &str, input: &[u8]) -> Result<(u64, Duration), ZfsError> {
+    let start = Instant::now();
+    let mut child = zfstry!(Command::new("sh")
+        .arg("-c")
+        .arg(compressor)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn(), or format!("failed to run {:?}", compressor));
+
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+    let (write_result, read_result) = std::thread::scope(|scope| {
+        // The compressor's stdin is closed when this thread finishes, letting it finish too.
+        let writer = scope.spawn(move || stdin.write_all(input));
+        let read_result = io::copy(&mut stdout, &mut io::sink());
+        (writer.join().unwrap(), read_result)
+    });
+
+    let exit_status = zfstry!(child.wait(), or format!("failed to run {:?}", compressor));
+    if !exit_status.success() {
+        return Err(ZfsError::from(format!("{:?} returned nonzero exit code: {}",
+                                          compressor, exit_status.code().unwrap_or(0))));
+    }
+    zfstry!(write_result, or format!("failed to write to {:?}", compressor));
+    let output_size = zfstry!(read_result, or format!("failed to read from {:?}", compressor));
+    Ok((output_size, start.elapsed()))
+}
+
+#[test]
+fn test_run_compressor() {
+    assert_eq!(run_compressor("cat", &[0; 100_000]).unwrap().0, 100_000);
+    assert_eq!(run_compressor("head -c 10", &[0; 100]).unwrap().0, 10);
+    assert!(run_compressor("cat >/dev/null; exit 3", b"abc").is_err());
+}
+
+/// What `rehash_file` did.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rehash {
+    Written,
+    /// There was already a sidecar, so it was left alone.
+    Skipped,
+    /// The sidecar is encrypted (see `BackupOptions::encrypt_metadata`). It's left alone, even
+    /// with `force`, rather than put a plain sidecar next to it.
+    Encrypted,
+}
+
+/// Hash a backup file and write its `.sha256sum` sidecar, in the same format a backup writes it.
+/// If the sidecar already exists, it's only rewritten if `force` is set.
+pub fn rehash_file(path: &Path, force: bool) -> Result<Rehash, ZfsError> {
+    let mut sidecar_name = path.file_name().unwrap_or_default().to_os_string();
+    sidecar_name.push(".sha256sum");
+    let sidecar_path = path.with_file_name(sidecar_name);
+    if encrypted_metadata_path(&sidecar_path).exists() {
+        return Ok(Rehash::Encrypted);
+    }
+    if !force && sidecar_path.exists() {
+        return Ok(Rehash::Skipped);
+    }
+
+    let mut file = zfstry!(fs::File::open(path), or format!("failed to open {:?}", path));
+    let hash = hash_stream::copy_hashed(
+        &mut file,
+        io::sink(),
+        &ring::digest::SHA256,
+        hash_stream::DEFAULT_BUFFER_SIZE,
+        &hash_stream::AtomicU64::new(0))
+        .map_err(|e| ZfsError::from(format!("failed to hash {:?}: {}", path, e)))?;
+
+    zfstry!(write_sidecar(&sidecar_path, &hash, path.file_name().unwrap_or_default(), None),
+        or format!("failed to write {:?}", sidecar_path));
+    Ok(Rehash::Written)
+}
+
+// A path with something added onto the end of its file name, like a sidecar's or chunk's.
+fn with_name_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+// The hash in a plain sidecar file, if there is one.
+fn read_sidecar_hash(path: &Path) -> Option<String> {
+    let contents = fs::read_to_string(path).ok()?;
+    contents.split_whitespace().next().map(str::to_owned)
+}
+
+// Compare a file's hash with the ones recorded for it (which may not all exist). Returns whether
+// there were any to compare with.
+fn check_hash(path: &Path, actual: &str, expected: &[Option<String>]) -> Result<bool, ZfsError> {
+    let mut checked = false;
+    for expected in expected.iter().flatten() {
+        if !expected.eq_ignore_ascii_case(actual) {
+            return Err(ZfsError::verification_failed(format!(
+                "{:?} doesn't match the hash recorded for it", path)));
+        }
+        checked = true;
+    }
+    Ok(checked)
+}
+
+/// Check a finished backup against the hashes recorded when it was written: those in its
+/// `.sha256sum` and `.sha512sum` sidecars, and in its manifest, if given (see
+/// `read_backup_manifest`). Encrypted sidecars are skipped, since the manifest has the same
+/// hashes.
+///
+/// Each chunk of a split backup is checked against its own sidecar and the manifest, and the
+/// chunks are also hashed together, in the same pass, to check them against the manifest's hashes
+/// of the whole backup, without having to put them back together first. An interrupted check can
+/// be picked up again from chunk number `from_chunk`, but then the whole backup can't be checked.
+pub fn verify_backup(path: &Path, manifest: Option<&Manifest>, from_chunk: usize,
+                     output: &Output)
+    -> Result<(), ZfsError>
+{
+    let recorded = |key: &str| manifest.and_then(|manifest| manifest.get(key)).map(str::to_owned);
+    let hash_file = |path: &Path, algos: &[&'static ring::digest::Algorithm],
+                     whole: &mut dyn Write| {
+        output(&Event::Verifying(path));
+        let mut file = zfstry!(fs::File::open(path), or format!("failed to open {:?}", path));
+        hash_stream::copy_hashed_multi(&mut file, whole, algos, hash_stream::DEFAULT_BUFFER_SIZE,
+                                       &hash_stream::AtomicU64::new(0))
+            .map_err(|e| ZfsError::from(format!("failed to hash {:?}: {}", path, e)))
+    };
+
+    let chunks: Vec<PathBuf> = match recorded("chunks") {
+        Some(count) => {
+            let count = count.parse::<usize>().map_err(|_| ZfsError::from(format!(
+                "bad chunk count {:?} in the manifest of {:?}", count, path)))?;
+            (0 .. count).map(|i| with_name_suffix(path, &format!(".{:03}", i))).collect()
+        }
+        None if !path.exists() => (0 ..)
+            .map(|i| with_name_suffix(path, &format!(".{:03}", i)))
+            .take_while(|chunk| chunk.exists())
+            .collect(),
+        None => vec![],
+    };
+
+    let sha512 = [read_sidecar_hash(&with_name_suffix(path, ".sha512sum")), recorded("sha512")];
+    let mut algos = vec![&ring::digest::SHA256];
+    if sha512.iter().any(Option::is_some) {
+        algos.push(&ring::digest::SHA512);
+    }
+
+    if chunks.is_empty() {
+        let hashes = hash_file(path, &algos, &mut io::sink())?;
+        let sha256 = [read_sidecar_hash(&with_name_suffix(path, ".sha256sum")), recorded("sha256")];
+        let mut checked = check_hash(path, &hashes[0], &sha256)?;
+        if let Some(hash) = hashes.get(1) {
+            checked |= check_hash(path, hash, &sha512)?;
+        }
+        if !checked {
+            return Err(ZfsError::from(format!(
+                "{:?} has no recorded hashes to check it against", path)));
+        }
+        return Ok(());
+    }
+
+    if from_chunk >= chunks.len() {
+        return Err(ZfsError::from(format!(
+            "{:?} only has {} chunks; can't start from chunk {}", path, chunks.len(), from_chunk)));
+    }
+    let mut whole = hash_stream::HashingWrite::with_algorithms(io::sink(), &algos);
+    for (i, chunk) in chunks.iter().enumerate().skip(from_chunk) {
+        let hash = hash_file(chunk, &[&ring::digest::SHA256], &mut whole)?.swap_remove(0);
+        let expected = [read_sidecar_hash(&with_name_suffix(chunk, ".sha256sum")),
+                        recorded(&format!("chunk.{:03}.sha256", i))];
+        if !check_hash(chunk, &hash, &expected)? {
+            output(&Event::Warning(&format!(
+                "{:?} has no recorded hash; it's only checked as part of the whole backup",
+                chunk)));
+        }
+    }
+
+    if from_chunk > 0 {
+        output(&Event::Warning(&format!(
+            "started from chunk {}, so {:?} as a whole wasn't checked", from_chunk, path)));
+        return Ok(());
+    }
+    let hashes: Vec<String> = whole.finish_all().iter().map(|hash| hash_stream::to_hex(hash))
+        .collect();
+    let mut checked = check_hash(path, &hashes[0], &[recorded("sha256")])?;
+    if let Some(hash) = hashes.get(1) {
+        checked |= check_hash(path, hash, &sha512)?;
+    }
+    if !checked {
+        output(&Event::Warning(&format!(
+            "there's no recorded hash of {:?} as a whole; only its chunks were checked", path)));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_verify_backup() {
+    let dir = TestDir::new("verify");
+    let path = dir.join("a.gpg");
+    let sha256 = |data: &[u8]| hash_stream::to_hex(ring::digest::digest(
+        &ring::digest::SHA256, data).as_ref());
+
+    fs::write(dir.join("a.gpg.000"), b"abc").unwrap();
+    fs::write(dir.join("a.gpg.001"), b"def").unwrap();
+    fs::write(dir.join("a.gpg.000.sha256sum"), format!("{} *a.gpg.000\n", sha256(b"abc")))
+        .unwrap();
+    let mut manifest = Manifest::new();
+    manifest.set("chunks", "2");
+    manifest.set("chunk.001.sha256", sha256(b"def"));
+    manifest.set("sha256", sha256(b"abcdef"));
+    let output = |_: &Event| ();
+
+    verify_backup(&path, Some(&manifest), 0, &output).unwrap();
+    verify_backup(&path, Some(&manifest), 1, &output).unwrap();
+    verify_backup(&path, None, 0, &output).unwrap();
+    assert!(verify_backup(&path, Some(&manifest), 2, &output).is_err());
+
+    // A chunk which doesn't match its own hash, or the whole backup not matching.
+    fs::write(dir.join("a.gpg.001"), b"xyz").unwrap();
+    let e = verify_backup(&path, Some(&manifest), 0, &output).unwrap_err();
+    assert!(e.is_verification_failure());
+    manifest.set("chunk.001.sha256", sha256(b"xyz"));
+    let e = verify_backup(&path, Some(&manifest), 0, &output).unwrap_err();
+    assert!(e.is_verification_failure());
+}
+
+/// Delete a backup: its file (or chunk files), hash sidecars, and manifest.
+pub fn remove_backup_files(path: &Path) -> Result<(), ZfsError> {
+    let filename = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let chunk_prefix = format!("{}.", filename);
+    for entry in zfstry!(fs::read_dir(dir), or format!("failed to read directory {:?}", dir)) {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        // The file itself, or its sidecars, manifest, or chunks (and their sidecars).
+        let chunk_or_sidecar = name.strip_prefix(&chunk_prefix)
+            .map(|rest| {
+                let rest = rest.strip_suffix(ENCRYPTED_METADATA_SUFFIX).unwrap_or(rest);
+                let rest = rest.trim_end_matches(".sha256sum").trim_end_matches(".sha512sum");
+                rest.is_empty()
+                    || rest == "sha256sum" || rest == "sha512sum" || rest == "manifest"
+                    || rest.chars().all(|c| c.is_ascii_digit())
+            })
+            .unwrap_or(false);
+        if name == filename || chunk_or_sidecar {
+            zfstry!(fs::remove_file(entry.path()),
+                or format!("failed to remove {:?}", entry.path()));
+        }
+    }
+    Ok(())
+}
+
+// Of a set of backups (given as their path, snapshot, and the snapshot name they're incremental
+// from), pick out the ones whose snapshot is gone from a volume that still exists, and which
+// aren't needed as the base of a backup being kept. Each volume's newest backup is always kept,
+// along with the backups it needs, since that may be all that's left of its older snapshots.
+fn orphans(backups: &[(PathBuf, String, Option<String>)],
+           snapshots: &HashSet<String>,
+           volumes: &HashSet<String>)
+    -> Vec<PathBuf>
+{
+    let is_orphan = |snapshot: &str| {
+        let volume = snapshot.split('@').next().unwrap();
+        volumes.contains(volume) && !snapshots.contains(snapshot)
+    };
+    let mut keep: HashSet<&str> = backups.iter()
+        .map(|(_, snapshot, _)| snapshot.as_str())
+        .filter(|snapshot| !is_orphan(snapshot))
+        .collect();
+
+    let age = |snapshot: &str| {
+        let name = snapshot.split_once('@').map(|(_, name)| name).unwrap_or("");
+        (snapshot_name_date(name), name.to_owned())
+    };
+    let mut newest = HashMap::<&str, &str>::new();
+    for (_, snapshot, _) in backups {
+        let volume = snapshot.split('@').next().unwrap();
+        let newest = newest.entry(volume).or_insert(snapshot);
+        if age(snapshot) > age(newest) {
+            *newest = snapshot;
+        }
+    }
+    keep.extend(newest.into_values());
+
+    // Keep the bases of kept incremental backups, and their bases, and so on.
+    loop {
+        let mut changed = false;
+        for (_, snapshot, base) in backups {
+            if let Some(base) = base.as_ref().filter(|_| keep.contains(snapshot.as_str())) {
+                let volume = snapshot.split('@').next().unwrap();
+                let base_snapshot = format!("{}@{}", volume, base);
+                if let Some((_, base_snapshot, _)) =
+                    backups.iter().find(|(_, other, _)| *other == base_snapshot)
+                {
+                    changed |= keep.insert(base_snapshot.as_str());
+                }
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    backups.iter()
+        .filter(|(_, snapshot, _)| !keep.contains(snapshot.as_str()))
+        .map(|(path, _, _)| path.clone())
+        .collect()
+}
+
+#[test]
+fn test_orphans() {
+    let backup = |name: &str, snapshot: &str, base: Option<&str>| {
+        (PathBuf::from(name), snapshot.to_owned(), base.map(str::to_owned))
+    };
+    let backups = vec![
+        backup("a1", "tank/a@2021-01-01", None),
+        backup("a2", "tank/a@2021-02-01", Some("2021-01-01")),
+        backup("a3", "tank/a@2021-03-01", Some("2021-02-01")),
+        backup("b0", "tank/b@2020-12-01", None),
+        backup("b1", "tank/b@2021-01-01", None),
+        backup("b2", "tank/b@2021-02-01", Some("2021-01-01")),
+        backup("c1", "tank/c@2021-01-01", None),
+        backup("gone", "tank/gone@2021-01-01", None),
+    ];
+    let snapshots = ["tank/a@2021-03-01", "tank/b@2021-03-01", "tank/c@2021-01-01"]
+        .iter().map(|s| s.to_string()).collect();
+    let volumes = ["tank/a", "tank/b", "tank/c"].iter().map(|s| s.to_string()).collect();
+
+    // a1 and a2 are gone from the pool, but a3 needs them. b2 is tank/b's newest backup, so it's
+    // kept, and b1 with it, but nothing needs b0. tank/gone isn't there at all, so its backups
+    // can't be judged.
+    assert_eq!(orphans(&backups, &snapshots, &volumes), vec![PathBuf::from("b0")]);
+}
+
+/// What's added to the names of sidecars and manifests encrypted because of
+/// `BackupOptions::encrypt_metadata`.
+pub const ENCRYPTED_METADATA_SUFFIX: &str = ".gpg";
+
+/// Where a sidecar or manifest goes when it's encrypted.
+pub fn encrypted_metadata_path(path: &Path) -> PathBuf {
+    let mut encrypted = path.as_os_str().to_owned();
+    encrypted.push(ENCRYPTED_METADATA_SUFFIX);
+    PathBuf::from(encrypted)
+}
+
+// How to encrypt a backup's sidecars and manifest: the same way as the backup.
+struct MetadataEncryption {
+    recipient: Option<String>,
+    passphrase: String,
+    cipher: Option<&'static str>,
+}
+
+// Encrypt a sidecar or manifest with gpg, writing it to its `encrypted_metadata_path`.
+fn gpg_encrypt_metadata(path: &Path, contents: &[u8], encryption: &MetadataEncryption)
+    -> io::Result<()>
+{
+    let encrypted_path = encrypted_metadata_path(path);
+    let mut tmp_path = encrypted_path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+
+    let mut gpg = Command::new("gpg");
+    gpg.args(["--batch", "--yes", "--compress-algo", "none"]);
+    // gpg reads the passphrase from the pipe, so each run needs a new one.
+    let mut passphrase_pipe = InheritablePipe::new()?;
+    match encryption.recipient {
+        Some(ref recipient) => {
+            gpg.args(["--encrypt", "--recipient", recipient]);
+        }
+        None => {
+            writeln!(passphrase_pipe, "{}", encryption.passphrase)?;
+            gpg.args(["--symmetric", "--passphrase-fd"])
+                .arg(passphrase_pipe.child_fd().to_string());
+        }
+    }
+    if let Some(cipher) = encryption.cipher {
+        gpg.args(["--cipher-algo", cipher]);
+    }
+    let mut child = gpg.arg("--output")
+        .arg(&tmp_path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::inherit())
+        .spawn()?;
+    // Dropping stdin once it's written closes it, letting gpg finish.
+    child.stdin.take().unwrap().write_all(contents)?;
+    let exit_status = child.wait()?;
+    if !exit_status.success() {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(io::Error::new(io::ErrorKind::Other,
+                                  format!("gpg failed to encrypt {:?}: {}", path, exit_status)));
+    }
+    fs::rename(&tmp_path, &encrypted_path)
+}
+
+/// Read a backup's manifest. If it was encrypted (see `BackupOptions::encrypt_metadata`), it's
+/// decrypted, with the given passphrase if there is one.
+pub fn read_backup_manifest(backup_path: &Path, passphrase: Option<&str>)
+    -> Result<Manifest, ZfsError>
+{
+    let path = manifest_path(backup_path);
+    let encrypted_path = encrypted_metadata_path(&path);
+    if path.exists() || !encrypted_path.exists() {
+        return Ok(zfstry!(Manifest::read(&path), or format!("failed to read {:?}", path)));
+    }
+
+    let mut gpg = Command::new("gpg");
+    gpg.args(["--batch", "--decrypt"]);
+    let mut passphrase_pipe =
+        zfstry!(InheritablePipe::new(), or "failed to create passphrase pipe");
+    if let Some(passphrase) = passphrase {
+        zfstry!(writeln!(passphrase_pipe, "{}", passphrase),
+            or "failed to write passphrase to pipe");
+        gpg.arg("--passphrase-fd").arg(passphrase_pipe.child_fd().to_string());
+    }
+    let output = zfstry!(gpg.arg(&encrypted_path).stderr(Stdio::inherit()).output(),
+        or "failed to run gpg");
+    if !output.status.success() {
+        return Err(ZfsError::from(format!("gpg failed to decrypt {:?}: {}",
+                                          encrypted_path, output.status)));
+    }
+    Ok(Manifest::parse(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Find the manifests of all the backups under a directory, including in subdirectories (which
+/// is where backups named with a template containing slashes end up). Encrypted manifests (see
+/// `BackupOptions::encrypt_metadata`) are included without their contents, since reading them
+/// needs the backup's key.
+pub fn find_manifests(dir: &Path) -> Result<Vec<(PathBuf, Option<Manifest>)>, ZfsError> {
+    let mut manifests = vec![];
+    for entry in zfstry!(fs::read_dir(dir), or format!("failed to read directory {:?}", dir)) {
+        let entry = entry?;
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if file_type.is_dir() {
+            manifests.extend(find_manifests(&path)?);
+        } else if name.ends_with(".manifest") {
+            if let Ok(manifest) = Manifest::read(&path) {
+                manifests.push((path, Some(manifest)));
+            }
+        } else if name.ends_with(&format!(".manifest{}", ENCRYPTED_METADATA_SUFFIX)) {
+            manifests.push((path, None));
+        }
+    }
+    Ok(manifests)
+}
+
+/// The path of the backup a manifest found by `find_manifests` belongs to.
+pub fn manifest_backup_path(manifest_path: &Path) -> PathBuf {
+    let name = manifest_path.file_name().unwrap_or_default().to_string_lossy();
+    let name = name.strip_suffix(ENCRYPTED_METADATA_SUFFIX).unwrap_or(&name);
+    manifest_path.with_file_name(name.trim_end_matches(".manifest"))
+}
+
+/// Run a user-supplied hook command through sh, with the given arguments passed to it (as `"$@"`)
+/// and the given variables set in its environment. All of zsnapmgr's hooks are run this way, so
+/// they can take what they're given either way.
+pub fn run_hook(cmd: &str, args: &[&OsStr], env: &[(&str, &OsStr)]) -> Result<(), ZfsError> {
+    let exit_status = zfstry!(Command::new("sh")
+            .arg("-c")
+            .arg(format!("{} \"$@\"", cmd))
+            .arg("sh")
+            .args(args)
+            .envs(env.iter().copied())
+            .stdin(Stdio::null())
+            .status(),
+        or format!("failed to run hook {:?}", cmd));
+    if !exit_status.success() {
+        return Err(ZfsError::from(format!("hook {:?} failed: {}", cmd, exit_status)));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_run_hook() {
+    let check = "f() { test \"$1 $2\" = \"a b c\" && test \"$HOOK_VAR\" = x; }; f";
+    let env = [("HOOK_VAR", OsStr::new("x"))];
+    assert!(run_hook(check, &[OsStr::new("a"), OsStr::new("b c")], &env).is_ok());
+    assert!(run_hook(check, &[OsStr::new("a"), OsStr::new("b")], &env).is_err());
+    assert!(run_hook(check, &[OsStr::new("a"), OsStr::new("b c")], &[]).is_err());
+}
+
+// Look for an executable program in PATH, or if it's given as a path, just check that.
+fn find_in_path(program: &str) -> Option<PathBuf> {
+    let is_executable = |candidate: &Path| {
+        let c_path = match CString::new(candidate.as_os_str().as_bytes()) {
+            Ok(c_path) => c_path,
+            Err(_) => return false,
+        };
+        candidate.is_file() && unsafe { libc::access(c_path.as_ptr(), libc::X_OK) } == 0
+    };
+
+    if program.contains('/') {
+        return Some(PathBuf::from(program)).filter(|path| is_executable(path));
+    }
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(program))
+        .find(|candidate| is_executable(candidate))
+}
+
+// Check that the external programs a backup or restore pipeline runs are all installed, so a
+// missing one is reported clearly instead of as an obscure failure from the middle of a pipeline.
+// The list is of (program, package) pairs.
+fn require_tools(tools: &[(&str, &str)]) -> Result<(), ZfsError> {
+    for (tool, package) in tools {
+        if find_in_path(tool).is_none() {
+            return Err(ZfsError::missing_tool(tool, package));
+        }
+    }
+    Ok(())
+}
+
+/// The symmetric ciphers which can be given as `BackupOptions::cipher`, as gpg names them.
+pub const GPG_CIPHERS: &[&str] = &[
+    "AES", "AES192", "AES256", "TWOFISH", "CAMELLIA128", "CAMELLIA192", "CAMELLIA256", "BLOWFISH",
+    "CAST5", "3DES", "IDEA",
+];
+
+/// Look up a cipher name (case-insensitively) in `GPG_CIPHERS`, giving the name gpg uses for it.
+pub fn gpg_cipher(name: &str) -> Result<&'static str, ZfsError> {
+    GPG_CIPHERS.iter()
+        .find(|cipher| cipher.eq_ignore_ascii_case(name))
+        .copied()
+        .ok_or_else(|| ZfsError::from(format!(
+            "unknown gpg cipher {:?}; expected one of: {}", name, GPG_CIPHERS.join(", "))))
+}
+
+#[test]
+fn test_gpg_cipher() {
+    assert_eq!(gpg_cipher("aes256").unwrap(), "AES256");
+    assert_eq!(gpg_cipher("Camellia128").unwrap(), "CAMELLIA128");
+    assert!(gpg_cipher("chacha20").is_err());
+    assert!(gpg_cipher("AES256; rm -rf /").is_err());
+}
+
+// Send stream features which the receiving pool needs to support, named as both the manifest key
+// which records that the stream uses them, and the pool feature.
+const STREAM_FEATURES: &[&str] = &["large_blocks", "embedded_data"];
+
+// The programs needed for backups and restores, and what package they usually come in.
+const PIPELINE_TOOLS: &[(&str, &str)] = &[
+    ("zstd", "zstd"),
+    ("gpg", "gnupg"),
+];
+
+// Why a backup of a zvol can't be browsed.
+fn zvol_browse_error(backup_path: &Path, volume: &str) -> ZfsError {
+    ZfsError::from(format!(
+        "{:?} is a backup of {}, which is a zvol; zvols have no files to browse. Use 'restore' \
+            and attach the zvol's device instead", backup_path, volume))
+}
+
+// Check that a backup destination is an existing directory we can create files in, so we don't
+// find out only after starting an expensive 'zfs send'.
+fn check_writable_dir(path: &Path) -> Result<(), ZfsError> {
+    let metadata = zfstry!(fs::metadata(path),
+        or format!("backup destination {:?} is not accessible", path));
+    if !metadata.is_dir() {
+        return Err(ZfsError::from(format!("backup destination {:?} is not a directory", path)));
+    }
+
+    let c_path = zfstry!(CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e)),
+        or format!("invalid backup destination {:?}", path));
+    if unsafe { libc::access(c_path.as_ptr(), libc::W_OK | libc::X_OK) } != 0 {
+        return Err(ZfsError::from((format!("backup destination {:?} is not writable", path),
+                                   io::Error::last_os_error())));
+    }
+    Ok(())
+}
+
+// The snapshots which the backups in a directory were made from, found in one pass over it, so
+// that many snapshots can be checked against them.
+struct BackupBases {
+    // Snapshot names from the backups' manifests, with the manifest each was found in.
+    manifests: HashMap<String, OsString>,
+    // The volume base and snapshot name in each backup's file name, with that file name. These
+    // come from encrypted manifests (which can't be read) and backups without manifests.
+    names: Vec<(String, String, OsString)>,
+}
+
+impl BackupBases {
+    fn scan(dir: &Path) -> Result<Self, ZfsError> {
+        let mut manifests = HashMap::new();
+        let mut names = vec![];
+        let mut add_name = |name: &str, file: OsString| {
+            if let Some((base, snap)) = backup_name_parts(name) {
+                names.push((base.to_owned(), snap.to_owned(), file));
+            }
+        };
+
+        for (path, manifest) in find_manifests(dir)? {
+            let file = path.strip_prefix(dir).unwrap_or(&path).as_os_str().to_owned();
+            match manifest {
+                Some(manifest) => {
+                    if let Some(snapshot) = manifest.get("snapshot") {
+                        manifests.entry(snapshot.to_owned()).or_insert(file);
+                    }
+                }
+                None => {
+                    let backup = manifest_backup_path(&path);
+                    let name = date_tree_flat_name(&backup).unwrap_or_else(|| {
+                        backup.file_name().unwrap_or_default().to_string_lossy().into_owned()
+                    });
+                    add_name(&name, file);
+                }
+            }
+        }
+
+        for entry in zfstry!(fs::read_dir(dir), or "failed to read backup directory") {
+            let name = entry?.file_name();
+            add_name(&name.to_string_lossy(), name.clone());
+        }
+        Ok(BackupBases { manifests, names })
+    }
+
+    // Find a backup made from the given snapshot, if there is one. This errs on the side of
+    // caution: a backup file named with just the end of the volume name counts as a match.
+    fn referencing(&self, volume: &str, snap_name: &str) -> Option<&OsStr> {
+        if let Some(file) = self.manifests.get(&format!("{}@{}", volume, snap_name)) {
+            return Some(file);
+        }
+        let volume_base = volume.replace('/', "_");
+        self.names.iter()
+            .find(|(base, snap, _)| snap == snap_name && volume_base_matches(base, &volume_base))
+            .map(|(_, _, file)| file.as_os_str())
+    }
 }
 
-fn date_from_snapshot(snap: &str) -> Option<NaiveDate> {
-    let datepart = match snap.splitn(2, '@').last() {
-        Some(s) => s,
-        None => return None,
-    };
+// The volume base and snapshot name a backup file's name says it was made from.
+fn backup_name_parts(name: &str) -> Option<(&str, &str)> {
+    let stem = match name.find(".zfs") {
+        Some(pos) if !name.ends_with("_partial") => &name[..pos],
+        _ => return None,
+    };
+    stem.split_once('@')
+}
+
+// Whether a volume base from a backup's name could be the given volume's. A name with just the end
+// of the volume name counts.
+fn volume_base_matches(base: &str, volume_base: &str) -> bool {
+    base == volume_base || volume_base.ends_with(&format!("_{}", base))
+}
+
+#[test]
+fn test_backup_bases() {
+    let matches = |name: &str, snapshot: &str| {
+        let names = backup_name_parts(name)
+            .map(|(base, snap)| (base.to_owned(), snap.to_owned(), OsString::from(name)))
+            .into_iter()
+            .collect();
+        let bases = BackupBases { manifests: HashMap::new(), names };
+        let (volume, snap_name) = snapshot.split_once('@').unwrap();
+        bases.referencing(volume, snap_name).is_some()
+    };
+    assert!(matches("tank_data@2021-01-02.zfs.zst.gpg", "tank/data@2021-01-02"));
+    assert!(matches("data@2021-01-02.zfs.zst.gpg", "tank/data@2021-01-02"));
+    assert!(!matches("data@2021-01-03.zfs.zst.gpg", "tank/data@2021-01-02"));
+    assert!(!matches("tank_data@2021-01-02.zfs_partial", "tank/data@2021-01-02"));
+    assert!(!matches("ank_data@2021-01-02.zfs", "tank/data@2021-01-02"));
+
+    let manifests = HashMap::from([("tank/data@2021-01-02".to_owned(),
+                                    OsString::from("2021/01/2021-01-02.zfs.manifest"))]);
+    let bases = BackupBases { manifests, names: vec![] };
+    assert!(bases.referencing("tank/data", "2021-01-02").is_some());
+    assert!(bases.referencing("tank/other", "2021-01-02").is_none());
+}
+
+trait Succ {
+    fn succ(&self) -> Self;
+}
+
+trait WeekOfYear {
+    fn week_of_year(&self) -> IsoWeek;
+}
+
+impl Succ for NaiveDate {
+    fn succ(&self) -> Self {
+        NaiveDate::succ_opt(self).unwrap()
+    }
+}
+
+impl<T: Datelike + Succ> WeekOfYear for T {
+    fn week_of_year(&self) -> IsoWeek {
+        // The original C# version of this program used System.Globalization.Calendar.GetWeekOfYear
+        // for this, using System.Globalization.DateTimeFormatInfo.InvariantInfo for the
+        // parameters.
+        //
+        // "The first week of the year starts on the first day of the year and ends before the
+        // following designated first day of the week."
+        // The first day of the week is designated as Sunday.
+        //
+        // This is similar to the ISO week date, except that ISO week date has:
+        //  - week starting on Monday
+        //  - the first day of the year can be week 53 from the previous year (week 1 is defined as
+        //    being the first week containing January 4 of that year)
+        //
+        // This isn't going to replicate the C# method exactly - the value can be +/- 1 depending
+        // on which year it is for.
+        // Figuring out whether to add 1 or not depending on the year is hard, and this method is
+        // only used for finding the first snapshot in a week, so the difference isn't important,
+        // as long as days in the same week get the same value.
+        // In fact, the C# method wouldn't even uphold this property on the first week of the year
+        // usually -- it would unconditionally change from 53 or 54 to 1 mid-week. This method does
+        // not.
+
+        if self.weekday() == Weekday::Sun {
+            // We want weeks starting on Sunday, so if it's Sunday, use the ISO week number for
+            // tomorrow.
+            self.succ().iso_week()
+        } else {
+            self.iso_week()
+        }
+    }
+}
+
+/// One snapshot's line in a `RetentionPlan`.
+#[derive(Debug)]
+pub struct PlannedSnapshot {
+    pub volume: String,
+    /// The snapshot name, without the volume.
+    pub name: String,
+    pub days_old: i64,
+    /// Position of the snapshot counting back from the newest, starting at 1.
+    pub number: usize,
+    pub action: RetentionAction,
+}
+
+impl PlannedSnapshot {
+    pub fn decision(&self) -> RetentionDecision<'_> {
+        RetentionDecision {
+            volume: &self.volume,
+            name: &self.name,
+            days_old: self.days_old,
+            number: self.number,
+            action: self.action.clone(),
+        }
+    }
+}
+
+/// What `snapshot_automanage` would do, as worked out by `plan_retention`.
+#[derive(Debug, Default)]
+pub struct RetentionPlan {
+    /// Every managed snapshot, including new ones, grouped by volume and newest first.
+    pub snapshots: Vec<PlannedSnapshot>,
+    pub to_create: Vec<String>,
+    pub to_delete: Vec<String>,
+    /// How many existing snapshots are kept, including ones only kept because of a hold.
+    pub kept: usize,
+}
+
+/// The name of the ZFS user property which sets a volume's `RetentionPolicy`.
+pub const RETENTION_PROPERTY: &str = "zsnapmgr:retention";
+
+/// How `snapshot_automanage` treats a volume. This is set with the `zsnapmgr:retention` user
+/// property, which is inherited like any other ZFS property, so it can be set once on a pool's
+/// root and overridden on particular children. The value set on the volume itself wins; failing
+/// that, the value set on its nearest ancestor; failing that, `Standard`.
+///
+/// Only automanage goes by this. Other commands, like `ls` and backups, still see every volume,
+/// whatever its policy.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum RetentionPolicy {
+    /// Create a snapshot each day, and thin out older ones (`standard`, or not set).
+    #[default]
+    Standard,
+    /// Create a snapshot each day, but never delete any (`keep`).
+    KeepAll,
+    /// Neither create nor delete any of the volume's snapshots (`off`).
+    Off,
+}
+
+impl RetentionPolicy {
+    pub fn parse(value: &str) -> Option<RetentionPolicy> {
+        match value {
+            "standard" | "-" | "" => Some(RetentionPolicy::Standard),
+            "keep" => Some(RetentionPolicy::KeepAll),
+            "off" => Some(RetentionPolicy::Off),
+            _ => None,
+        }
+    }
+}
+
+/// How far apart the snapshots kept by a `RetentionRule` are. Snapshots are grouped into buckets
+/// this long, and only the first in each bucket is kept. Week buckets start on Mondays, and month
+/// buckets on the first of a month.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RetentionInterval {
+    Days(u32),
+    Weeks(u32),
+    Months(u32),
+}
+
+impl RetentionInterval {
+    // Which bucket a date is in. Buckets of the same interval are numbered in date order.
+    fn bucket(self, date: NaiveDate) -> i64 {
+        // Day 0 is Monday, 0001-01-01.
+        let day = i64::from(date.num_days_from_ce() - 1);
+        match self {
+            RetentionInterval::Days(n) => day.div_euclid(i64::from(n)),
+            RetentionInterval::Weeks(n) => day.div_euclid(7 * i64::from(n)),
+            RetentionInterval::Months(n) => {
+                (i64::from(date.year()) * 12 + i64::from(date.month0())).div_euclid(i64::from(n))
+            }
+        }
+    }
+}
+
+impl fmt::Display for RetentionInterval {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RetentionInterval::Days(n) => write!(f, "{}d", n),
+            RetentionInterval::Weeks(n) => write!(f, "{}w", n),
+            RetentionInterval::Months(n) => write!(f, "{}m", n),
+        }
+    }
+}
+
+/// One rule of a retention schedule: keep one snapshot per `interval` among those up to
+/// `max_age_days` old (or of any age, if that's `None`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetentionRule {
+    pub max_age_days: Option<i64>,
+    pub interval: RetentionInterval,
+}
+
+/// Parse a retention schedule, written as a comma-separated list of `<max age>:<interval>` rules,
+/// from the youngest snapshots to the oldest. Ages and intervals are a number followed by `d`,
+/// `w`, or `m` (days, weeks, or months; a month counts as 31 days of age), and the last max age can
+/// be `forever`. For example, `14d:1d,26w:1w,forever:1m` keeps daily snapshots for two weeks,
+/// then weekly ones for six months, then monthly ones forever. Snapshots older than the last
+/// rule are deleted. Snapshots are only made daily, so intervals can't be less than a day.
+pub fn parse_schedule(spec: &str) -> Result<Vec<RetentionRule>, String> {
+    let parse_number = |s: &str| -> Result<(u32, char), String> {
+        let unit = s.chars().last().ok_or_else(|| "empty value".to_owned())?;
+        let n = s[.. s.len() - unit.len_utf8()].parse::<u32>()
+            .ok()
+            .filter(|n| *n > 0)
+            .ok_or_else(|| format!("invalid number in {:?}", s))?;
+        Ok((n, unit))
+    };
+
+    let mut rules: Vec<RetentionRule> = vec![];
+    for rule in spec.split(',') {
+        let (max_age, interval) = rule.split_once(':')
+            .ok_or_else(|| format!("rule {:?} should be <max age>:<interval>", rule))?;
+        let max_age_days = match max_age {
+            "forever" => None,
+            _ => Some(match parse_number(max_age)? {
+                (n, 'd') => i64::from(n),
+                (n, 'w') => i64::from(n) * 7,
+                (n, 'm') => i64::from(n) * 31,
+                _ => return Err(format!("unknown unit in {:?}", max_age)),
+            }),
+        };
+        let interval = match parse_number(interval)? {
+            (n, 'd') => RetentionInterval::Days(n),
+            (n, 'w') => RetentionInterval::Weeks(n),
+            (n, 'm') => RetentionInterval::Months(n),
+            _ => return Err(format!("unknown unit in {:?}", interval)),
+        };
+        match rules.last() {
+            Some(RetentionRule { max_age_days: None, .. }) => {
+                return Err("no rules can come after one for 'forever'".to_owned());
+            }
+            Some(RetentionRule { max_age_days: Some(prev), .. })
+                if max_age_days.map(|age| age <= *prev).unwrap_or(false) =>
+            {
+                return Err("rules must be in order of increasing age".to_owned());
+            }
+            _ => (),
+        }
+        rules.push(RetentionRule { max_age_days, interval });
+    }
+    Ok(rules)
+}
+
+#[test]
+fn test_parse_schedule() {
+    assert_eq!(parse_schedule("14d:1d,26w:1w,forever:1m"), Ok(vec![
+        RetentionRule { max_age_days: Some(14), interval: RetentionInterval::Days(1) },
+        RetentionRule { max_age_days: Some(182), interval: RetentionInterval::Weeks(1) },
+        RetentionRule { max_age_days: None, interval: RetentionInterval::Months(1) },
+    ]));
+    assert!(parse_schedule("forever:1m,30d:1d").is_err());
+    assert!(parse_schedule("30d:1d,14d:1w").is_err());
+    assert!(parse_schedule("30d:6h").is_err());
+    assert!(parse_schedule("30d:0d").is_err());
+    assert!(parse_schedule("30d").is_err());
+}
+
+// Whether a snapshot should be deleted under a retention schedule, and if so, why. `snaps` is
+// all the volume's managed snapshots, by date.
+fn scheduled_deletion(schedule: &[RetentionRule],
+                      snaps: &BTreeMap<NaiveDate, String>,
+                      date: NaiveDate,
+                      days_old: i64)
+    -> Option<String>
+{
+    let today = date + chrono::Duration::days(days_old);
+    // Which rule applies to a snapshot of the given date, by index.
+    let rule_for = |date: NaiveDate| {
+        let days_old = today.signed_duration_since(date).num_days();
+        schedule.iter()
+            .position(|rule| rule.max_age_days.map(|max| days_old <= max).unwrap_or(true))
+    };
+    let index = match rule_for(date) {
+        Some(index) => index,
+        None => return Some("older than the schedule keeps".to_owned()),
+    };
+    let rule = &schedule[index];
+    // Only the snapshots under the same rule count; the bucket's older ones may be too old to
+    // keep, and its newer ones may still be under a rule which keeps more.
+    let bucket = rule.interval.bucket(date);
+    let first = snaps.keys()
+        .find(|other| rule.interval.bucket(**other) == bucket && rule_for(**other) == Some(index))
+        .unwrap();
+    if *first == date {
+        None
+    } else {
+        Some(format!("not first of its {} interval ({})", rule.interval, first.format("%Y-%m-%d")))
+    }
+}
+
+#[test]
+fn test_scheduled_deletion() {
+    let date = |s: &str| NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap();
+    let today = date("2021-06-01");
+    let snaps: BTreeMap<NaiveDate, String> = (0..200)
+        .map(|days| today - chrono::Duration::days(days))
+        .map(|d| (d, format!("tank@{}", d)))
+        .collect();
+    let schedule = parse_schedule("7d:1d,30d:1w,90d:1m").unwrap();
+    let kept: Vec<NaiveDate> = snaps.keys()
+        .copied()
+        .filter(|d| {
+            scheduled_deletion(&schedule, &snaps, *d, today.signed_duration_since(*d).num_days())
+                .is_none()
+        })
+        .collect();
+    // The last week, then Mondays back to 30 days, then firsts of months back to 90 days.
+    assert!((0..=7).all(|days| kept.contains(&(today - chrono::Duration::days(days)))));
+    assert!(kept.contains(&date("2021-05-10")) && !kept.contains(&date("2021-05-11")));
+    assert!(kept.contains(&date("2021-04-01")) && !kept.contains(&date("2021-04-02")));
+    assert!(kept.contains(&date("2021-03-03")) && !kept.contains(&date("2021-03-04")));
+    assert!(kept.iter().all(|d| today.signed_duration_since(*d).num_days() <= 90));
+}
+
+/// Decide which snapshots to create and delete, given the names of all the snapshots there are
+/// (like the output of 'zfs list -H -t snapshot -o name'), which of them have holds, and today's
+/// date. `changed` says whether a volume should get a new snapshot (usually because it has
+/// changed since its last one), and `policy` gives each volume's `RetentionPolicy`. This doesn't
+/// touch ZFS at all, so plans can be made from a saved snapshot list.
+pub fn plan_retention(snapshots: &[String],
+                      held: &HashSet<String>,
+                      today: NaiveDate,
+                      options: &AutomanageOptions,
+                      changed: &dyn Fn(&str) -> bool,
+                      policy: &dyn Fn(&str) -> RetentionPolicy)
+    -> RetentionPlan
+{
+    let today_str = format!("{}{:04}-{:02}-{:02}",
+                            options.prefix,
+                            today.year(),
+                            today.month(),
+                            today.day());
+
+    let mut snaps_map: BTreeMap<String, BTreeMap<NaiveDate, String>> = BTreeMap::new();
+    for snap in snapshots {
+        let snap_date = match managed_snapshot_date(snap, &options.prefix) {
+            Some(date) => date,
+            None => continue,
+        };
+
+        let volume = snap.split('@').next().unwrap().to_string();
+        let entry = snaps_map.entry(volume).or_insert_with(BTreeMap::new);
+        entry.insert(snap_date, snap.clone());
+    }
+
+    let mut plan = RetentionPlan::default();
+
+    for (volume, snaps) in snaps_map {
+        let policy = policy(&volume);
+        if policy == RetentionPolicy::Off {
+            continue;
+        }
+        let mut count = 0;
+
+        for (snap_date, snap) in snaps.iter().rev() {
+            count += 1;
+
+            let days_old = (today.signed_duration_since(*snap_date)).num_days();
+
+            if (count == 1) && (days_old != 0) && changed(&volume) {
+                plan.snapshots.push(PlannedSnapshot {
+                    volume: volume.clone(),
+                    name: today_str.clone(),
+                    days_old: 0,
+                    number: 1,
+                    action: RetentionAction::New,
+                });
+                plan.to_create.push(format!("{}@{}", volume, today_str));
+                count += 1;
+            }
+
+            // Give the tuple elements names.
+            struct Pair<'a> {
+                date: &'a NaiveDate,
+                snap: &'a str,
+            }
+
+            const ISO8601_DATE_FMT: &str = "%Y-%m-%d";
 
-    let dateparts: Vec<i32> = datepart.splitn(3, '-')
-                                      .filter_map(|part| {
-                                          part.parse::<i32>()
-                                              .ok()
+            let mut delete = None::<String>; // set to Some(reason) if deletion should happen
+
+            let first_of_month = snaps.iter()
+                                      .map(|(date, snap)| Pair { date, snap })
+                                      .find(|pair| {
+                                          pair.date.year() == snap_date.year() &&
+                                          pair.date.month() == snap_date.month()
                                       })
-                                      .collect();
+                                      .unwrap();
 
-    if dateparts.len() != 3 {
-        return None;
-    }
+            if let Some(ref schedule) = options.schedule {
+                delete = scheduled_deletion(schedule, &snaps, *snap_date, days_old);
+            } else if count > 60 {
+                // Keep only the first snapshot of the month.
+                if first_of_month.snap != snap {
+                    delete = Some(format!("not first of month ({})",
+                        first_of_month.date.format(ISO8601_DATE_FMT)));
+                }
+            } else if count > 30 {
+                // Keep only the first snapshot of the week or month.
+                let first_of_week = snaps.iter()
+                                         .map(|(date, snap)| Pair { date, snap })
+                                         .find(|pair| {
+                                             pair.date.week_of_year() == snap_date.week_of_year()
+                                         })
+                                         .unwrap();
 
-    Some(NaiveDate::from_ymd_opt(dateparts[0], dateparts[1] as u32, dateparts[2] as u32).unwrap())
-}
+                if first_of_week.snap != snap &&
+                   first_of_month.snap != snap {
+                    delete = Some(format!("not first of month ({}) or first of week ({})",
+                        first_of_month.date.format(ISO8601_DATE_FMT),
+                        first_of_week.date.format(ISO8601_DATE_FMT)));
+                }
+            }
 
-trait Succ {
-    fn succ(&self) -> Self;
-}
+            if policy == RetentionPolicy::KeepAll {
+                delete = None;
+            }
 
-trait WeekOfYear {
-    fn week_of_year(&self) -> IsoWeek;
-}
+            let action = match delete {
+                Some(why) if held.contains(snap) => RetentionAction::Held(why),
+                Some(why) if days_old < options.min_age_days => RetentionAction::TooYoung(why),
+                Some(why) => {
+                    plan.to_delete.push(snap.to_string());
+                    RetentionAction::Delete(why)
+                }
+                None => RetentionAction::Keep,
+            };
+            if !matches!(action, RetentionAction::Delete(_)) {
+                plan.kept += 1;
+            }
 
-impl Succ for NaiveDate {
-    fn succ(&self) -> Self {
-        NaiveDate::succ_opt(self).unwrap()
+            plan.snapshots.push(PlannedSnapshot {
+                volume: volume.clone(),
+                name: snap.splitn(2, '@').last().unwrap().to_owned(),
+                days_old,
+                number: count,
+                action,
+            });
+        }
     }
+
+    plan
 }
 
-impl<T: Datelike + Succ> WeekOfYear for T {
-    fn week_of_year(&self) -> IsoWeek {
-        // The original C# version of this program used System.Globalization.Calendar.GetWeekOfYear
-        // for this, using System.Globalization.DateTimeFormatInfo.InvariantInfo for the
-        // parameters.
-        //
-        // "The first week of the year starts on the first day of the year and ends before the
-        // following designated first day of the week."
-        // The first day of the week is designated as Sunday.
-        //
-        // This is similar to the ISO week date, except that ISO week date has:
-        //  - week starting on Monday
-        //  - the first day of the year can be week 53 from the previous year (week 1 is defined as
-        //    being the first week containing January 4 of that year)
-        //
-        // This isn't going to replicate the C# method exactly - the value can be +/- 1 depending
-        // on which year it is for.
-        // Figuring out whether to add 1 or not depending on the year is hard, and this method is
-        // only used for finding the first snapshot in a week, so the difference isn't important,
-        // as long as days in the same week get the same value.
-        // In fact, the C# method wouldn't even uphold this property on the first week of the year
-        // usually -- it would unconditionally change from 53 or 54 to 1 mid-week. This method does
-        // not.
+#[test]
+fn test_plan_retention() {
+    let today = NaiveDate::from_ymd_opt(2021, 6, 1).unwrap();
+    let mut snapshots: Vec<String> = (1..=100)
+        .map(|days| format!("tank@{}", (today - chrono::Duration::days(days)).format("%Y-%m-%d")))
+        .collect();
+    snapshots.push("tank@manual".to_owned());
+    let oldest_held = snapshots[98].clone();
+    let held = std::iter::once(oldest_held.clone()).collect();
 
-        if self.weekday() == Weekday::Sun {
-            // We want weeks starting on Sunday, so if it's Sunday, use the ISO week number for
-            // tomorrow.
-            self.succ().iso_week()
-        } else {
-            self.iso_week()
-        }
-    }
+    let options = AutomanageOptions::default();
+    let standard = |_: &str| RetentionPolicy::Standard;
+    let plan = plan_retention(&snapshots, &held, today, &options, &|_| true, &standard);
+    assert_eq!(plan.to_create, vec!["tank@2021-06-01"]);
+    assert_eq!(plan.snapshots.len(), 101);
+    assert!(plan.to_delete.iter().all(|snap| snapshots.contains(snap)));
+    assert!(!plan.to_delete.contains(&oldest_held));
+    assert!(!plan.to_delete.contains(&"tank@2021-05-31".to_owned()));
+    assert_eq!(plan.kept + plan.to_delete.len(), 100);
+
+    let plan = plan_retention(&snapshots, &held, today, &options, &|_| false, &standard);
+    assert!(plan.to_create.is_empty());
+
+    let plan = plan_retention(&snapshots, &held, today, &options, &|_| true,
+                              &|_| RetentionPolicy::KeepAll);
+    assert_eq!(plan.to_create.len(), 1);
+    assert!(plan.to_delete.is_empty());
+
+    let plan = plan_retention(&snapshots, &held, today, &options, &|_| true,
+                              &|_| RetentionPolicy::Off);
+    assert!(plan.snapshots.is_empty() && plan.to_create.is_empty());
 }
 
 impl ZSnapMgr {
     pub fn new(use_sudo: bool) -> Result<ZSnapMgr, ZfsError> {
         Ok(ZSnapMgr {
             zfs: Zfs::new(use_sudo)?,
+            output: Arc::new(|_| ()),
         })
     }
 
+    /// Use a different program for 'zfs', instead of the one in `ZSNAPMGR_ZFS_BIN` or PATH.
+    pub fn set_zfs_program(&mut self, path: &str) {
+        self.zfs.zfs_program = path.to_owned();
+    }
+
+    /// Use a different program for 'zpool', instead of the one in `ZSNAPMGR_ZPOOL_BIN` or PATH.
+    pub fn set_zpool_program(&mut self, path: &str) {
+        self.zfs.zpool_program = path.to_owned();
+    }
+
+    /// Use a different program for 'sudo', instead of the one in `ZSNAPMGR_SUDO_BIN` or PATH.
+    pub fn set_sudo_program(&mut self, path: &str) {
+        self.zfs.sudo_program = path.to_owned();
+    }
+
+    /// Set a callback to receive `Event`s describing what's going on. By default, they're
+    /// discarded.
+    pub fn set_output(&mut self, output: Box<Output>) {
+        let output: Arc<Output> = Arc::from(output);
+        self.zfs.set_output(Arc::clone(&output));
+        self.output = output;
+    }
+
+    /// Volume and snapshot lists are cached, and only refreshed when zsnapmgr itself creates or
+    /// destroys something. Call this if something else might have changed them.
+    pub fn invalidate_cache(&self) {
+        self.zfs.invalidate_cache();
+    }
+
     pub fn get_volumes(&self) -> Result<Vec<String>, ZfsError> {
         self.zfs.volumes(None)
     }
@@ -107,137 +1755,836 @@ impl ZSnapMgr {
         self.zfs.snapshots(dataset)
     }
 
+    /// Check with libzfs whether a dataset (of any type) exists.
+    pub fn dataset_exists(&self, name: &str) -> bool {
+        self.zfs.dataset_exists(name)
+    }
+
+    /// Check with libzfs that a snapshot exists and can be opened, e.g. to make sure it's usable
+    /// as the base of an incremental send.
+    pub fn snapshot_exists(&self, snapshot: &str) -> bool {
+        self.zfs.snapshot_exists(snapshot)
+    }
+
+    /// Snapshot a dataset now, optionally along with all the volumes under it. The snapshot name
+    /// defaults to today's date. If any of the datasets already has a snapshot by that name, a
+    /// suffix is added to make it unique (see `unique_snapshot_name`). Returns the full names of
+    /// the snapshots created.
+    pub fn snapshot(&self, dataset: &str, name: Option<&str>, recursive: bool)
+        -> Result<Vec<String>, ZfsError>
+    {
+        let name = match name {
+            Some(name) => name.to_owned(),
+            None => Local::now().date_naive().format("%Y-%m-%d").to_string(),
+        };
+
+        let mut datasets = vec![dataset.to_owned()];
+        if recursive {
+            let child_prefix = format!("{}/", dataset);
+            datasets.extend(self.get_volumes()?
+                .into_iter()
+                .filter(|volume| volume.starts_with(&child_prefix)));
+        }
+
+        let existing: HashSet<String> = self.get_snapshots(None)?.into_iter().collect();
+        let name = unique_snapshot_name(&name, &|name| {
+            datasets.iter().any(|dataset| existing.contains(&format!("{}@{}", dataset, name)))
+        });
+
+        let snapshots: Vec<String> = datasets.iter()
+            .map(|dataset| format!("{}@{}", dataset, name))
+            .collect();
+        self.zfs.create_snapshots(snapshots.iter())?;
+        Ok(snapshots)
+    }
+
+    /// Check whether a snapshot should be protected from deletion, returning the reasons why (or
+    /// nothing if it's fine to delete). A snapshot is protected if it is the newest snapshot of
+    /// its volume, or if a backup in `backups_dir` was made from it (and so it may be needed as
+    /// the base for the next incremental backup).
+    pub fn destroy_guards(&self, snapshot: &str, backups_dir: Option<&Path>)
+        -> Result<Vec<String>, ZfsError>
+    {
+        let (volume, snap_name) = match snapshot.split_once('@') {
+            Some(parts) => parts,
+            None => return Err(ZfsError::from(format!("{:?} is not a snapshot name", snapshot))),
+        };
+
+        let mut reasons = vec![];
+
+        let snapshots = self.get_snapshots(Some(volume))?;
+        if !snapshots.iter().any(|snap| snap == snapshot) {
+            return Err(ZfsError::from(format!("snapshot {:?} does not exist", snapshot)));
+        }
+        if snapshots.last().map(String::as_str) == Some(snapshot) {
+            reasons.push(format!("it is the newest snapshot of {}", volume));
+        }
+
+        if let Some(dir) = backups_dir {
+            if let Some(file) = BackupBases::scan(dir)?.referencing(volume, snap_name) {
+                reasons.push(format!("it is the base of backup {:?}", file));
+            }
+        }
+
+        Ok(reasons)
+    }
+
+    /// Find a volume's snapshots which aren't named the way `snapshot_automanage` names them
+    /// (with the given prefix), and work out what to rename them to so that they are: each is
+    /// named for the day it was made.
+    pub fn adoption_candidates(&self, volume: &str, prefix: &str)
+        -> Result<Vec<Adoption>, ZfsError>
+    {
+        let mut snapshots: Vec<(i64, String)> = self.zfs.snapshot_creation_times(volume)?
+            .into_iter()
+            .map(|(name, time)| (time, name))
+            .collect();
+        snapshots.sort();
+        let snapshots: Vec<(String, NaiveDate)> = snapshots.into_iter()
+            .filter_map(|(time, name)| {
+                Some((name, Local.timestamp_opt(time, 0).single()?.date_naive()))
+            })
+            .collect();
+        Ok(plan_adoption(&snapshots, prefix))
+    }
+
+    /// Give a snapshot a new name (the part after the '@').
+    pub fn rename_snapshot(&self, snapshot: &str, new_name: &str) -> Result<(), ZfsError> {
+        self.zfs.rename_snapshot(snapshot, new_name)
+    }
+
+    /// Send up to `sample_size` bytes of a snapshot through each of the given compressors (shell
+    /// command lines, like those in `BENCHMARK_COMPRESSORS`), to see how well and how fast each
+    /// compresses it. Returns how big the sample actually was, which is less than `sample_size`
+    /// if the whole stream is smaller than that.
+    pub fn benchmark_compression(&self, snapshot: &str, sample_size: u64, compressors: &[&str])
+        -> Result<(u64, Vec<CompressionBenchmark>), ZfsError>
+    {
+        let sample = self.zfs.send_sample(snapshot, sample_size, &*self.output)?;
+        let results = compressors.iter()
+            .map(|compressor| {
+                (self.output)(&Event::Running(compressor));
+                CompressionBenchmark {
+                    compressor: (*compressor).to_owned(),
+                    result: run_compressor(compressor, &sample),
+                }
+            })
+            .collect();
+        Ok((sample.len() as u64, results))
+    }
+
+    /// Find out how healthy a pool is.
+    pub fn pool_health(&self, pool: &str) -> Result<PoolState, ZfsError> {
+        self.zfs.pool_health(pool)
+    }
+
+    /// List the files which changed between two snapshots of the same volume.
+    pub fn diff(&self, from: &str, to: &str) -> Result<Vec<DiffEntry>, ZfsError> {
+        self.zfs.diff(from, to)
+    }
+
+    /// Place a hold on a snapshot, which prevents it from being destroyed, including by
+    /// `snapshot_automanage`.
+    pub fn hold(&self, snapshot: &str, tag: &str) -> Result<(), ZfsError> {
+        self.zfs.hold(snapshot, tag)
+    }
+
+    pub fn release(&self, snapshot: &str, tag: &str) -> Result<(), ZfsError> {
+        self.zfs.release(snapshot, tag)
+    }
+
+    pub fn destroy_snapshot(&self, snapshot: &str) -> Result<(), ZfsError> {
+        self.zfs.destroy_snapshots(std::iter::once(snapshot))
+    }
+
+    /// Snapshot several datasets at exactly the same point in time (in the same transaction
+    /// group), so they are consistent with each other. ZFS can only do this within a pool, so the
+    /// datasets must all be in the same pool.
+    pub fn snapshot_atomic(&self, datasets: &[&str], name: &str) -> Result<Vec<String>, ZfsError> {
+        let pool = |dataset: &str| dataset.split('/').next().unwrap_or(dataset).to_owned();
+        if let Some(first) = datasets.first() {
+            if let Some(other) = datasets.iter().find(|ds| pool(ds) != pool(first)) {
+                return Err(ZfsError::from(format!(
+                    "can't atomically snapshot datasets in different pools ({} and {})",
+                    first, other)));
+            }
+        }
+
+        let snapshots: Vec<String> = datasets.iter()
+            .map(|dataset| format!("{}@{}", dataset, name))
+            .collect();
+        self.zfs.create_snapshots(snapshots.iter())?;
+        Ok(snapshots)
+    }
+
+    /// Summarize the date-named snapshots of every volume.
+    pub fn summarize_volumes(&self) -> Result<Vec<VolumeSummary>, ZfsError> {
+        let mut summaries: BTreeMap<String, VolumeSummary> = self.zfs.volumes_typed(None)?
+            .into_iter()
+            .map(|(volume, kind)| (volume.clone(), VolumeSummary {
+                volume,
+                zvol: kind == DatasetType::Volume,
+                snapshot_count: 0,
+                latest: None,
+            }))
+            .collect();
+
+        for snap in self.get_snapshots(None)? {
+            let date = match date_from_snapshot(&snap) {
+                Some(date) => date,
+                None => continue,
+            };
+            let volume = snap.split('@').next().unwrap();
+            if let Some(summary) = summaries.get_mut(volume) {
+                summary.snapshot_count += 1;
+                if summary.latest.map(|latest| latest < date).unwrap_or(true) {
+                    summary.latest = Some(date);
+                }
+            }
+        }
+
+        Ok(summaries.into_values().collect())
+    }
+
+    /// Estimate how big the stream for a backup would be, before compression and encryption. The
+    /// incremental start is a snapshot name without the volume, as for `backup`.
+    pub fn estimate_backup_size(&self, snapshot: &str, incremental_start: Option<&str>,
+                                options: &BackupOptions)
+        -> Result<u64, ZfsError>
+    {
+        self.zfs.estimate_send_size(snapshot, incremental_start, &options.send)
+    }
+
+    /// Back up a snapshot to `path` (and `options.extra_dirs`). Returns the paths of the backup
+    /// files which were written; see `SendOptions::keep_going_on_destination_error`.
     pub fn backup(&self,
                   path: &Path,
                   snapshot: &str,
                   passphrase: &str,
-                  incremental_start: Option<&str>)
-                  -> Result<(), ZfsError> {
+                  incremental_start: Option<&str>,
+                  options: &BackupOptions)
+                  -> Result<Vec<PathBuf>, ZfsError> {
+        self.require_pipeline_tools(!options.skip_zstd)?;
+        let prepare_destination = |dir: &Path| -> Result<PathBuf, ZfsError> {
+            check_writable_dir(dir)?;
+            if !options.allow_same_pool {
+                self.check_other_pool(dir, snapshot)?;
+            }
+            let file_path = options.file_path(dir, snapshot)?;
+            if let Some(parent) = file_path.parent().filter(|_| !options.send.dry_run) {
+                zfstry!(fs::create_dir_all(parent),
+                    or format!("failed to create backup directory {:?}", parent));
+            }
+            Ok(file_path)
+        };
+        let destination_path = prepare_destination(path)?;
+        let mut send_options = options.send.clone();
+        for dir in &options.extra_dirs {
+            send_options.extra_destinations.push(prepare_destination(dir)?);
+        }
+
+        let cipher = options.cipher.as_deref().map(gpg_cipher).transpose()?;
+        if let Some(cipher) = cipher {
+            send_options.manifest_entries.push(("cipher".to_owned(), cipher.to_owned()));
+        }
+        if options.skip_zstd {
+            send_options.manifest_entries.push(("zstd".to_owned(), "false".to_owned()));
+        }
+        if let Some(ref recipient) = options.recipient {
+            send_options.manifest_entries.push(("recipient".to_owned(), recipient.clone()));
+        }
+
         let mut passphrase_pipe =
             zfstry!(InheritablePipe::new(), or "failed to create passphrase pipe");
 
         zfstry!(write!(passphrase_pipe, "{}\n", passphrase), or "failed to write passphrase to pipe");
 
-        let destination_path = path.join(OsString::from(snapshot.replace('/', "_") + ".zfs.zst.gpg"));
+        let encryption = match options.recipient {
+            Some(ref recipient) => format!("--encrypt --recipient {}", shell_quote(recipient)),
+            None => format!("--symmetric --passphrase-fd {}", passphrase_pipe.child_fd()),
+        };
+
+        if options.encrypt_metadata {
+            let encryption = MetadataEncryption {
+                recipient: options.recipient.clone(),
+                passphrase: passphrase.to_owned(),
+                cipher,
+            };
+            send_options.metadata_writer = Some(MetadataWriter(Arc::new(
+                move |path, contents| gpg_encrypt_metadata(path, contents, &encryption))));
+        }
 
         self.zfs.send(snapshot,
                       &destination_path,
                       incremental_start,
-                      Some(&format!("zstd -T0 --size-hint=1000000000 | gpg --batch --symmetric --passphrase-fd {} \
-                                     --output -",
-                                    passphrase_pipe.child_fd())))
+                      Some(&format!("{}gpg --batch {} {}{}--output -",
+                                    if options.skip_zstd {
+                                        ""
+                                    } else {
+                                        "zstd -T0 --size-hint=1000000000 | "
+                                    },
+                                    encryption,
+                                    if options.gpg_compression {
+                                        ""
+                                    } else {
+                                        "--compress-algo none "
+                                    },
+                                    cipher.map(|c| format!("--cipher-algo {} ", c))
+                                        .unwrap_or_default())),
+                      &send_options,
+                      &*self.output)
     }
 
-    pub fn snapshot_automanage(&self) -> Result<(), ZfsError> {
-        let today = Local::now().date_naive();
-        let today_str = format!("{:04}-{:02}-{:02}",
-                                today.year(),
-                                today.month(),
-                                today.day());
-
-        let mut all_snaps = self.get_snapshots(None)?;
-        let mut snaps_map: BTreeMap<String, BTreeMap<NaiveDate, String>> = BTreeMap::new();
-        for snap in all_snaps.drain(..) {
-            let snap_date = match date_from_snapshot(&snap) {
-                Some(date) => date,
-                None => continue,
-            };
+    // Refuse to back up a snapshot into a directory on the same pool.
+    fn check_other_pool(&self, path: &Path, snapshot: &str) -> Result<(), ZfsError> {
+        let source_pool = snapshot.split(['/', '@']).next().unwrap();
+        match self.zfs.dataset_containing(path) {
+            Ok(Some(dataset)) if dataset.split('/').next() == Some(source_pool) => {
+                Err(ZfsError::from(format!(
+                    "backup destination {:?} is on {}, in the same pool as {}; use --force to back \
+                     up there anyway", path, dataset, snapshot)))
+            }
+            Ok(_) => Ok(()),
+            Err(e) => {
+                (self.output)(&Event::Warning(&format!(
+                    "couldn't tell which pool backup destination {:?} is on: {}", path, e)));
+                Ok(())
+            }
+        }
+    }
+
+    // zstd is only needed for backups (and restores of backups) compressed with it.
+    fn require_pipeline_tools(&self, needs_zstd: bool) -> Result<(), ZfsError> {
+        let tools: Vec<(&str, &str)> = PIPELINE_TOOLS.iter()
+            .filter(|(tool, _)| needs_zstd || *tool != "zstd")
+            .copied()
+            .collect();
+        require_tools(&tools)?;
+        // With sudo, 'zfs' is looked up in sudo's own secure PATH, not ours.
+        if self.zfs.use_sudo {
+            require_tools(&[(&self.zfs.sudo_program, "sudo")])
+        } else {
+            require_tools(&[(&self.zfs.zfs_program, "zfsutils-linux")])
+        }
+    }
 
-            let volume = snap.split('@').next().unwrap().to_string();
-            let entry = snaps_map.entry(volume).or_insert_with(BTreeMap::new);
-            entry.insert(snap_date, snap);
+    // Warn if a backup's stream uses pool features which the pool it's about to be received into
+    // doesn't have enabled, since 'zfs receive' will then fail only once it gets to them.
+    fn check_stream_features(&self, manifest: &Manifest, target: &str) {
+        let pool = target.split('/').next().unwrap();
+        for feature in STREAM_FEATURES {
+            if manifest.get(feature) != Some("true") {
+                continue;
+            }
+            match self.zfs.pool_feature_enabled(pool, feature) {
+                Ok(true) => (),
+                Ok(false) => (self.output)(&Event::Warning(&format!(
+                    "the backup's stream uses the {} feature, but pool {} doesn't have it \
+                     enabled; receiving it will probably fail", feature, pool))),
+                Err(e) => (self.output)(&Event::Warning(&format!(
+                    "couldn't check whether pool {} has the {} feature enabled: {}",
+                    pool, feature, e))),
+            }
         }
+    }
 
-        let mut to_delete = Vec::<String>::new();
-        let mut to_create = Vec::<String>::new();
+    // Run a backup file back through gpg and zstd (unless its manifest says it wasn't compressed
+    // with zstd) into 'zfs receive'.
+    fn receive_backup(&self,
+                      backup_path: &Path,
+                      target: &str,
+                      passphrase: &str,
+                      receive_args: &[&str])
+                      -> Result<(), ZfsError> {
+        let manifest = read_backup_manifest(backup_path, Some(passphrase)).ok();
+        let zstd = manifest.as_ref()
+            .map(|manifest| manifest.get("zstd") != Some("false"))
+            .unwrap_or(true);
+        self.require_pipeline_tools(zstd)?;
 
-        for (volume, snaps) in snaps_map {
-            let mut count = 0;
+        let mut passphrase_pipe =
+            zfstry!(InheritablePipe::new(), or "failed to create passphrase pipe");
 
-            for (snap_date, snap) in snaps.iter().rev() {
-                count += 1;
+        zfstry!(write!(passphrase_pipe, "{}\n", passphrase), or "failed to write passphrase to pipe");
 
-                let days_old = (today.signed_duration_since(*snap_date)).num_days();
+        if let Some(ref manifest) = manifest {
+            self.check_stream_features(manifest, target);
+        }
 
-                if (count == 1) && (days_old != 0) {
-                    println!("{}\t{}\t0 days old\t#1\t[NEW]", volume, today_str);
-                    to_create.push(format!("{}@{}", volume, today_str));
-                    count += 1;
-                }
+        self.zfs.receive(backup_path,
+                         target,
+                         Some(&format!("gpg --batch --decrypt --passphrase-fd {}{}",
+                                       passphrase_pipe.child_fd(),
+                                       if zstd { " | zstd -d" } else { "" })),
+                         receive_args,
+                         &*self.output)
+    }
+
+    /// Restore a full backup into a new dataset. If the dataset already exists, it's only
+    /// overwritten if `rollback` is set ('zfs receive -F'), in which case the full stream
+    /// replaces it entirely, destroying its current contents and all its snapshots.
+    pub fn restore(&self, backup_path: &Path, target: RestoreTarget, passphrase: &str,
+                   rollback: bool)
+        -> Result<(), ZfsError>
+    {
+        let (dataset, parent, args): (&str, &str, &[&str]) = match target {
+            RestoreTarget::Dataset(dataset) => {
+                let parent = match dataset.rsplit_once('/') {
+                    Some((parent, _)) => parent,
+                    None => return Err(ZfsError::from(format!(
+                        "can't restore to {:?}: it must be inside a pool", dataset))),
+                };
+                (dataset, parent, &[])
+            }
+            RestoreTarget::UnderWithPath(parent) => (parent, parent, &["-d"]),
+            RestoreTarget::UnderWithName(parent) => (parent, parent, &["-e"]),
+        };
+
+        if !self.zfs.dataset_exists(parent) {
+            return Err(ZfsError::from(format!(
+                "can't restore to {:?}: {:?} does not exist", dataset, parent)));
+        }
+        if let RestoreTarget::Dataset(dataset) = target {
+            if !rollback && self.zfs.dataset_exists(dataset) {
+                return Err(ZfsError::from(format!(
+                    "can't restore to {:?}: it already exists", dataset)));
+            }
+        }
 
-                print!("{}\t{}\t{} days old\t#{}",
-                       volume,
-                       snap.splitn(2, '@').last().unwrap(),
-                       days_old,
-                       count);
+        let mut args = args.to_vec();
+        if rollback {
+            args.push("-F");
+        }
+        self.receive_backup(backup_path, dataset, passphrase, &args)
+    }
 
-                // Give the tuple elements names.
-                struct Pair<'a> {
-                    date: &'a NaiveDate,
-                    snap: &'a str,
+    /// Restore a full backup into a temporary read-only dataset in the given pool and mount it, so
+    /// individual files can be copied out of it.
+    pub fn browse(&self, backup_path: &Path, pool: &str, passphrase: &str)
+        -> Result<BrowseDataset<'_>, ZfsError>
+    {
+        if let Ok(manifest) = Manifest::read(&manifest_path(backup_path)) {
+            if let Some(base) = manifest.get("incremental_from") {
+                return Err(ZfsError::from(format!(
+                    "{:?} is an incremental backup (from {}); only full backups can be browsed",
+                    backup_path, base)));
+            }
+            // Catch backups of zvols early if we can, rather than after receiving the whole thing.
+            if let Some(volume) = manifest.get("snapshot").and_then(|s| s.split('@').next()) {
+                let zvol = self.zfs.volumes_typed(None)?
+                    .into_iter()
+                    .any(|(name, kind)| name == volume && kind == DatasetType::Volume);
+                if zvol {
+                    return Err(zvol_browse_error(backup_path, volume));
                 }
+            }
+        }
+
+        let dataset = format!("{}/zsnapmgr-browse-{}", pool, std::process::id());
+        self.receive_backup(backup_path, &dataset, passphrase, &["-u", "-o", "readonly=on"])?;
 
-                const ISO8601_DATE_FMT: &str = "%Y-%m-%d";
+        // From here on, the guard takes care of destroying the dataset if anything fails.
+        let mut browse = BrowseDataset {
+            zfs: &self.zfs,
+            output: &*self.output,
+            dataset,
+            mountpoint: PathBuf::new(),
+        };
+        if self.zfs.is_zvol(&browse.dataset)? {
+            return Err(zvol_browse_error(backup_path, &browse.dataset));
+        }
+        browse.mountpoint = self.zfs.mount(&browse.dataset)?;
+        Ok(browse)
+    }
+
+    /// Find backups under a directory (going by their manifests) whose snapshot no longer exists on
+    /// its volume, and which aren't the base of an incremental backup that's still needed. Each
+    /// volume's newest backup is always kept. Backups of volumes which don't exist (any more, or
+    /// on this machine) are never included, and neither are backups without a manifest.
+    pub fn orphan_backup_files(&self, dir: &Path) -> Result<Vec<PathBuf>, ZfsError> {
+        let backups: Vec<(PathBuf, String, Option<String>)> = find_manifests(dir)?
+            .into_iter()
+            .filter_map(|(path, manifest)| {
+                // Encrypted manifests can't be read, so those backups are left alone too.
+                let manifest = manifest?;
+                let snapshot = manifest.get("snapshot")?.to_owned();
+                let base = manifest.get("incremental_from").map(str::to_owned);
+                Some((manifest_backup_path(&path), snapshot, base))
+            })
+            .collect();
+        let snapshots = self.get_snapshots(None)?.into_iter().collect();
+        let volumes = self.get_volumes()?.into_iter().collect();
+        let mut orphans = orphans(&backups, &snapshots, &volumes);
+        orphans.sort();
+        Ok(orphans)
+    }
 
-                let mut delete = None::<String>; // set to Some(reason) if deletion should happen
+    /// Look in a backup directory for leftovers of in-progress or interrupted backups: `_partial`
+    /// files and their sidecars, and status files (see `--status-file`) ending in `.json`. With
+    /// `recursive`, subdirectories (such as ones made by `--output-name` templates) are looked in
+    /// too.
+    pub fn backup_status(&self, dir: &Path, recursive: bool)
+        -> Result<Vec<BackupState>, ZfsError>
+    {
+        let mut states = vec![];
+        self.find_backup_states(dir, recursive, &mut states)?;
+        states.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(states)
+    }
 
-                let first_of_month = snaps.iter()
-                                          .map(|(date, snap)| Pair { date, snap })
-                                          .find(|pair| {
-                                              pair.date.year() == snap_date.year() &&
-                                              pair.date.month() == snap_date.month()
-                                          })
-                                          .unwrap();
+    fn find_backup_states(&self, dir: &Path, recursive: bool, states: &mut Vec<BackupState>)
+        -> Result<(), ZfsError>
+    {
+        for entry in zfstry!(fs::read_dir(dir), or format!("failed to read directory {:?}", dir)) {
+            let entry = entry?;
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().into_owned();
 
-                if count > 60 {
-                    // Keep only the first snapshot of the month.
-                    if first_of_month.snap != snap {
-                        delete = Some(format!("not first of month ({})",
-                            first_of_month.date.format(ISO8601_DATE_FMT)));
-                    }
-                } else if count > 30 {
-                    // Keep only the first snapshot of the week or month.
-                    let first_of_week = snaps.iter()
-                                             .map(|(date, snap)| Pair { date, snap })
-                                             .find(|pair| {
-                                                 pair.date.week_of_year() == snap_date.week_of_year()
-                                             })
-                                             .unwrap();
-
-                    if first_of_week.snap != snap &&
-                       first_of_month.snap != snap {
-                        delete = Some(format!("not first of month ({}) or first of week ({})",
-                            first_of_month.date.format(ISO8601_DATE_FMT),
-                            first_of_week.date.format(ISO8601_DATE_FMT)));
+            // Symlinks aren't followed, so this can't loop.
+            if recursive && entry.file_type()?.is_dir() {
+                self.find_backup_states(&path, recursive, states)?;
+                continue;
+            }
+
+            let status = if name.ends_with("_partial")
+                || name.ends_with("_partial.sha256sum")
+                || name.ends_with("_partial.sha512sum")
+            {
+                None
+            } else if name.ends_with(".json") {
+                match status::read_status_file(&path) {
+                    Ok(Some(info)) => Some(info),
+                    Ok(None) => continue,
+                    Err(e) => {
+                        (self.output)(&Event::Warning(&format!(
+                            "failed to read {:?}: {}", path, e)));
+                        continue;
                     }
                 }
+            } else {
+                continue;
+            };
+
+            let metadata = entry.metadata()?;
+            states.push(BackupState {
+                path,
+                size: metadata.len(),
+                modified: metadata.modified().ok().map(DateTime::<Local>::from),
+                status,
+            });
+        }
+        Ok(())
+    }
+
+    // Run the post-snapshot hook for a snapshot, warning if it fails.
+    fn run_snapshot_hook(&self, cmd: &str, snapshot: &str) {
+        (self.output)(&Event::Running(&format!("{} {}", cmd, snapshot)));
+        let snapshot = OsStr::new(snapshot);
+        if let Err(e) = run_hook(cmd, &[snapshot], &[("ZSNAPMGR_SNAPSHOT", snapshot)]) {
+            (self.output)(&Event::Warning(&format!("{} (for {:?})", e, snapshot)));
+        }
+    }
 
-                if let Some(why) = delete {
-                    print!("\t[DELETE] {}", why);
-                    to_delete.push(snap.to_string());
+    // Create snapshots, skipping any which already exist. If creating them all at once fails,
+    // they're retried one at a time so one bad snapshot doesn't stop the others.
+    fn create_snapshots_idempotent(&self, names: Vec<String>, existing: &HashSet<String>)
+        -> SnapshotCreation
+    {
+        let mut result = SnapshotCreation::default();
+        let (skipped, to_create): (Vec<String>, Vec<String>) = names.into_iter()
+            .partition(|name| existing.contains(name));
+        result.skipped = skipped;
+
+        if self.zfs.create_snapshots(to_create.iter()).is_ok() {
+            result.created = to_create;
+            return result;
+        }
+
+        for name in to_create {
+            match self.zfs.create_snapshots(std::iter::once(&name)) {
+                Ok(()) => result.created.push(name),
+                Err(e) => result.failed.push((name, e)),
+            }
+        }
+        result
+    }
+
+    // Whether a volume might need a new snapshot: false only if `skip_unchanged` is set and
+    // nothing has been written to it since the last one.
+    fn volume_changed(&self, volume: &str, options: &AutomanageOptions) -> bool {
+        if !options.skip_unchanged {
+            return true;
+        }
+        match self.zfs.written_since_last_snapshot(volume) {
+            Ok(written) => written != 0,
+            Err(e) => {
+                (self.output)(&Event::Warning(&format!(
+                    "couldn't tell if {} has changed, so snapshotting it anyway: {}", volume, e)));
+                true
+            }
+        }
+    }
+
+    // The filesystems which `filter` says shouldn't get new snapshots.
+    fn filtered_filesystems(&self, filter: MountFilter) -> HashSet<String> {
+        let (property, excluded) = match filter.excluded_value() {
+            Some(pair) => pair,
+            None => return HashSet::new(),
+        };
+        match self.zfs.property_values(property) {
+            Ok(values) => values.into_iter()
+                .filter(|(_, (value, _))| value == excluded)
+                .map(|(volume, _)| volume)
+                .collect(),
+            Err(e) => {
+                (self.output)(&Event::Warning(&format!(
+                    "failed to read {} properties, so snapshotting everything: {}", property, e)));
+                HashSet::new()
+            }
+        }
+    }
+
+    /// Work out what `snapshot_automanage` would do, without doing it.
+    pub fn plan_automanage(&self, options: &AutomanageOptions)
+        -> Result<RetentionPlan, ZfsError>
+    {
+        let today = Local::now().date_naive();
+        let all_snaps = self.get_snapshots(None)?;
+        let held = self.zfs.held_snapshots()?;
+        let policies = self.retention_policies();
+        let filtered = self.filtered_filesystems(options.mount_filter);
+        let mut plan = plan_retention(&all_snaps, &held, today, options,
+                                      &|volume| {
+                                          !filtered.contains(volume)
+                                              && self.volume_changed(volume, options)
+                                      },
+                                      &|volume| policies.get(volume).copied().unwrap_or_default());
+        self.apply_destroy_guards(&mut plan, &all_snaps, options.backups_dir.as_deref());
+        Ok(plan)
+    }
+
+    // Keep any snapshot a plan would delete which `destroy_guards` would protect, so that pruning
+    // is held to the same rules as destroying a snapshot by hand. The backups are only looked
+    // through once; if that fails, every deletion is held back.
+    fn apply_destroy_guards(&self, plan: &mut RetentionPlan, all_snaps: &[String],
+                            backups_dir: Option<&Path>)
+    {
+        let mut newest = HashMap::new();
+        for snap in all_snaps {
+            if let Some((volume, _)) = snap.split_once('@') {
+                newest.insert(volume, snap.as_str());
+            }
+        }
+
+        let bases = backups_dir.map(BackupBases::scan).transpose();
+        if let Err(ref e) = bases {
+            (self.output)(&Event::Warning(&format!(
+                "couldn't check which snapshots backups were made from, so not deleting any: {}",
+                e)));
+        }
+
+        for planned in &mut plan.snapshots {
+            let why = match planned.action {
+                RetentionAction::Delete(ref why) => why.clone(),
+                _ => continue,
+            };
+            let snapshot = format!("{}@{}", planned.volume, planned.name);
+            let mut reasons = vec![];
+            if newest.get(planned.volume.as_str()) == Some(&snapshot.as_str()) {
+                reasons.push(format!("it is the newest snapshot of {}", planned.volume));
+            }
+            match bases {
+                Ok(Some(ref bases)) => {
+                    if let Some(file) = bases.referencing(&planned.volume, &planned.name) {
+                        reasons.push(format!("it is the base of backup {:?}", file));
+                    }
                 }
+                Ok(None) => (),
+                Err(ref e) => reasons.push(format!("couldn't check for backups of it: {}", e)),
+            }
+            if !reasons.is_empty() {
+                planned.action =
+                    RetentionAction::Protected(format!("{}; {}", why, reasons.join("; ")));
+                plan.to_delete.retain(|snap| *snap != snapshot);
+                plan.kept += 1;
+            }
+        }
+    }
+
+    /// Write out a plan (from `plan_automanage`) as a shell script which carries it out, with the
+    /// same 'zfs' commands `snapshot_automanage` would run (and the post-snapshot command, if
+    /// there is one), so it can be looked over and run by hand.
+    pub fn retention_script(&self, plan: &RetentionPlan, options: &AutomanageOptions) -> String {
+        let zfs = self.zfs.zfs_shell_command();
+        let mut script = format!("#!/bin/sh\n\
+            # Written by zsnapmgr on {}.\n\
+            # Destroys {} snapshot(s) and creates {}; {} are kept.\n",
+            Local::now().format("%Y-%m-%d %H:%M:%S"), plan.to_delete.len(), plan.to_create.len(),
+            plan.kept);
 
-                println!();
+        if !plan.to_delete.is_empty() {
+            script += "\n";
+        }
+        for snap in &plan.to_delete {
+            script += &format!("{} destroy {}\n", zfs, shell_quote(snap));
+        }
+
+        if !plan.to_create.is_empty() {
+            script += "\n";
+        }
+        for snap in &plan.to_create {
+            let snap = shell_quote(snap);
+            match options.post_snapshot_cmd {
+                Some(ref cmd) => {
+                    script += &format!("{} snapshot {} && ZSNAPMGR_SNAPSHOT={} sh -c {} sh {}\n",
+                                       zfs, snap, snap, shell_quote(&format!("{} \"$@\"", cmd)),
+                                       snap);
+                }
+                None => script += &format!("{} snapshot {}\n", zfs, snap),
             }
         }
+        script
+    }
 
-        for snap in &to_delete {
-            println!("ZFS DELETE {:?}", snap);
-            if let Err(e) = self.zfs.destroy_snapshots(std::iter::once(snap)) {
-                eprintln!("Failed to delete snapshot: {}", e);
+    /// Read each volume's `RetentionPolicy`. Volumes with no policy set anywhere are left out.
+    pub fn retention_policies(&self) -> HashMap<String, RetentionPolicy> {
+        let values = match self.zfs.property_values(RETENTION_PROPERTY) {
+            Ok(values) => values,
+            Err(e) => {
+                (self.output)(&Event::Warning(&format!(
+                    "failed to read {} properties; using the standard policy: {}",
+                    RETENTION_PROPERTY, e)));
+                return HashMap::new();
             }
+        };
+        values.into_iter()
+            .map(|(volume, (value, source))| {
+                let policy = RetentionPolicy::parse(&value).unwrap_or_else(|| {
+                    // Err on the side of not deleting anything.
+                    let from = match source {
+                        PropertySource::Inherited(ancestor) => format!(" (from {})", ancestor),
+                        _ => String::new(),
+                    };
+                    (self.output)(&Event::Warning(&format!(
+                        "{} has an unknown {} of {:?}{}; keeping all its snapshots",
+                        volume, RETENTION_PROPERTY, value, from)));
+                    RetentionPolicy::KeepAll
+                });
+                (volume, policy)
+            })
+            .collect()
+    }
+
+    pub fn snapshot_automanage(&self, options: &AutomanageOptions)
+        -> Result<AutomanageResult, ZfsError>
+    {
+        let plan = self.plan_automanage(options)?;
+        for planned in &plan.snapshots {
+            (self.output)(&Event::Retention(&planned.decision()));
         }
+        let existing: HashSet<String> = self.get_snapshots(None)?.into_iter().collect();
+        let RetentionPlan { to_create, to_delete, kept, .. } = plan;
+        let (to_delete, mut declined) =
+            options.confirm_changes(to_delete, |snap| AutomanageChange::Delete(snap));
+        let (to_create, declined_creations) =
+            options.confirm_changes(to_create, |snap| AutomanageChange::Create(snap));
+        let kept = kept + declined.len();
+        declined.extend(declined_creations);
+
+        let (deleted, delete_failed) = self.destroy_snapshots_resilient(&to_delete);
 
         for snap in &to_create {
-            println!("ZFS SNAPSHOT {}", snap);
+            (self.output)(&Event::Creating(snap));
         }
 
-        self.zfs.create_snapshots(to_create.into_iter())
-            .map_err(|e| {
-                eprintln!("failed to create snapshots: {}", e);
-                e
-            })?;
+        let creation = self.create_snapshots_idempotent(to_create, &existing);
+        if let Some(ref cmd) = options.post_snapshot_cmd {
+            for snap in &creation.created {
+                self.run_snapshot_hook(cmd, snap);
+            }
+        }
 
-        Ok(())
+        if let Some(threshold) = options.snapshot_count_warning {
+            let deleted: HashSet<&String> = to_delete.iter().collect();
+            let mut counts = BTreeMap::<&str, usize>::new();
+            for snap in existing.iter().filter(|snap| !deleted.contains(snap))
+                .chain(creation.created.iter())
+            {
+                *counts.entry(snap.split('@').next().unwrap()).or_insert(0) += 1;
+            }
+            for (volume, count) in counts {
+                if count > threshold {
+                    (self.output)(&Event::Warning(&format!(
+                        "{} has {} snapshots; consider tightening retention", volume, count)));
+                }
+            }
+        }
+
+        Ok(AutomanageResult { creation, deleted, delete_failed, kept, declined })
+    }
+
+    /// Delete just those of the given snapshots which `snapshot_automanage` would delete right
+    /// now anyway: ones which still exist, and which the schedule, holds, and minimum age still
+    /// allow deleting. This is for carrying out a list of deletions made earlier from
+    /// `plan_automanage`, once it has been approved. Any others on the list are left alone, with
+    /// a warning, and no snapshots are created.
+    pub fn delete_approved(&self, options: &AutomanageOptions, approved: &[String])
+        -> Result<AutomanageResult, ZfsError>
+    {
+        let plan = self.plan_automanage(options)?;
+        let existing: HashSet<String> = self.get_snapshots(None)?.into_iter().collect();
+        let planned: HashSet<&String> = plan.to_delete.iter().collect();
+        let mut to_delete = vec![];
+        for snap in approved {
+            if !existing.contains(snap) {
+                (self.output)(&Event::Warning(&format!(
+                    "not deleting {}: it no longer exists", snap)));
+            } else if !planned.contains(snap) {
+                (self.output)(&Event::Warning(&format!(
+                    "not deleting {}: automanage would no longer delete it", snap)));
+            } else if !to_delete.contains(snap) {
+                to_delete.push(snap.clone());
+            }
+        }
+        let (to_delete, declined) =
+            options.confirm_changes(to_delete, |snap| AutomanageChange::Delete(snap));
+
+        let (deleted, delete_failed) = self.destroy_snapshots_resilient(&to_delete);
+        Ok(AutomanageResult {
+            creation: SnapshotCreation::default(),
+            deleted,
+            delete_failed,
+            kept: plan.kept + plan.to_delete.len() - to_delete.len(),
+            declined,
+        })
+    }
+
+    // Destroy snapshots, returning which were deleted and which couldn't be. Everything is tried
+    // in one go, but if that fails, they're done one at a time so that one problematic snapshot
+    // doesn't stop the rest from being deleted.
+    fn destroy_snapshots_resilient(&self, to_delete: &[String]) -> (Vec<String>, Vec<String>) {
+        for snap in to_delete {
+            (self.output)(&Event::Destroying(snap));
+        }
+
+        let mut deleted = vec![];
+        let mut delete_failed = vec![];
+        if let Err(e) = self.zfs.destroy_snapshots(to_delete.iter()) {
+            (self.output)(&Event::Warning(&format!(
+                "Failed to delete snapshots in bulk ({}); retrying individually.", e)));
+            for snap in to_delete {
+                match self.zfs.destroy_snapshots(std::iter::once(snap)) {
+                    Ok(()) => deleted.push(snap.clone()),
+                    Err(e) => {
+                        (self.output)(&Event::Warning(&format!(
+                            "Failed to delete snapshot {:?}: {}", snap, e)));
+                        delete_failed.push(snap.clone());
+                    }
+                }
+            }
+        } else {
+            deleted = to_delete.to_vec();
+        }
+        (deleted, delete_failed)
     }
 }
@@ -0,0 +1,89 @@
+// Lock :: Keeps more than one zsnapmgr from working on the same pools at once.
+//
+// Copyright (c) 2021 by William R. Fraser
+//
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// What a running zsnapmgr locks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LockScope {
+    /// One lock for everything: only one zsnapmgr can make changes at a time.
+    Global,
+    /// A lock for each pool, so runs working on different pools don't get in each other's way.
+    PerPool,
+    /// Don't lock at all.
+    Disabled,
+}
+
+/// A set of held locks, which are released when this is dropped.
+pub struct Lock {
+    _files: Vec<fs::File>,
+}
+
+// Where lock files go: somewhere shared between users, since cron jobs may run as root while
+// someone runs zsnapmgr interactively via sudo.
+fn lock_dir() -> PathBuf {
+    let run_lock = PathBuf::from("/run/lock");
+    if run_lock.is_dir() {
+        run_lock
+    } else {
+        std::env::temp_dir()
+    }
+}
+
+// Open a lock file, creating it if need be. flock only needs it open for reading, so whichever
+// user creates it, other users can still take the lock, as long as it's readable by everyone.
+fn open_lock_file(path: &Path) -> io::Result<fs::File> {
+    match fs::OpenOptions::new().read(true).open(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+        result => return result,
+    }
+    match fs::OpenOptions::new().create_new(true).write(true).mode(0o644).open(path) {
+        Ok(file) => {
+            // The umask may have taken away more than that.
+            file.set_permissions(fs::Permissions::from_mode(0o644))?;
+            Ok(file)
+        }
+        // Someone else just created it.
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            fs::OpenOptions::new().read(true).open(path)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+impl Lock {
+    /// Take the named locks, in sorted order so that two processes wanting overlapping sets can't
+    /// deadlock. If `wait` is false and any of them is held by another process, this fails with
+    /// `io::ErrorKind::WouldBlock`.
+    pub fn acquire(names: &[String], wait: bool) -> io::Result<Lock> {
+        let mut names = names.to_vec();
+        names.sort();
+        names.dedup();
+
+        let dir = lock_dir();
+        let mut files = vec![];
+        for name in names {
+            let path = dir.join(format!("zsnapmgr-{}.lock", name.replace('/', "_")));
+            let file = open_lock_file(&path)?;
+
+            let operation = if wait { libc::LOCK_EX } else { libc::LOCK_EX | libc::LOCK_NB };
+            loop {
+                if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+                    break;
+                }
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+            files.push(file);
+        }
+        Ok(Lock { _files: files })
+    }
+}
@@ -3,19 +3,33 @@
 // Copyright (c) 2016-2021 by William R. Fraser
 //
 
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::env;
 use std::ffi::{OsStr, OsString};
+use std::fmt;
 use std::fs;
 use std::iter::Iterator;
 use std::io;
-use std::io::{Read, Write};
+use std::io::{BufRead, Read, Write};
 use std::ops::Deref;
-use std::path::Path;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
 
+use chrono::{Local, NaiveDate};
 use regex::Regex;
 use termios::*;
-use zsnapmgr::ZSnapMgr;
+use zsnapmgr::{dataset_depth, date_tree_flat_name, encrypted_metadata_path, find_manifests,
+    human_number, is_backup_data_file, json_string, manifest_backup_path, manifest_path,
+    read_backup_manifest, rehash_file, remove_backup_files, run_hook, snapshot_name_date,
+    AutomanageChange,
+    AutomanageOptions, AutomanageResult, BackupOptions, BackupState, BatchProgress, Confirmation,
+    Event, Manifest, MountFilter, Output, PoolState, Rehash, RestoreTarget, RetentionAction,
+    RetentionDecision, RetentionPolicy, ZSnapMgr, ZfsError, BATCH_PROGRESS_FILE,
+    BENCHMARK_COMPRESSORS, DATE_TREE_TEMPLATE};
 
 mod table;
 use table::Table;
@@ -23,8 +37,21 @@ use table::Table;
 mod backups;
 use backups::{Backup, Backups};
 
+mod lock;
+use lock::{Lock, LockScope};
+
+mod run_config;
+use run_config::{is_due, RunConfig, RunState};
+
 static USE_SUDO: bool = true;
 
+// Set by the --verbose option, to show each libzfs call as it's made.
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+// Set by the --table-separator option: what to put between table columns, or "box" to use
+// box-drawing characters.
+static TABLE_SEPARATOR: OnceLock<String> = OnceLock::new();
+
 // Print and flush.
 macro_rules! printf {
     ( $fmt:expr ) => {
@@ -37,6 +64,202 @@ macro_rules! printf {
     }
 }
 
+// Show what the library is up to, the way the command line tool always has.
+fn print_event(event: &Event, last_line_length: &AtomicUsize) {
+    match event {
+        Event::Running(cmdline) => println!("running: {}", cmdline),
+        Event::DryRun(destination) => {
+            println!("dry run: not running it; would write to {}", destination.display());
+        }
+        Event::SendSize(size) => {
+            println!("Full size: {}B", human_number(*size, 1));
+            if *size == 0 {
+                println!("Empty snapshot; skipping.");
+            }
+        }
+        Event::SendProgress { progress, compression } => {
+            let elapsed = progress.elapsed_seconds;
+            let percent: f64 = if progress.total_size == 0 {
+                0.
+            } else {
+                (progress.sent as f64) / (progress.total_size as f64) * 100.
+            };
+            let mut outline = format!("{:02}:{:02}:{:02} {:.1}% {}B in {}B out ({:.1}% compressed)",
+                                      elapsed / 3600,
+                                      elapsed / 60 % 60,
+                                      elapsed % 60,
+                                      percent,
+                                      human_number(progress.sent, 1),
+                                      human_number(progress.written, 1),
+                                      compression.unwrap_or(0.));
+            // The source rate (uncompressed, from zfs) is what determines how long is left; the
+            // write rate (compressed, to the destination) is only shown for interest.
+            let rates = progress.sent.checked_div(elapsed)
+                .zip(progress.written.checked_div(elapsed));
+            if let Some((source_rate, write_rate)) = rates {
+                outline += &format!(", source {}B/s, write {}B/s",
+                                    human_number(source_rate, 1),
+                                    human_number(write_rate, 1));
+                if source_rate > 0 && progress.total_size > progress.sent {
+                    let eta = (progress.total_size - progress.sent) / source_rate;
+                    outline += &format!(", ETA {:02}:{:02}:{:02}",
+                                        eta / 3600, eta / 60 % 60, eta % 60);
+                }
+            }
+            // Overwrite the previous progress line, blanking out anything left over from it.
+            let spacing = last_line_length.swap(outline.len(), Ordering::Relaxed)
+                .saturating_sub(outline.len());
+            printf!("\r{}{}", outline, " ".repeat(spacing));
+        }
+        Event::ReceiveProgress { total_size, read, elapsed_seconds, .. } => {
+            let elapsed = *elapsed_seconds;
+            let percent: f64 = if *total_size == 0 {
+                0.
+            } else {
+                (*read as f64) / (*total_size as f64) * 100.
+            };
+            let mut outline = format!("{:02}:{:02}:{:02} {:.1}% {}B of {}B read",
+                                      elapsed / 3600,
+                                      elapsed / 60 % 60,
+                                      elapsed % 60,
+                                      percent,
+                                      human_number(*read, 1),
+                                      human_number(*total_size, 1));
+            if let Some(rate) = read.checked_div(elapsed) {
+                outline += &format!(", {}B/s", human_number(rate, 1));
+                if rate > 0 && total_size > read {
+                    let eta = (total_size - read) / rate;
+                    outline += &format!(", ETA {:02}:{:02}:{:02}",
+                                        eta / 3600, eta / 60 % 60, eta % 60);
+                }
+            }
+            let spacing = last_line_length.swap(outline.len(), Ordering::Relaxed)
+                .saturating_sub(outline.len());
+            printf!("\r{}{}", outline, " ".repeat(spacing));
+        }
+        Event::SendFinished | Event::ReceiveFinished => {
+            last_line_length.store(0, Ordering::Relaxed);
+            println!();
+        }
+        Event::Verifying(path) => println!("Verifying {:?}", path),
+        Event::Retention(decision) => println!("{}", retention_line(decision)),
+        Event::Destroying(snapshot) => println!("ZFS DELETE {:?}", snapshot),
+        Event::Creating(snapshot) => println!("ZFS SNAPSHOT {}", snapshot),
+        Event::ZfsCall(call) => {
+            if VERBOSE.load(Ordering::Relaxed) {
+                if last_line_length.load(Ordering::Relaxed) != 0 {
+                    eprintln!();
+                }
+                eprintln!("libzfs: {}", call);
+            }
+        }
+        Event::Warning(msg) => {
+            if last_line_length.load(Ordering::Relaxed) != 0 {
+                eprintln!();
+            }
+            eprintln!("{}", msg);
+        }
+    }
+}
+
+fn retention_line(decision: &RetentionDecision) -> String {
+    let line = format!("{}\t{}\t{} days old\t#{}",
+                       decision.volume,
+                       decision.name,
+                       decision.days_old,
+                       decision.number);
+    match decision.action {
+        RetentionAction::New => line + "\t[NEW]",
+        RetentionAction::Keep => line,
+        RetentionAction::Delete(ref why) => format!("{}\t[DELETE] {}", line, why),
+        RetentionAction::Held(ref why) => format!("{}\tskipped (held) {}", line, why),
+        RetentionAction::TooYoung(ref why) => format!("{}\tskipped (too new) {}", line, why),
+        RetentionAction::Protected(ref why) => format!("{}\tskipped (protected) {}", line, why),
+    }
+}
+
+// Whether a retention decision creates or deletes a snapshot.
+fn is_change(action: &RetentionAction) -> bool {
+    matches!(action, RetentionAction::New | RetentionAction::Delete(_))
+}
+
+/// Retention decisions for one volume, held back until it's known whether any of them create or
+/// delete a snapshot, so that volumes where nothing changes can be left out (see
+/// `automanage --changes-only`).
+#[derive(Default)]
+struct ChangedVolumes {
+    volume: Option<String>,
+    lines: Vec<String>,
+    changed: bool,
+    unchanged_count: usize,
+}
+
+impl ChangedVolumes {
+    fn push(&mut self, decision: &RetentionDecision) {
+        if self.volume.as_deref() != Some(decision.volume) {
+            self.flush();
+            self.volume = Some(decision.volume.to_owned());
+        }
+        self.changed |= is_change(&decision.action);
+        self.lines.push(retention_line(decision));
+    }
+
+    /// Print the held-back volume's decisions if it has any changes, or else just count it.
+    fn flush(&mut self) {
+        if self.volume.take().is_none() {
+            return;
+        }
+        if self.changed {
+            for line in &self.lines {
+                println!("{}", line);
+            }
+        } else {
+            self.unchanged_count += 1;
+        }
+        self.lines.clear();
+        self.changed = false;
+    }
+}
+
+#[test]
+fn test_changed_volumes() {
+    let decision = |volume, action| RetentionDecision {
+        volume,
+        name: "2021-01-01",
+        days_old: 0,
+        number: 1,
+        action,
+    };
+    let mut changed_volumes = ChangedVolumes::default();
+    changed_volumes.push(&decision("tank/a", RetentionAction::Keep));
+    changed_volumes.push(&decision("tank/a", RetentionAction::Keep));
+    changed_volumes.push(&decision("tank/b", RetentionAction::Keep));
+    changed_volumes.push(&decision("tank/b", RetentionAction::New));
+    changed_volumes.push(&decision("tank/c", RetentionAction::Held("why".to_owned())));
+    changed_volumes.flush();
+    assert_eq!(changed_volumes.unchanged_count, 2);
+    assert!(changed_volumes.lines.is_empty());
+}
+
+fn new_zsnapmgr_with_output(output: Box<Output>) -> ZSnapMgr {
+    let mut z = ZSnapMgr::new(USE_SUDO).expect("unable to initialize libzfs");
+    z.set_output(output);
+    z
+}
+
+fn new_zsnapmgr() -> ZSnapMgr {
+    let last_line_length = AtomicUsize::new(0);
+    new_zsnapmgr_with_output(Box::new(move |event| print_event(event, &last_line_length)))
+}
+
+fn new_table(headers: &[&str]) -> Table {
+    match TABLE_SEPARATOR.get().map(String::as_str) {
+        None => Table::new(headers),
+        Some("box") => Table::new(headers).box_drawing(),
+        Some(separator) => Table::new(headers).with_separator(separator),
+    }
+}
+
 trait OptionDeref<T: Deref> {
     fn as_deref(&self) -> Option<&T::Target>;
 }
@@ -94,6 +317,246 @@ fn enumerate_files(path: &Path) -> Result<Box<dyn Iterator<Item = String>>, io::
     Ok(Box::new(iter))
 }
 
+/// How a file in the backups directory relates to the volumes on the system.
+#[derive(Debug, PartialEq)]
+enum FileMatch {
+    Matched { filename_base: String, volume: String, snapshot: String },
+    Ambiguous { filename_base: String, snapshot: String, candidates: Vec<String> },
+    NoMatch,
+    Malformed,
+    Partial,
+    NotBackup,
+}
+
+fn classify_backup_file(file_path: &str, volumes: &[String]) -> FileMatch {
+    let zfs_pos = match file_path.find(".zfs") {
+        Some(pos) => pos,
+        None => return FileMatch::NotBackup,
+    };
+
+    if file_path.ends_with("_partial") {
+        return FileMatch::Partial;
+    }
+
+    let parts = file_path[0..zfs_pos].splitn(2, '@').collect::<Vec<&str>>();
+    if parts.len() != 2 {
+        return FileMatch::Malformed;
+    }
+    let filename_base = parts[0];
+    let backup_snap = parts[1].to_string();
+    let matched = |volume: &str| FileMatch::Matched {
+        filename_base: filename_base.to_string(),
+        volume: volume.to_string(),
+        snapshot: backup_snap.clone(),
+    };
+
+    // Backup filenames have the '/' in volume names replaced with '_', so that's what the
+    // filename gets compared against. In order of preference, look for:
+    //  1. a volume whose name is exactly the filename with '_' turned back into '/'
+    //  2. a volume whose name, with '/' turned into '_', is exactly the filename (this handles
+    //     volume names that contain underscores)
+    //  3. the same, but ignoring case
+    //  4. a unique volume whose name ends with the filename, for backups made with only the
+    //     last part of the volume name.
+
+    let volume_name = filename_base.replace('_', "/");
+    if volumes.contains(&volume_name) {
+        return matched(&volume_name);
+    }
+
+    let normalized: Vec<(&String, String)> = volumes.iter()
+        .map(|vol| (vol, vol.replace('/', "_")))
+        .collect();
+
+    if let Some((vol, _)) = normalized.iter().find(|(_, norm)| norm == filename_base) {
+        return matched(vol);
+    }
+
+    let case_matches: Vec<&String> = normalized.iter()
+        .filter(|(_, norm)| norm.eq_ignore_ascii_case(filename_base))
+        .map(|(vol, _)| *vol)
+        .collect();
+    if case_matches.len() == 1 {
+        return matched(case_matches[0]);
+    }
+
+    let suffix = "_".to_string() + filename_base;
+    let matches: Vec<&String> = normalized.iter()
+        .filter(|(_, norm)| norm.ends_with(&suffix))
+        .map(|(vol, _)| *vol)
+        .collect();
+
+    let ambiguous = |candidates: Vec<&String>| FileMatch::Ambiguous {
+        filename_base: filename_base.to_string(),
+        snapshot: backup_snap.clone(),
+        candidates: candidates.into_iter().cloned().collect(),
+    };
+
+    match matches.len() {
+        0 if case_matches.is_empty() => FileMatch::NoMatch,
+        0 => ambiguous(case_matches),
+        1 => matched(matches[0]),
+        _ => ambiguous(matches),
+    }
+}
+
+#[test]
+fn test_classify_backup_file() {
+    let volumes: Vec<String> = ["tank", "tank/a/data", "tank/b/data", "tank/my_stuff", "pool/Media",
+                                "pool/photos"]
+        .iter().map(|s| s.to_string()).collect();
+
+    let matched = |volume: &str, base: &str| FileMatch::Matched {
+        filename_base: base.to_owned(),
+        volume: volume.to_owned(),
+        snapshot: "2021-01-01".to_owned(),
+    };
+
+    assert_eq!(classify_backup_file("tank_a_data@2021-01-01.zfs.zst.gpg", &volumes),
+               matched("tank/a/data", "tank_a_data"));
+    assert_eq!(classify_backup_file("tank_b_data@2021-01-01.zfs.zst.gpg", &volumes),
+               matched("tank/b/data", "tank_b_data"));
+    assert_eq!(classify_backup_file("b_data@2021-01-01.zfs.zst.gpg", &volumes),
+               matched("tank/b/data", "b_data"));
+    assert_eq!(classify_backup_file("data@2021-01-01.zfs.zst.gpg", &volumes),
+               FileMatch::Ambiguous {
+                   filename_base: "data".to_owned(),
+                   snapshot: "2021-01-01".to_owned(),
+                   candidates: vec!["tank/a/data".to_owned(), "tank/b/data".to_owned()],
+               });
+    assert_eq!(classify_backup_file("tank_my_stuff@2021-01-01.zfs.zst.gpg", &volumes),
+               matched("tank/my_stuff", "tank_my_stuff"));
+    assert_eq!(classify_backup_file("pool_media@2021-01-01.zfs.zst.gpg", &volumes),
+               matched("pool/Media", "pool_media"));
+    assert_eq!(classify_backup_file("photos@2021-01-01.zfs.zst.gpg", &volumes),
+               matched("pool/photos", "photos"));
+    assert_eq!(classify_backup_file("other@2021-01-01.zfs.zst.gpg", &volumes), FileMatch::NoMatch);
+    assert_eq!(classify_backup_file("tank@2021-01-01.zfs.zst.gpg_partial", &volumes),
+               FileMatch::Partial);
+    assert_eq!(classify_backup_file("tank.zfs.zst.gpg", &volumes), FileMatch::Malformed);
+    assert_eq!(classify_backup_file("notes.txt", &volumes), FileMatch::NotBackup);
+}
+
+/// Print how each file in the backups directory was matched up with a volume, without doing
+/// anything else.
+fn list_backup_files(z: &ZSnapMgr, path: &Path) -> anyhow::Result<()> {
+    let volumes = z.get_volumes()?;
+    let mut files: Vec<String> = enumerate_files(path)?.collect();
+    files.sort();
+
+    let mut table = new_table(&["file", "classification", "volume", "snapshot"]);
+    for file in files {
+        let (class, volume, snapshot) = match classify_backup_file(&file, &volumes) {
+            FileMatch::Matched { volume, snapshot, .. } => ("matched", volume, snapshot),
+            FileMatch::Ambiguous { candidates, snapshot, .. } => {
+                ("ambiguous", candidates.join(", "), snapshot)
+            }
+            FileMatch::NoMatch => ("no match", String::new(), String::new()),
+            FileMatch::Malformed => ("malformed", String::new(), String::new()),
+            FileMatch::Partial => ("partial", String::new(), String::new()),
+            FileMatch::NotBackup => ("not a backup", String::new(), String::new()),
+        };
+        table.push(vec![file, class.to_owned(), volume, snapshot]);
+    }
+
+    print!("{}", table);
+    Ok(())
+}
+
+// Ask which of several volumes a backup file belongs to.
+fn choose_volume(candidates: &[String]) -> Option<String> {
+    for (i, volume) in candidates.iter().enumerate() {
+        println!("\t{}: {}", i + 1, volume);
+    }
+    printf!("Which volume is it? (number, or <return> to skip it): ");
+
+    let mut input = String::new();
+    io::stdin().read_line(&mut input).ok()?;
+    match input.trim().parse::<usize>() {
+        Ok(n) if n >= 1 && n <= candidates.len() => Some(candidates[n - 1].clone()),
+        _ => None,
+    }
+}
+
+// Find the files in the date-tree layout (`<volume_base>/<YYYY>/<MM>/<file>`) under a backup
+// directory, along with the names they'd have had at the top level (see `date_tree_flat_name`).
+fn date_tree_backup_files(path: &Path) -> Vec<(PathBuf, String)> {
+    let subdirs = |dir: &Path| -> Vec<PathBuf> {
+        match fs::read_dir(dir) {
+            Ok(entries) => entries.filter_map(Result::ok)
+                .filter(|entry| entry.file_type().map(|ft| ft.is_dir()).unwrap_or(false))
+                .map(|entry| entry.path())
+                .collect(),
+            Err(_) => vec![],
+        }
+    };
+
+    let mut files = vec![];
+    for volume_dir in subdirs(path) {
+        for year_dir in subdirs(&volume_dir) {
+            for month_dir in subdirs(&year_dir) {
+                let names = match enumerate_files(&month_dir) {
+                    Ok(names) => names,
+                    Err(e) => {
+                        println!("Error enumerating files in {:?}: {}", month_dir, e);
+                        continue;
+                    }
+                };
+                for name in names {
+                    let file_path = month_dir.join(name);
+                    if let Some(flat_name) = date_tree_flat_name(&file_path) {
+                        files.push((file_path, flat_name));
+                    }
+                }
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+// What's added to a backup's path to get the paths of the files that go with it, starting with the
+// backup itself.
+const BACKUP_METADATA_SUFFIXES: &[&str] = &["", ".sha256sum", ".sha512sum", ".manifest",
+    ".sha256sum.gpg", ".sha512sum.gpg", ".manifest.gpg"];
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+// Whether a file is one of the backups in `described` (which has the paths from
+// BACKUP_METADATA_SUFFIXES for each), or one of their chunks or a chunk's sidecar.
+fn is_described(described: &HashSet<PathBuf>, path: &Path) -> bool {
+    if described.contains(path) {
+        return true;
+    }
+    let path = path.to_string_lossy();
+    BACKUP_METADATA_SUFFIXES.iter()
+        .filter_map(|suffix| path.strip_suffix(suffix)?.rsplit_once('.'))
+        .any(|(backup, chunk)| {
+            !chunk.is_empty() && chunk.chars().all(|c| c.is_ascii_digit())
+                && described.contains(Path::new(backup))
+        })
+}
+
+#[test]
+fn test_is_described() {
+    let described = BACKUP_METADATA_SUFFIXES.iter()
+        .map(|suffix| with_suffix(Path::new("/b/tank"), suffix))
+        .collect();
+    let described = |path: &str| is_described(&described, Path::new(path));
+    assert!(described("/b/tank"));
+    assert!(described("/b/tank.sha256sum"));
+    assert!(described("/b/tank.manifest.gpg"));
+    assert!(described("/b/tank.003"));
+    assert!(described("/b/tank.003.sha512sum"));
+    assert!(!described("/b/tank_a"));
+    assert!(!described("/b/tank.x"));
+    assert!(!described("/b/tank.003.txt"));
+}
+
 fn gather_volumes(z: &ZSnapMgr, path: &Path) -> Vec<Backup> {
     let snapshots: Vec<String> = match z.get_snapshots(None) {
         Ok(s) => s,
@@ -113,6 +576,34 @@ fn gather_volumes(z: &ZSnapMgr, path: &Path) -> Vec<Backup> {
 
     let mut backups = Backups::new();
 
+    // Backups' manifests say exactly which snapshot they were made from, so use those where
+    // possible. This also finds backups in subdirectories (see --output-name), whose names can't
+    // be relied on.
+    let mut described = HashSet::new();
+    match find_manifests(path) {
+        Ok(manifests) => {
+            // Encrypted manifests can't be read; those backups go by their file names below.
+            for (manifest_path, manifest) in manifests {
+                let manifest = match manifest {
+                    Some(manifest) => manifest,
+                    None => continue,
+                };
+                let backup_path = manifest_backup_path(&manifest_path);
+                described.extend(BACKUP_METADATA_SUFFIXES.iter()
+                    .map(|suffix| with_suffix(&backup_path, suffix)));
+                match manifest.get("snapshot").and_then(|s| s.split_once('@')) {
+                    Some((volume, snapshot)) if volumes.iter().any(|v| v == volume) => {
+                        backups.insert(volume.replace('/', "_"),
+                                       volume.to_owned(),
+                                       Some(snapshot.to_owned()));
+                    }
+                    _ => (),
+                }
+            }
+        }
+        Err(e) => println!("Error looking for backup manifests: {}", e),
+    }
+
     let file_iter = match enumerate_files(path) {
         Ok(x) => x,
         Err(e) => {
@@ -121,46 +612,34 @@ fn gather_volumes(z: &ZSnapMgr, path: &Path) -> Vec<Backup> {
         }
     };
 
-    for file_path in file_iter {
-        if let Some(zfs_pos) = file_path.find(".zfs") {
-            if !file_path.ends_with("_partial") {
-                let parts = file_path[0..zfs_pos].splitn(2, '@').collect::<Vec<&str>>();
-                if parts.len() != 2 {
-                    println!("ERROR: malformed ZFS filename: {:?}", file_path);
-                    continue;
-                }
-                let volume_name = parts[0].replace('_', "/");
-                let backup_snap = parts[1];
-
-                if volumes.contains(&volume_name) {
-                    backups.insert(parts[0].to_string(),
-                                   volume_name.to_string(),
-                                   Some(backup_snap.to_string()));
-                } else {
-                    let volume_name_mod = "/".to_string() + &volume_name;
-                    let matches: Vec<&str> = volumes.iter()
-                                                    .filter(|vol| {
-                                                        vol.ends_with(&volume_name_mod)
-                                                    })
-                                                    .map(Deref::deref)
-                                                    .collect();
-
-                    if matches.len() == 1 {
-                        backups.insert(volume_name.to_string(),
-                                       matches[0].to_string(),
-                                       Some(backup_snap.to_string()));
-                    } else {
-                        print!("Backup filename \"{}\" ", file_path);
-                        if matches.len() > 1 {
-                            println!("matches more than one volume.\nIt could be any of: {:?}",
-                                     matches);
-                        } else {
-                            println!("doesn't match any volumes.");
-                        }
-                        println!("Skipping it.\n");
-                    }
+    // Fall back to working it out from the names of files without manifests (or which belong to
+    // one, like hash sidecars). Files in the date-tree layout (see --layout) go by the names
+    // they'd have had at the top level.
+    let files = file_iter.map(|name| (path.join(&name), name))
+        .chain(date_tree_backup_files(path));
+    for (full_path, file_path) in files {
+        if is_described(&described, &full_path) {
+            continue;
+        }
+        match classify_backup_file(&file_path, &volumes) {
+            FileMatch::Matched { filename_base, volume, snapshot } => {
+                backups.insert(filename_base, volume, Some(snapshot));
+            }
+            FileMatch::Malformed => {
+                println!("ERROR: malformed ZFS filename: {:?}", file_path);
+            }
+            FileMatch::Ambiguous { filename_base, snapshot, candidates } => {
+                println!("Backup filename \"{}\" matches more than one volume.", file_path);
+                match choose_volume(&candidates) {
+                    Some(volume) => backups.insert(filename_base, volume, Some(snapshot)),
+                    None => println!("Skipping it.\n"),
                 }
             }
+            FileMatch::NoMatch => {
+                println!("Backup filename \"{}\" doesn't match any volumes.\nSkipping it.\n",
+                         file_path);
+            }
+            FileMatch::Partial | FileMatch::NotBackup => (),
         }
     }
 
@@ -185,6 +664,13 @@ fn gather_volumes(z: &ZSnapMgr, path: &Path) -> Vec<Backup> {
                 println!("Snapshot \"{}\" doesn't exist any more; doing full backup instead.\n",
                          start_snapshot);
                 backup.start_snapshot = None;
+            } else if !z.snapshot_exists(&start_snapshot) {
+                // It's listed, but make sure it can actually be opened, or 'zfs send -i' will
+                // reject it.
+                println!("Snapshot \"{}\" can't be used as an incremental base; doing full \
+                          backup instead.\n",
+                         start_snapshot);
+                backup.start_snapshot = None;
             }
         }
 
@@ -203,18 +689,41 @@ fn gather_volumes(z: &ZSnapMgr, path: &Path) -> Vec<Backup> {
     backups.into_values()
 }
 
+// Parse a size in bytes, with an optional K, M, or G (binary) suffix.
+fn parse_size(s: &str) -> Option<u64> {
+    let (number, multiplier) = match s.chars().last()?.to_ascii_uppercase() {
+        'K' => (&s[.. s.len() - 1], 1 << 10),
+        'M' => (&s[.. s.len() - 1], 1 << 20),
+        'G' => (&s[.. s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+#[test]
+fn test_parse_size() {
+    assert_eq!(parse_size("8192"), Some(8192));
+    assert_eq!(parse_size("64k"), Some(64 * 1024));
+    assert_eq!(parse_size("1M"), Some(1024 * 1024));
+    assert_eq!(parse_size("M"), None);
+    assert_eq!(parse_size(""), None);
+}
+
 fn getpass(prompt: &str) -> io::Result<String> {
-    let mut termios = Termios::from_fd(0).expect("failed to get termios settings");
+    // Read from the terminal directly rather than stdin, which may be in use for something else.
+    let tty = fs::File::open("/dev/tty")?;
+    let fd = tty.as_raw_fd();
+
+    let mut termios = Termios::from_fd(fd).expect("failed to get termios settings");
 
     let old = termios.c_lflag;
     termios.c_lflag &= !ECHO;   // disable echo
     termios.c_lflag &= !ICANON; // disable line-buffering
-    tcsetattr(0, TCSAFLUSH, &termios).expect("failed to set termios settings");
+    tcsetattr(fd, TCSAFLUSH, &termios).expect("failed to set termios settings");
 
     printf!("{}", prompt);
 
-    let stdin = io::stdin();
-    let mut bytes = stdin.lock().bytes();
+    let mut bytes = io::BufReader::new(&tty).bytes();
     let mut line = String::new();
     let mut utf8 = Vec::<u8>::new();
     loop {
@@ -251,177 +760,870 @@ fn getpass(prompt: &str) -> io::Result<String> {
     }
 
     termios.c_lflag = old;
-    tcsetattr(0, TCSAFLUSH, &termios).expect("failed to reset termios settings");
+    tcsetattr(fd, TCSAFLUSH, &termios).expect("failed to reset termios settings");
 
     Ok(line)
 }
 
-fn do_backups(backups: &[Backup], path: &Path) {
-    if backups.is_empty() {
-        println!("Nothing to do.");
-        return;
-    }
+// Set up a full backup of the latest snapshot of a volume, or the latest one whose name matches
+// a pattern.
+fn full_backup_of_latest(z: &ZSnapMgr, vol: &str, pattern: Option<&Regex>)
+    -> Result<Backup, String>
+{
+    let latest_snap: String = match z.get_snapshots(Some(vol))
+       .map(|snaps| {
+            snaps.into_iter()
+                .rev()
+                .filter_map(|full_name| {
+                    full_name.rsplit('@')
+                        .next()
+                        .map(|s| s.to_owned())
+                })
+                .find(|name| match pattern {
+                    Some(pattern) => pattern.is_match(name),
+                    None => true,
+                })
+        })
+    {
+        Ok(Some(date)) => date,
+        Ok(None) if pattern.is_some() => {
+            return Err("No matching snapshots available for that volume.".to_owned());
+        }
+        Ok(None) => return Err("No snapshots available for that volume.".to_owned()),
+        Err(e) => return Err(format!("Error listing snapshots: {}", e)),
+    };
 
-    let passphrase: String;
-    loop {
-        let pass1 = getpass("GPG passphrase: ").unwrap();
-        let pass2 = getpass("again: ").unwrap();
-        if pass1 != pass2 {
-            println!("Passphrases do not match.");
-        } else {
-            passphrase = pass1;
-            break;
+    Ok(Backup {
+        filename_base: vol.replace('/', "_"),
+        volume: vol.to_owned(),
+        start_snapshot: None,
+        end_snapshot: Some(latest_snap),
+        recipient: None,
+        passphrase_file: None,
+    })
+}
+
+// Turn a wildcard pattern ('*' for any run of characters, '?' for any one character) into a regex
+// matching whole names.
+fn glob_regex(pattern: &str) -> Regex {
+    let mut regex = String::from("^");
+    for c in pattern.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            c => regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
         }
     }
+    regex.push('$');
+    Regex::new(&regex).unwrap()
+}
 
-    for backup in backups {
-        let z = ZSnapMgr::new(USE_SUDO).expect("unable to initialize libzfs");
+fn is_glob(s: &str) -> bool {
+    s.contains(['*', '?'])
+}
 
-        let snapshot = format!("{}@{}",
-                               backup.volume,
-                               backup.end_snapshot.as_deref().unwrap());
+#[test]
+fn test_glob_regex() {
+    let regex = glob_regex("tank/*");
+    assert!(regex.is_match("tank/home") && regex.is_match("tank/home/user"));
+    assert!(!regex.is_match("tank") && !regex.is_match("pool/tank/home"));
+    let regex = glob_regex("2021-0?-*");
+    assert!(regex.is_match("2021-03-01") && !regex.is_match("2021-10-01"));
+    assert!(glob_regex("a.b").is_match("a.b") && !glob_regex("a.b").is_match("axb"));
+}
 
-        println!("\nBacking up: {}", snapshot);
+// Set up full backups from what was typed to add: a volume, to back up its latest snapshot, or
+// `volume@pattern`, to back up its latest snapshot matching the pattern. The volume can be a
+// pattern too, which adds every volume it matches which has a matching snapshot, and returns the
+// ones which don't have one alongside.
+fn full_backups_matching(z: &ZSnapMgr, input: &str)
+    -> Result<(Vec<Backup>, Vec<String>), String>
+{
+    let (volume, snapshot) = match input.split_once('@') {
+        Some((volume, snapshot)) => (volume, Some(snapshot)),
+        None => (input, None),
+    };
+    let snapshot = snapshot.map(glob_regex);
+    if !is_glob(volume) {
+        return full_backup_of_latest(z, volume, snapshot.as_ref())
+            .map(|backup| (vec![backup], vec![]));
+    }
 
-        if let Err(e) = z.backup(
-            path,
-            &snapshot,
-            &passphrase,
-            backup.start_snapshot.as_deref(),
-        ) {
-            println!("failed backup of {}: {}", backup.volume, e);
+    let pattern = glob_regex(volume);
+    let volumes: Vec<String> = z.get_volumes()
+        .map_err(|e| format!("Error listing volumes: {}", e))?
+        .into_iter()
+        .filter(|volume| pattern.is_match(volume))
+        .collect();
+    if volumes.is_empty() {
+        return Err(format!("No volumes match {:?}.", volume));
+    }
+    let mut backups = vec![];
+    let mut skipped = vec![];
+    for volume in volumes {
+        match full_backup_of_latest(z, &volume, snapshot.as_ref()) {
+            Ok(backup) => backups.push(backup),
+            Err(e) => skipped.push(format!("{}: {}", volume, e)),
         }
     }
+    Ok((backups, skipped))
 }
 
-fn interactive_backup(backups_dir: &Path) {
-    let z = ZSnapMgr::new(USE_SUDO).expect("unable to initialize libzfs");
-    let mut backups: Vec<Backup> = gather_volumes(&z, backups_dir);
-    loop {
-        let mut table = Table::new(&["_", "volume", "incremental", "snapshot date"]);
-        for (i, backup) in backups.iter().enumerate() {
-            let start = if backup.start_snapshot.is_none() {
-                "full backup".to_string()
-            } else {
-                backup.start_snapshot.as_ref().unwrap().clone()
-            };
-
-            table.push(vec![(i + 1).to_string(),
-                            backup.volume.clone(),
-                            start,
-                            backup.end_snapshot.as_ref().unwrap().clone()]);
+// Back up the latest snapshot of each volume named on stdin, one per line.
+fn backup_from_stdin(backups_dir: &Path, options: &BatchOptions) -> anyhow::Result<()> {
+    let z = new_zsnapmgr();
+    let mut backups = vec![];
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        let vol = line.trim();
+        if vol.is_empty() {
+            continue;
         }
+        match full_backup_of_latest(&z, vol, None) {
+            Ok(backup) => backups.push(backup),
+            Err(e) => println!("Skipping {:?}: {}", vol, e),
+        }
+    }
+    do_backups(&backups, backups_dir, options)
+}
 
-        println!("Volumes to backup:\n{}", table);
-
-        printf!(concat!("Enter a number to make changes,\n",
-                         "\t'+' to add a volume,\n",
-                         "\t'-' to remove one,\n",
-                         "\t'd' to change all dates,\n",
-                         "\tor <return> to start backup: "));
+// Do an incremental backup of the latest snapshot of a volume, starting from the snapshot an
+// existing backup file was made from.
+fn backup_incremental_from_file(backups_dir: &Path, base_file: &Path, options: &BatchOptions)
+    -> anyhow::Result<()>
+{
+    let z = new_zsnapmgr();
+    let volumes = z.get_volumes()?;
+    let filename = match base_file.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name,
+        None => anyhow::bail!("{:?} is not a backup file name", base_file),
+    };
 
-        let mut input = String::new();
-        match io::stdin().read_line(&mut input) {
-            Ok(bytes_read) => {
-                if bytes_read == 0 {
-                    panic!("EOF");
-                }
-                input.pop();
+    let (volume, start) = match classify_backup_file(filename, &volumes) {
+        FileMatch::Matched { volume, snapshot, .. } => (volume, snapshot),
+        FileMatch::Ambiguous { snapshot, candidates, .. } => {
+            println!("Backup filename \"{}\" matches more than one volume.", filename);
+            match choose_volume(&candidates) {
+                Some(volume) => (volume, snapshot),
+                None => anyhow::bail!("no volume chosen"),
             }
-            Err(e) => panic!("{}", e),
         }
+        FileMatch::NoMatch => anyhow::bail!("{:?} doesn't match any volume", filename),
+        FileMatch::Partial => anyhow::bail!("{:?} is an incomplete backup", filename),
+        FileMatch::Malformed | FileMatch::NotBackup => {
+            anyhow::bail!("{:?} is not a backup file name", filename)
+        }
+    };
 
-        if input == "+" {
-            printf!("Volume: ");
+    let start_snapshot = format!("{}@{}", volume, start);
+    if !z.snapshot_exists(&start_snapshot) {
+        anyhow::bail!("snapshot {} no longer exists, so can't be used as an incremental base",
+                      start_snapshot);
+    }
 
-            let mut vol = String::new();
-            io::stdin().read_line(&mut vol).unwrap();
-            vol.pop();
+    let mut backup = full_backup_of_latest(&z, &volume, None).map_err(anyhow::Error::msg)?;
+    if backup.end_snapshot.as_deref() == Some(&start) {
+        println!("Backup of \"{}\" is up to date (@{}).", volume, start);
+        return Ok(());
+    }
+    backup.start_snapshot = Some(start);
+    do_backups(&[backup], backups_dir, options)
+}
 
-            let latest_snap: String = match z.get_snapshots(Some(&vol))
-               .map(|ref mut snaps| {
-                    snaps.pop()
-                        .and_then(|full_name| {
-                            full_name.rsplit('@')
-                                .next()
-                                .map(|s| s.to_owned())
-                        })
-                })
-            {
-                Ok(Some(date)) => date,
-                Ok(None) => {
-                    println!("No snapshots available for that volume.\n");
-                    continue;
-                }
-                Err(e) => {
-                    println!("Error listing snapshots: {}\n", e);
-                    continue;
-                }
-            };
+/// Options for a batch of backups.
+#[derive(Debug, Default)]
+struct BatchOptions {
+    backup: BackupOptions,
 
-            backups.push(Backup {
-                filename_base: vol.replace('/', "_").to_string(),
-                volume: vol.clone(),
-                start_snapshot: None,
-                end_snapshot: Some(latest_snap),
-            });
+    /// Do full backups, even where an incremental backup is possible.
+    force_full: bool,
 
-        } else if input.starts_with('-') {
+    /// Skip any backup which would have to be a full one, leaving those for a separate run.
+    only_incremental: bool,
 
-            let index: usize;
+    /// How to order the volumes in the interactive list.
+    sort: SortOrder,
 
-            if input.len() > 1 {
-                match input[1..].parse::<usize>() {
-                    Ok(n) => {
-                        index = n;
-                    },
-                    Err(e) => {
-                        println!("Invalid number: {}\n", e);
-                        continue;
-                    }
-                }
-            } else {
-                printf!("Remove which one?: ");
+    /// Leave pools' root datasets out of the interactive list.
+    skip_pool_roots: bool,
 
-                input.clear();
-                io::stdin().read_line(&mut input).unwrap();
-                input.pop();
+    /// Redo backups which an earlier, interrupted run of the batch already finished (see
+    /// `BatchProgress`).
+    restart: bool,
 
-                match input.parse::<usize>() {
-                    Ok(n) => {
-                        index = n;
-                    },
-                    Err(e) => {
-                        println!("Invalid number: {}\n", e);
-                        continue;
-                    }
-                }
-            }
+    /// Stop at the first failed backup instead of carrying on with the rest.
+    fail_fast: bool,
 
-            if backups.len() < index || index == 0 {
-                println!("Number out of range.\n");
-                continue;
-            }
+    /// Back up from pools which aren't healthy (see `check_pool_health`).
+    ignore_pool_health: bool,
 
-            backups.remove(index - 1);
+    /// Shell commands to run before and after each backup (see `run_backup_hook`).
+    pre_backup_cmd: Option<String>,
+    post_backup_cmd: Option<String>,
 
-        } else if input.starts_with('d') || input.starts_with('D') {
+    /// Count a failed hook as a failed backup, rather than just warning about it.
+    hook_errors_fatal: bool,
 
-            printf!("Snapshot date (yyyy-MM-dd): ");
+    /// When the batch is done, pipe a JSON summary of it to this shell command...
+    notify_cmd: Option<String>,
 
-            let mut date = String::new();
-            io::stdin().read_line(&mut date).unwrap();
-            date.pop();
+    /// ...and/or POST it to this URL (using curl).
+    notify_url: Option<String>,
+}
 
-            for backup in &mut backups {
-                backup.end_snapshot = Some(date.clone());
-                //TODO: check that the snapshot exists for that volume
-            }
+// How one backup in a batch went.
+struct BackupResult {
+    snapshot: String,
+    result: anyhow::Result<()>,
+    size: Option<u64>,
+    duration: Duration,
+}
 
-        } else if input.is_empty() {
-            println!("Starting backups.\n");
-            do_backups(&backups, backups_dir);
+// Summarize a batch of backups as JSON, for notifications.
+fn summary_json(results: &[BackupResult], not_started: &[String]) -> String {
+    let mut entries = vec![];
+    for r in results {
+        entries.push(format!(
+            "{{\"snapshot\": {}, \"status\": {}, \"error\": {}, \"size\": {}, \
+             \"duration_seconds\": {}}}",
+            json_string(&r.snapshot),
+            json_string(if r.result.is_ok() { "ok" } else { "failed" }),
+            match r.result {
+                Ok(()) => "null".to_owned(),
+                Err(ref e) => json_string(&e.to_string()),
+            },
+            r.size.map(|n| n.to_string()).unwrap_or_else(|| "null".to_owned()),
+            r.duration.as_secs()));
+    }
+    for snapshot in not_started {
+        entries.push(format!(
+            "{{\"snapshot\": {}, \"status\": \"not started\", \"error\": null, \
+             \"size\": null, \"duration_seconds\": null}}",
+            json_string(snapshot)));
+    }
+    let failed = results.iter().filter(|r| r.result.is_err()).count();
+    format!("{{\"succeeded\": {}, \"failed\": {}, \"not_started\": {}, \"backups\": [{}]}}\n",
+            results.len() - failed,
+            failed,
+            not_started.len(),
+            entries.join(", "))
+}
+
+// Send the summary of a batch wherever the user asked for it to go. Problems here are only
+// warned about, since the backups themselves are done by now.
+fn send_notifications(options: &BatchOptions, summary: &str) {
+    let mut commands = vec![];
+    if let Some(ref cmd) = options.notify_cmd {
+        let mut command = process::Command::new("sh");
+        command.arg("-c").arg(cmd);
+        commands.push(command);
+    }
+    if let Some(ref url) = options.notify_url {
+        let mut command = process::Command::new("curl");
+        command.args(["-fsS", "-X", "POST", "-H", "Content-Type: application/json",
+                      "--data-binary", "@-"])
+            .arg(url);
+        commands.push(command);
+    }
+
+    for mut command in commands {
+        let result = command.stdin(process::Stdio::piped())
+            .spawn()
+            .and_then(|mut child| {
+                child.stdin.take().unwrap().write_all(summary.as_bytes())?;
+                child.wait()
+            });
+        match result {
+            Ok(status) if status.success() => (),
+            Ok(status) => eprintln!("warning: notification {:?} failed: {}", command, status),
+            Err(e) => eprintln!("warning: failed to run notification {:?}: {}", command, e),
+        }
+    }
+}
+
+// Run a pre- or post-backup hook command (see `run_hook`). The volume, the backup file path, and
+// (for the post-backup hook) "ok" or "failed" are passed as $1, $2, and $3, and also in the
+// environment, along with the snapshot.
+fn run_backup_hook(cmd: &str, snapshot: &str, volume: &str, file: &Path, status: Option<&str>)
+    -> anyhow::Result<()>
+{
+    let mut args = vec![OsStr::new(volume), file.as_os_str()];
+    let mut env = vec![("ZSNAPMGR_SNAPSHOT", OsStr::new(snapshot)),
+                       ("ZSNAPMGR_VOLUME", OsStr::new(volume)),
+                       ("ZSNAPMGR_BACKUP_FILE", file.as_os_str())];
+    if let Some(status) = status {
+        args.push(OsStr::new(status));
+        env.push(("ZSNAPMGR_BACKUP_STATUS", OsStr::new(status)));
+    }
+    Ok(run_hook(cmd, &args, &env)?)
+}
+
+// Read a GPG passphrase from the first line of a file.
+fn read_passphrase_file(path: &Path) -> anyhow::Result<String> {
+    let contents = fs::read_to_string(path).map_err(|e| {
+        anyhow::Error::from(e).context(format!("failed to read passphrase file {:?}", path))
+    })?;
+    Ok(contents.lines().next().unwrap_or("").to_owned())
+}
+
+// Work out the passphrase and options for one backup in a batch, which may have its own encryption
+// settings rather than using the batch's passphrase.
+fn backup_encryption(backup: &Backup, batch_passphrase: &str, options: &BackupOptions)
+    -> anyhow::Result<(String, BackupOptions)>
+{
+    let mut options = options.clone();
+    let passphrase = if let Some(ref recipient) = backup.recipient {
+        options.recipient = Some(recipient.clone());
+        String::new()
+    } else if let Some(ref path) = backup.passphrase_file {
+        read_passphrase_file(path)?
+    } else {
+        batch_passphrase.to_owned()
+    };
+    Ok((passphrase, options))
+}
+
+// Back up one snapshot, named in full, without going through the list of volumes.
+fn backup_single_snapshot(snapshot: &str, dir: &Path, incremental_from: Option<&str>,
+                          passphrase_file: Option<&Path>, options: &BackupOptions)
+    -> anyhow::Result<()>
+{
+    let volume = match snapshot.split_once('@') {
+        Some((volume, _)) => volume,
+        None => anyhow::bail!("{:?} is not a snapshot name", snapshot),
+    };
+    // The start can be given in full or as just the part after the '@'.
+    let incremental_start = match incremental_from.map(|start| start.split_once('@')) {
+        Some(Some((start_volume, _))) if !start_volume.is_empty() && start_volume != volume => {
+            anyhow::bail!("can't back up {} incrementally from a snapshot of {}",
+                          snapshot, start_volume);
+        }
+        Some(Some((_, start))) => Some(start),
+        Some(None) => incremental_from,
+        None => None,
+    };
+
+    let passphrase = if let Some(path) = passphrase_file {
+        read_passphrase_file(path)?
+    } else if options.send.dry_run {
+        String::new()
+    } else {
+        loop {
+            let pass1 = getpass("GPG passphrase: ")?;
+            let pass2 = getpass("again: ")?;
+            if pass1 == pass2 {
+                break pass1;
+            }
+            println!("Passphrases do not match.");
+        }
+    };
+
+    println!("Backing up: {}", snapshot);
+    match new_zsnapmgr().backup(dir, snapshot, &passphrase, incremental_start, options) {
+        Ok(written) => {
+            check_main_destination(&options.file_path(dir, snapshot)?, &written).map_err(|e| {
+                Failure::Backup(format!("failed backup of {}: {}", snapshot, e)).into()
+            })
+        }
+        Err(e) if e.is_verification_failure() => Err(e.into()),
+        Err(e) => Err(Failure::Backup(format!("failed backup of {}: {}", snapshot, e)).into()),
+    }
+}
+
+// With --dest-keep-going, a backup can succeed with only some of its destinations written. It
+// only counts as done if the main one was, since that's where batch progress and hooks look.
+fn check_main_destination(file: &Path, written: &[PathBuf]) -> anyhow::Result<()> {
+    if written.iter().any(|path| path == file) {
+        Ok(())
+    } else {
+        anyhow::bail!("failed to write {:?}; only written to {:?}", file, written)
+    }
+}
+
+// Make sure the pools being backed up from are healthy, since a backup of a sick pool may have
+// bad data in it. A degraded pool can still be read reliably, so that's only a warning; anything
+// worse stops the backups, unless `ignore` is set.
+fn check_pool_health(backups: &[&Backup], ignore: bool) -> anyhow::Result<()> {
+    let z = new_zsnapmgr();
+    let pools: BTreeSet<&str> = backups.iter()
+        .map(|backup| backup.volume.split('/').next().unwrap())
+        .collect();
+    for pool in pools {
+        match z.pool_health(pool) {
+            Ok(PoolState::Online) => (),
+            Ok(PoolState::Degraded) => {
+                eprintln!("warning: pool {} is DEGRADED; backing up from it anyway", pool);
+            }
+            Ok(state) if ignore => {
+                eprintln!("warning: pool {} is {}; backing up from it anyway", pool, state);
+            }
+            Ok(state) => {
+                anyhow::bail!("pool {} is {}, so its backups may not be good; use --force to back \
+                               up from it anyway", pool, state);
+            }
+            Err(e) => eprintln!("warning: couldn't check the health of pool {}: {}", pool, e),
+        }
+    }
+    Ok(())
+}
+
+// Run a batch of backups, then summarize how they went. Fails if any of them did, so that
+// scheduled runs notice.
+fn do_backups(backups: &[Backup], path: &Path, options: &BatchOptions) -> anyhow::Result<()> {
+    let (backups, needs_full): (Vec<&Backup>, Vec<&Backup>) = backups.iter()
+        .partition(|backup| !options.only_incremental || backup.start_snapshot.is_some());
+    let needs_full: Vec<String> = needs_full.iter()
+        .map(|backup| format!("{}@{}", backup.volume, backup.end_snapshot.as_deref().unwrap()))
+        .collect();
+    for snapshot in &needs_full {
+        println!("{}: skipped (needs full)", snapshot);
+    }
+
+    let dry_run = options.backup.send.dry_run;
+    let progress = BatchProgress::new(&path.join(BATCH_PROGRESS_FILE));
+    if options.restart && !dry_run {
+        progress.clear()?;
+    }
+    let done = if options.restart {
+        HashSet::new()
+    } else {
+        progress.done().unwrap_or_else(|e| {
+            eprintln!("warning: failed to read batch progress: {}", e);
+            HashSet::new()
+        })
+    };
+    let backups: Vec<&Backup> = backups.into_iter()
+        .filter(|backup| {
+            let snapshot =
+                format!("{}@{}", backup.volume, backup.end_snapshot.as_deref().unwrap());
+            if done.contains(&snapshot) {
+                println!("{}: already done by an earlier run of this batch (use --restart to \
+                          redo it)", snapshot);
+                false
+            } else {
+                true
+            }
+        })
+        .collect();
+
+    if backups.is_empty() {
+        println!("Nothing to do.");
+        return Ok(());
+    }
+
+    check_pool_health(&backups, options.ignore_pool_health)?;
+
+    // Backups with their own encryption settings don't need the batch's passphrase.
+    let need_passphrase = backups.iter()
+        .any(|backup| backup.recipient.is_none() && backup.passphrase_file.is_none());
+    let passphrase: String;
+    loop {
+        if dry_run || !need_passphrase {
+            passphrase = String::new();
             break;
+        }
+        let pass1 = getpass("GPG passphrase: ").unwrap();
+        let pass2 = getpass("again: ").unwrap();
+        if pass1 != pass2 {
+            println!("Passphrases do not match.");
+        } else {
+            passphrase = pass1;
+            break;
+        }
+    }
+
+    let mut results = vec![];
+    for backup in &backups {
+        let z = new_zsnapmgr();
+
+        let snapshot = format!("{}@{}",
+                               backup.volume,
+                               backup.end_snapshot.as_deref().unwrap());
+
+        println!("\nBacking up: {}", snapshot);
+
+        let incremental_start = backup.start_snapshot.as_deref();
+
+        let file = match options.backup.file_path(path, &snapshot) {
+            Ok(file) => file,
+            Err(e) => {
+                println!("failed backup of {}: {}", backup.volume, e);
+                results.push(BackupResult {
+                    snapshot,
+                    result: Err(e.into()),
+                    size: None,
+                    duration: Duration::ZERO,
+                });
+                if options.fail_fast {
+                    println!("Stopping here; not starting the remaining backups.");
+                    break;
+                }
+                continue;
+            }
+        };
+        let hook_failed = |e: anyhow::Error| -> anyhow::Result<()> {
+            if options.hook_errors_fatal {
+                Err(e)
+            } else {
+                eprintln!("warning: {}", e);
+                Ok(())
+            }
+        };
+
+        let start_time = Instant::now();
+        let mut result = match options.pre_backup_cmd {
+            Some(ref cmd) if dry_run => {
+                println!("dry run: not running pre-backup command: {}", cmd);
+                Ok(())
+            }
+            Some(ref cmd) => {
+                run_backup_hook(cmd, &snapshot, &backup.volume, &file, None).or_else(hook_failed)
+            }
+            None => Ok(()),
+        };
+        if result.is_ok() {
+            result = backup_encryption(backup, &passphrase, &options.backup)
+                .and_then(|(passphrase, backup_options)| z.backup(
+                    path,
+                    &snapshot,
+                    &passphrase,
+                    incremental_start,
+                    &backup_options,
+                ).map_err(anyhow::Error::from))
+                .and_then(|written| check_main_destination(&file, &written));
+
+            if let Some(cmd) = options.post_backup_cmd.as_ref().filter(|_| !dry_run) {
+                let status = if result.is_ok() { "ok" } else { "failed" };
+                let hook_result = run_backup_hook(cmd, &snapshot, &backup.volume, &file,
+                                                  Some(status))
+                    .or_else(hook_failed);
+                result = result.and(hook_result);
+            }
+        }
+        let failed = result.is_err();
+        if let Err(ref e) = result {
+            println!("failed backup of {}: {}", backup.volume, e);
+        } else if !dry_run {
+            if let Err(e) = progress.record(&snapshot) {
+                eprintln!("warning: failed to record batch progress: {}", e);
+            }
+        }
+        // Split backups don't have a single file, but the manifest has the total.
+        let size = fs::metadata(&file).map(|m| m.len()).ok()
+            .or_else(|| Manifest::read(&manifest_path(&file)).ok()
+                .and_then(|manifest| manifest.get("size").and_then(|n| n.parse().ok())));
+        results.push(BackupResult {
+            snapshot,
+            size: if failed { None } else { size },
+            result,
+            duration: start_time.elapsed(),
+        });
+        if failed && options.fail_fast {
+            println!("Stopping here; not starting the remaining backups.");
+            break;
+        }
+    }
+
+    let not_started: Vec<String> = backups[results.len()..].iter()
+        .map(|backup| format!("{}@{}", backup.volume, backup.end_snapshot.as_deref().unwrap()))
+        .collect();
+
+    let mut table = new_table(&["snapshot", "_size", "_time", "result"]);
+    for r in &results {
+        let secs = r.duration.as_secs();
+        table.push(vec![
+            r.snapshot.clone(),
+            r.size.map(|n| format!("{}B", human_number(n, 1))).unwrap_or_default(),
+            format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60),
+            match r.result {
+                Ok(()) => "ok".to_owned(),
+                Err(ref e) => format!("FAILED: {}", e),
+            }]);
+    }
+    for snapshot in &not_started {
+        table.push(vec![snapshot.clone(), String::new(), String::new(),
+                        "not started".to_owned()]);
+    }
+    for snapshot in &needs_full {
+        table.push(vec![snapshot.clone(), String::new(), String::new(),
+                        "skipped (needs full)".to_owned()]);
+    }
+    println!("\nSummary:\n{}", table);
+
+    if !dry_run {
+        send_notifications(options, &summary_json(&results, &not_started));
+    }
+
+    let failures = results.iter().filter(|r| r.result.is_err()).count();
+    if failures > 0 {
+        let message = format!("{} of {} backup(s) failed", failures, backups.len());
+        let verification = results.iter()
+            .filter_map(|r| r.result.as_ref().err())
+            .any(|e| exit_code(e) == EXIT_VERIFY);
+        return Err(if verification {
+            Failure::Verification(message)
+        } else {
+            Failure::Backup(message)
+        }.into());
+    }
+    if !dry_run && not_started.is_empty() {
+        if let Err(e) = progress.clear() {
+            eprintln!("warning: failed to remove batch progress: {}", e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "serde")]
+fn save_backups(backups: &[Backup], path: &Path) -> anyhow::Result<()> {
+    let file = fs::File::create(path)?;
+    serde_json::to_writer_pretty(file, backups)?;
+    Ok(())
+}
+
+#[cfg(feature = "serde")]
+fn load_backups(path: &Path) -> anyhow::Result<Vec<Backup>> {
+    let file = fs::File::open(path)?;
+    let backups: Vec<Backup> = serde_json::from_reader(file)?;
+    for (i, backup) in backups.iter().enumerate() {
+        if backup.end_snapshot.is_none() {
+            anyhow::bail!("backup #{} ({}) in {:?} has no end_snapshot",
+                i + 1, backup.volume, path);
+        }
+    }
+    Ok(backups)
+}
+
+#[cfg(not(feature = "serde"))]
+fn save_backups(_backups: &[Backup], _path: &Path) -> anyhow::Result<()> {
+    anyhow::bail!("zsnapmgr was built without the 'serde' feature")
+}
+
+#[cfg(not(feature = "serde"))]
+fn load_backups(_path: &Path) -> anyhow::Result<Vec<Backup>> {
+    anyhow::bail!("zsnapmgr was built without the 'serde' feature")
+}
+
+const DATE_INPUT_HELP: &str = "yyyy-MM-dd, 'today', 'yesterday', -<days ago>, or 'latest'";
+
+// Work out which snapshot a date entered at an interactive prompt means. It can be a date, or one
+// of the relative forms in DATE_INPUT_HELP, and has to be one of the given snapshot dates.
+fn resolve_date(input: &str, snapshot_dates: &[NaiveDate], today: NaiveDate)
+    -> Result<NaiveDate, String>
+{
+    let days_ago = |n: u64| today.checked_sub_days(chrono::Days::new(n))
+        .ok_or_else(|| format!("{} days ago is too long ago", n));
+    let date = match input {
+        "latest" => {
+            return snapshot_dates.iter().max().copied()
+                .ok_or_else(|| "there are no snapshots".to_owned());
+        }
+        "today" => today,
+        "yesterday" => days_ago(1)?,
+        _ => match input.strip_prefix('-').and_then(|n| n.parse::<u64>().ok()) {
+            Some(n) => days_ago(n)?,
+            None => NaiveDate::parse_from_str(input, "%Y-%m-%d")
+                .map_err(|_| format!("invalid date {:?}", input))?,
+        }
+    };
+    if snapshot_dates.contains(&date) {
+        Ok(date)
+    } else {
+        Err(format!("there's no snapshot from {}", date.format("%Y-%m-%d")))
+    }
+}
+
+#[test]
+fn test_resolve_date() {
+    let date = |s: &str| NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap();
+    let snapshots = [date("2021-03-01"), date("2021-03-09"), date("2021-03-10")];
+    let today = date("2021-03-10");
+    let resolve = |input: &str| resolve_date(input, &snapshots, today);
+    assert_eq!(resolve("today"), Ok(date("2021-03-10")));
+    assert_eq!(resolve("yesterday"), Ok(date("2021-03-09")));
+    assert_eq!(resolve("-9"), Ok(date("2021-03-01")));
+    assert_eq!(resolve("2021-03-01"), Ok(date("2021-03-01")));
+    assert_eq!(resolve("latest"), Ok(date("2021-03-10")));
+    assert!(resolve("-2").is_err());
+    assert!(resolve("March 1").is_err());
+    assert!(resolve_date("latest", &[], today).is_err());
+}
+
+fn interactive_backup(backups_dir: &Path, options: &BatchOptions) -> anyhow::Result<()> {
+    let z = new_zsnapmgr();
+    let mut backups: Vec<Backup> = gather_volumes(&z, backups_dir);
+    if options.skip_pool_roots {
+        backups.retain(|backup| dataset_depth(&backup.volume) > 0);
+    }
+    if options.force_full {
+        for backup in &mut backups {
+            backup.start_snapshot = None;
+        }
+    }
+    let today = Local::now().date_naive();
+    // The volume's snapshots which have a date in their name (possibly with a prefix, or a suffix
+    // like '-2'), paired with that date, oldest first.
+    let dated_snapshots = |volume: &str| -> Vec<(NaiveDate, String)> {
+        let volume_at = format!("{}@", volume);
+        z.get_snapshots(None).unwrap_or_default()
+            .iter()
+            .filter_map(|snap| snap.strip_prefix(&volume_at))
+            .filter_map(|name| Some((snapshot_name_date(name)?, name.to_owned())))
+            .collect()
+    };
+    // Pick the snapshot for a date entered at the prompt. If there are several from that day, the
+    // last one taken is used.
+    let pick_snapshot = |input: &str, volume: &str| -> Result<String, String> {
+        let snapshots = dated_snapshots(volume);
+        let dates = snapshots.iter().map(|(date, _)| *date).collect::<Vec<_>>();
+        let date = resolve_date(input, &dates, today)?;
+        Ok(snapshots.into_iter().rev().find(|(d, _)| *d == date).unwrap().1)
+    };
+    // Estimates are slow to get, so keep them in case the same backup comes up again after
+    // changes are made.
+    let mut estimates = HashMap::<(String, Option<String>), Option<u64>>::new();
+    let estimate_key = |backup: &Backup| {
+        (format!("{}@{}", backup.volume, backup.end_snapshot.as_ref().unwrap()),
+         backup.start_snapshot.clone())
+    };
+    let snapshot_date = |backup: &Backup| {
+        backup.end_snapshot.as_deref().and_then(snapshot_name_date)
+    };
+    loop {
+        for backup in &backups {
+            let (snapshot, start) = estimate_key(backup);
+            estimates.entry((snapshot.clone(), start.clone()))
+                .or_insert_with(|| z.estimate_backup_size(
+                        &snapshot, start.as_deref(), &options.backup).ok());
+        }
+        options.sort.sort(&mut backups,
+                          |backup| backup.volume.clone(),
+                          snapshot_date,
+                          |backup| estimates[&estimate_key(backup)]);
+
+        let mut table = new_table(&["_", "volume", "incremental", "snapshot date", "_est. size"]);
+        for (i, backup) in backups.iter().enumerate() {
+            let start = if backup.start_snapshot.is_none() {
+                "full backup".to_string()
+            } else {
+                backup.start_snapshot.as_ref().unwrap().clone()
+            };
+
+            let estimate = estimates[&estimate_key(backup)];
+
+            table.push(vec![(i + 1).to_string(),
+                            backup.volume.clone(),
+                            start,
+                            backup.end_snapshot.as_ref().unwrap().clone(),
+                            estimate.map(|size| format!("{}B", human_number(size, 1)))
+                                .unwrap_or_else(|| "?".to_owned())]);
+        }
+
+        println!("Volumes to backup:\n{}", table);
+
+        printf!(concat!("Enter a number to make changes,\n",
+                         "\t'+' to add a volume (or several),\n",
+                         "\t'-' to remove one,\n",
+                         "\t'd' to change all dates,\n",
+                         "\t's <file>' to save this selection,\n",
+                         "\tor <return> to start backup: "));
+
+        let mut input = String::new();
+        match io::stdin().read_line(&mut input) {
+            Ok(bytes_read) => {
+                if bytes_read == 0 {
+                    panic!("EOF");
+                }
+                input.pop();
+            }
+            Err(e) => panic!("{}", e),
+        }
+
+        if input == "+" {
+            printf!("Volume (or volume@snapshot; either can have * and ? wildcards): ");
+
+            let mut vol = String::new();
+            io::stdin().read_line(&mut vol).unwrap();
+            vol.pop();
+
+            match full_backups_matching(&z, vol.trim()) {
+                Ok((added, skipped)) => {
+                    for skipped in skipped {
+                        println!("Skipping {}", skipped);
+                    }
+                    backups.extend(added);
+                }
+                Err(e) => {
+                    println!("{}\n", e);
+                    continue;
+                }
+            }
+
+        } else if input.starts_with('-') {
+
+            let index: usize;
+
+            if input.len() > 1 {
+                match input[1..].parse::<usize>() {
+                    Ok(n) => {
+                        index = n;
+                    },
+                    Err(e) => {
+                        println!("Invalid number: {}\n", e);
+                        continue;
+                    }
+                }
+            } else {
+                printf!("Remove which one?: ");
+
+                input.clear();
+                io::stdin().read_line(&mut input).unwrap();
+                input.pop();
+
+                match input.parse::<usize>() {
+                    Ok(n) => {
+                        index = n;
+                    },
+                    Err(e) => {
+                        println!("Invalid number: {}\n", e);
+                        continue;
+                    }
+                }
+            }
+
+            if backups.len() < index || index == 0 {
+                println!("Number out of range.\n");
+                continue;
+            }
+
+            backups.remove(index - 1);
+
+        } else if let Some(file) = input.strip_prefix("s ") {
+
+            match save_backups(&backups, Path::new(file.trim())) {
+                Ok(()) => println!("Saved.\n"),
+                Err(e) => println!("Error saving selection: {}\n", e),
+            }
+
+        } else if input.starts_with('d') || input.starts_with('D') {
+
+            printf!("Snapshot date ({}): ", DATE_INPUT_HELP);
+
+            let mut date = String::new();
+            io::stdin().read_line(&mut date).unwrap();
+            date.pop();
+
+            for backup in &mut backups {
+                match pick_snapshot(date.trim(), &backup.volume) {
+                    Ok(name) => backup.end_snapshot = Some(name),
+                    Err(e) => println!("{}: {}; leaving it alone.", backup.volume, e),
+                }
+            }
+            println!();
+
+        } else if input.is_empty() {
+            println!("Starting backups.\n");
+            return do_backups(&backups, backups_dir, options);
         } else {
             let index = match input.parse::<usize>() {
                 Ok(n) => n,
@@ -454,9 +1656,9 @@ fn interactive_backup(backups_dir: &Path) {
                 continue;
             }
 
-            printf!("Date (yyyy-MM-dd");
+            printf!("Date ({}", DATE_INPUT_HELP);
             if start {
-                printf!(" or 'none' for full backup");
+                printf!(", or 'none' for full backup");
             }
             printf!("): ");
 
@@ -464,29 +1666,954 @@ fn interactive_backup(backups_dir: &Path) {
             io::stdin().read_line(&mut input).unwrap();
             input.pop();
 
-            if start {
-                vol.start_snapshot = if input == "none" {
-                    None
-                } else {
-                    Some(input)
+            if start && input == "none" {
+                vol.start_snapshot = None;
+                continue;
+            }
+            let date = match pick_snapshot(input.trim(), &vol.volume) {
+                Ok(name) => name,
+                Err(e) => {
+                    println!("{}\n", e);
+                    continue;
                 }
+            };
+            if start {
+                vol.start_snapshot = Some(date);
             } else {
-                vol.end_snapshot = Some(input);
+                vol.end_snapshot = Some(date);
             }
         }
     }
 }
 
-fn snapshot_automanage() -> anyhow::Result<()> {
-    let z = ZSnapMgr::new(USE_SUDO).expect("unable to initialize libzfs");
-    z.snapshot_automanage()?;
-    Ok(())
-}
+fn backup_status(backups_dir: &Path) -> anyhow::Result<()> {
+    let z = new_zsnapmgr();
+    let states = z.backup_status(backups_dir, false)?;
+    if states.is_empty() {
+        println!("No backups in progress or interrupted.");
+        return Ok(());
+    }
 
-fn main() -> anyhow::Result<()> {
-    eprintln!("zsnapmgr3/{}", env!("GIT_HASH"));
+    let in_progress = sends_in_progress(&states);
+    let mut table = new_table(&["file", "_size", "modified", "state"]);
+    for state in &states {
+        let description = match state.status {
+            Some(ref info) => {
+                let percent = if info.total_size == 0 {
+                    0.
+                } else {
+                    info.sent as f64 / info.total_size as f64 * 100.
+                };
+                format!("{} {} ({:.1}%, {}B written, pid {})",
+                        if info.running { "sending" } else { "stale status for" },
+                        info.snapshot,
+                        percent,
+                        human_number(info.written, 1),
+                        info.pid)
+            }
+            None if is_in_progress(&state.path, &in_progress) => {
+                "partial (in progress)".to_owned()
+            }
+            None => "partial (interrupted)".to_owned(),
+        };
+
+        table.push(vec![state.path.file_name().unwrap().to_string_lossy().into_owned(),
+                        format!("{}B", human_number(state.size, 1)),
+                        state.modified
+                            .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+                            .unwrap_or_default(),
+                        description]);
+    }
+
+    print!("{}", table);
+    Ok(())
+}
+
+// The destinations of the sends which status files say are still running.
+fn sends_in_progress(states: &[BackupState]) -> Vec<&Path> {
+    states.iter()
+        .filter_map(|state| state.status.as_ref())
+        .filter(|info| info.running)
+        .map(|info| info.destination.as_path())
+        .collect()
+}
+
+// Whether a partial file (or its sidecar) belongs to one of the sends still running.
+fn is_in_progress(partial_path: &Path, in_progress: &[&Path]) -> bool {
+    let name = partial_path.file_name().unwrap().to_string_lossy();
+    let base = name.trim_end_matches(".sha256sum")
+        .trim_end_matches(".sha512sum")
+        .trim_end_matches("_partial");
+    in_progress.iter().any(|dest| dest.file_name() == Some(OsStr::new(base)))
+}
+
+// Delete the partial files left behind by interrupted backups, after asking.
+fn clean_partials(dir: &Path, recursive: bool, dry_run: bool, yes: bool) -> anyhow::Result<()> {
+    let z = new_zsnapmgr();
+    let states = z.backup_status(dir, recursive)?;
+    let in_progress = sends_in_progress(&states);
+    let partials: Vec<&BackupState> = states.iter()
+        .filter(|state| state.status.is_none() && !is_in_progress(&state.path, &in_progress))
+        .collect();
+    if partials.is_empty() {
+        println!("No partial files from interrupted backups found.");
+        return Ok(());
+    }
+
+    let now = Local::now();
+    let mut table = new_table(&["file", "_size", "modified", "_days old"]);
+    for state in &partials {
+        let path = state.path.strip_prefix(dir).unwrap_or(&state.path);
+        table.push(vec![path.display().to_string(),
+                        format!("{}B", human_number(state.size, 1)),
+                        state.modified
+                            .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+                            .unwrap_or_default(),
+                        state.modified
+                            .map(|t| now.signed_duration_since(t).num_days().to_string())
+                            .unwrap_or_default()]);
+    }
+    print!("{}", table);
+    let total: u64 = partials.iter().map(|state| state.size).sum();
+    if dry_run {
+        println!("dry run: not deleting them ({}B).", human_number(total, 1));
+        return Ok(());
+    }
+
+    if !yes {
+        printf!("Delete these {} file(s) ({}B)? [y/N] ", partials.len(), human_number(total, 1));
+        let mut input = String::new();
+        io::stdin().read_line(&mut input)?;
+        if !input.trim().eq_ignore_ascii_case("y") {
+            println!("Not deleting them.");
+            return Ok(());
+        }
+    }
+
+    let mut failures = 0;
+    for state in &partials {
+        if let Err(e) = fs::remove_file(&state.path) {
+            println!("{}: {}", state.path.display(), e);
+            failures += 1;
+        }
+    }
+    if failures > 0 {
+        anyhow::bail!("failed to delete {} file(s)", failures);
+    }
+    println!("Deleted {} file(s).", partials.len());
+    Ok(())
+}
+
+/// How to order a list of volumes.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+enum SortOrder {
+    #[default]
+    Name,
+    /// Oldest (latest snapshot) first, so stale volumes stand out.
+    Age,
+    /// Biggest first.
+    Size,
+}
+
+impl SortOrder {
+    fn parse(s: &str) -> Option<SortOrder> {
+        match s {
+            "name" => Some(SortOrder::Name),
+            "age" => Some(SortOrder::Age),
+            "size" => Some(SortOrder::Size),
+            _ => None,
+        }
+    }
+
+    // Sort items, given how to get the name, date, and size of each. Anything with no date counts
+    // as oldest, and anything with no size comes last. Ties go by name.
+    fn sort<T>(self,
+               items: &mut [T],
+               name: impl Fn(&T) -> String,
+               date: impl Fn(&T) -> Option<NaiveDate>,
+               size: impl Fn(&T) -> Option<u64>)
+    {
+        items.sort_by(|a, b| {
+            let order = match self {
+                SortOrder::Name => std::cmp::Ordering::Equal,
+                SortOrder::Age => date(a).cmp(&date(b)),
+                SortOrder::Size => size(b).cmp(&size(a)),
+            };
+            order.then_with(|| name(a).cmp(&name(b)))
+        });
+    }
+}
+
+#[test]
+fn test_sort_order() {
+    let date = |s: &str| NaiveDate::parse_from_str(s, "%Y-%m-%d").ok();
+    let mut items = vec![("b", date("2021-01-02"), Some(5)),
+                         ("a", date("2021-03-01"), None),
+                         ("c", None, Some(10)),
+                         ("d", date("2021-01-02"), Some(1))];
+    let mut sorted = |order: SortOrder| {
+        order.sort(&mut items, |item| item.0.to_owned(), |item| item.1, |item| item.2);
+        items.iter().map(|item| item.0).collect::<Vec<_>>()
+    };
+    assert_eq!(sorted(SortOrder::Name), ["a", "b", "c", "d"]);
+    assert_eq!(sorted(SortOrder::Age), ["c", "b", "d", "a"]);
+    assert_eq!(sorted(SortOrder::Size), ["c", "b", "d", "a"]);
+}
+
+fn list_volumes(parsable: bool, sort: SortOrder, skip_pool_roots: bool) -> anyhow::Result<()> {
+    let z = new_zsnapmgr();
+    let today = Local::now().date_naive();
+
+    let mut summaries = z.summarize_volumes()?;
+    if skip_pool_roots {
+        summaries.retain(|summary| dataset_depth(&summary.volume) > 0);
+    }
+    sort.sort(&mut summaries, |summary| summary.volume.clone(), |summary| summary.latest, |_| None);
+
+    // "type" goes last so that scripts reading --parsable output by column aren't thrown off.
+    let mut table = new_table(&["volume", "latest snapshot", "_days old", "_snapshots", "type"]);
+    for summary in summaries {
+        let kind = if summary.zvol { "zvol" } else { "filesystem" };
+        let (latest, age) = match summary.latest {
+            Some(date) => (date.format("%Y-%m-%d").to_string(),
+                           today.signed_duration_since(date).num_days().to_string()),
+            None => ("-".to_owned(), "-".to_owned()),
+        };
+        table.push(vec![summary.volume, latest, age, summary.snapshot_count.to_string(),
+                        kind.to_owned()]);
+    }
+
+    if parsable {
+        print!("{}", table.to_tsv());
+    } else {
+        print!("{}", table);
+    }
+    Ok(())
+}
+
+fn destroy_snapshot(snapshot: &str, backups_dir: Option<&Path>, yes: bool, force: bool)
+    -> anyhow::Result<()>
+{
+    let z = new_zsnapmgr();
+
+    let reasons = z.destroy_guards(snapshot, backups_dir)?;
+    if !reasons.is_empty() {
+        for reason in &reasons {
+            println!("{} is protected: {}", snapshot, reason);
+        }
+        if !force {
+            anyhow::bail!("{} is protected; use --force to destroy it anyway", snapshot);
+        }
+    }
+
+    if !yes {
+        printf!("Really destroy {}? [y/N] ", snapshot);
+        let mut input = String::new();
+        io::stdin().read_line(&mut input)?;
+        if !input.trim().eq_ignore_ascii_case("y") {
+            println!("Not destroying it.");
+            return Ok(());
+        }
+    }
+
+    println!("ZFS DELETE {:?}", snapshot);
+    z.destroy_snapshot(snapshot)?;
+    Ok(())
+}
+
+fn snapshot_automanage(options: &AutomanageOptions, changes_only: bool) -> anyhow::Result<()> {
+    let changed_volumes = Arc::new(Mutex::new(ChangedVolumes::default()));
+    let z = if changes_only {
+        let changed_volumes = Arc::clone(&changed_volumes);
+        let last_line_length = AtomicUsize::new(0);
+        new_zsnapmgr_with_output(Box::new(move |event| {
+            let mut changed_volumes = changed_volumes.lock().unwrap();
+            match event {
+                Event::Retention(decision) => changed_volumes.push(decision),
+                _ => {
+                    // The decisions all come first, so anything else means they're done.
+                    changed_volumes.flush();
+                    print_event(event, &last_line_length);
+                }
+            }
+        }))
+    } else {
+        new_zsnapmgr()
+    };
+
+    // Show the last volume's decisions before asking about any of them.
+    let mut options = options.clone();
+    if let (true, Some(Confirmation(confirm))) = (changes_only, options.confirm.clone()) {
+        let changed_volumes = Arc::clone(&changed_volumes);
+        options.confirm = Some(Confirmation(Arc::new(move |change| {
+            changed_volumes.lock().unwrap().flush();
+            confirm(change)
+        })));
+    }
+
+    let result = z.snapshot_automanage(&options)?;
+    if changes_only {
+        let mut changed_volumes = changed_volumes.lock().unwrap();
+        changed_volumes.flush();
+        println!("{} volume(s) unchanged", changed_volumes.unchanged_count);
+    }
+    let creation = &result.creation;
+    for snap in &creation.skipped {
+        println!("{} already exists; skipped.", snap);
+    }
+    for (snap, e) in &creation.failed {
+        eprintln!("failed to create snapshot {}: {}", snap, e);
+    }
+
+    // Last, so it's easy to find in logs.
+    println!("{}", automanage_summary(&result));
+
+    if !creation.failed.is_empty() {
+        anyhow::bail!("failed to create {} snapshot(s)", creation.failed.len());
+    }
+    Ok(())
+}
+
+// Show what automanage would do, either with the live snapshot list or one read from a file (as
+// written by 'zfs list -H -t snapshot -o name').
+fn plan_automanage(options: &AutomanageOptions, plan_from: Option<&Path>, changes_only: bool)
+    -> anyhow::Result<()>
+{
+    let plan = match plan_from {
+        Some(path) => {
+            let snapshots: Vec<String> = fs::read_to_string(path)?
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(str::to_owned)
+                .collect();
+            // Without the pool, there's no knowing about holds, whether volumes have changed,
+            // their retention policies, or which snapshots backups were made from.
+            let today = Local::now().date_naive();
+            zsnapmgr::plan_retention(&snapshots, &HashSet::new(), today, options, &|_| true,
+                                     &|_| RetentionPolicy::Standard)
+        }
+        None => new_zsnapmgr().plan_automanage(options)?,
+    };
+
+    let changed: HashSet<&str> = plan.snapshots.iter()
+        .filter(|planned| is_change(&planned.action))
+        .map(|planned| planned.volume.as_str())
+        .collect();
+    let mut table = new_table(&["snapshot", "_days old", "_#", "action"]);
+    let mut volume = None;
+    let mut unchanged_count = 0;
+    for planned in &plan.snapshots {
+        let is_new_volume = volume != Some(&planned.volume);
+        if changes_only && !changed.contains(planned.volume.as_str()) {
+            if is_new_volume {
+                unchanged_count += 1;
+                volume = Some(&planned.volume);
+            }
+            continue;
+        }
+        if is_new_volume {
+            table.push_section(&planned.volume);
+            volume = Some(&planned.volume);
+        }
+        let action = match planned.action {
+            RetentionAction::New => "[NEW]".to_owned(),
+            RetentionAction::Keep => String::new(),
+            RetentionAction::Delete(ref why) => format!("[DELETE] {}", why),
+            RetentionAction::Held(ref why) => format!("skipped (held) {}", why),
+            RetentionAction::TooYoung(ref why) => format!("skipped (too new) {}", why),
+            RetentionAction::Protected(ref why) => format!("skipped (protected) {}", why),
+        };
+        table.push(vec![planned.name.clone(), planned.days_old.to_string(),
+                        planned.number.to_string(), action]);
+    }
+    print!("{}", table);
+    if changes_only {
+        println!("{} volume(s) unchanged", unchanged_count);
+    }
+    println!("dry run: would create {}, delete {}, keep {}",
+             plan.to_create.len(), plan.to_delete.len(), plan.kept);
+    Ok(())
+}
+
+// Write the names of the snapshots automanage would delete to a file, one per line, so the list
+// can be approved before it's carried out with --delete-from.
+fn emit_delete_list_file(options: &AutomanageOptions, path: &Path) -> anyhow::Result<()> {
+    let plan = new_zsnapmgr().plan_automanage(options)?;
+    let contents: String = plan.to_delete.iter().map(|snap| format!("{}\n", snap)).collect();
+    fs::write(path, contents).map_err(|e| anyhow::Error::from(e)
+        .context(format!("failed to write {:?}", path)))?;
+    println!("Wrote {} snapshot(s) to delete to {}", plan.to_delete.len(), path.display());
+    Ok(())
+}
+
+// Delete the snapshots listed in a file from --emit-delete-list, as far as automanage still
+// agrees they should go. Blank lines and lines starting with '#' are ignored.
+fn delete_from_list(options: &AutomanageOptions, path: &Path) -> anyhow::Result<()> {
+    let approved: Vec<String> = fs::read_to_string(path)
+        .map_err(|e| anyhow::Error::from(e).context(format!("failed to read {:?}", path)))?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect();
+    let result = new_zsnapmgr().delete_approved(options, &approved)?;
+    println!("{}", automanage_summary(&result));
+    if !result.delete_failed.is_empty() {
+        anyhow::bail!("failed to delete {} snapshot(s)", result.delete_failed.len());
+    }
+    Ok(())
+}
+
+fn automanage_summary(result: &AutomanageResult) -> String {
+    let plural = |n: usize| if n == 1 { "" } else { "s" };
+    let created = result.creation.created.len();
+    let deleted = result.deleted.len();
+    let mut summary = format!("Created {} snapshot{}, deleted {} snapshot{}, kept {}, skipped {}",
+        created, plural(created), deleted, plural(deleted), result.kept,
+        result.creation.skipped.len());
+    let failed = result.creation.failed.len() + result.delete_failed.len();
+    if failed > 0 {
+        summary += &format!(", failed {}", failed);
+    }
+    if !result.declined.is_empty() {
+        summary += &format!(", declined {}", result.declined.len());
+    }
+    summary
+}
+
+#[test]
+fn test_automanage_summary() {
+    let mut result = AutomanageResult {
+        deleted: vec!["tank@2021-01-02".to_owned(), "tank@2021-01-03".to_owned()],
+        kept: 30,
+        ..AutomanageResult::default()
+    };
+    result.creation.created.push("tank@2021-03-01".to_owned());
+    assert_eq!(automanage_summary(&result),
+               "Created 1 snapshot, deleted 2 snapshots, kept 30, skipped 0");
+    result.delete_failed.push("tank@2021-01-04".to_owned());
+    assert_eq!(automanage_summary(&result),
+               "Created 1 snapshot, deleted 2 snapshots, kept 30, skipped 0, failed 1");
+    result.declined.push("tank@2021-01-05".to_owned());
+    assert_eq!(automanage_summary(&result),
+               "Created 1 snapshot, deleted 2 snapshots, kept 30, skipped 0, failed 1, declined 1");
+}
+
+// Restore a backup into a temporary dataset and keep it mounted until the user is done with it.
+fn browse(backup_path: &Path, pool: Option<&str>, lock_options: &LockOptions)
+    -> anyhow::Result<()>
+{
+    // Default to the pool the backup came from, if the manifest says.
+    let manifest_pool = Manifest::read(&manifest_path(backup_path))
+        .ok()
+        .and_then(|manifest| manifest.get("snapshot")
+            .and_then(|snapshot| snapshot.split(['/', '@']).next())
+            .map(|pool| pool.to_owned()));
+    let pool = match pool.map(|pool| pool.to_owned()).or(manifest_pool) {
+        Some(pool) => pool,
+        None => anyhow::bail!("can't tell which pool {:?} came from; use --pool", backup_path),
+    };
+    let _lock = take_lock(lock_options, Some(&[&pool]))?;
+
+    let z = new_zsnapmgr();
+    let passphrase = getpass("GPG passphrase: ")?;
+    let browse = z.browse(backup_path, &pool, &passphrase)?;
+
+    println!("Backup is mounted read-only at {}", browse.mountpoint.display());
+    printf!("Press enter when done to unmount and destroy {}. ", browse.dataset);
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    Ok(())
+}
+
+// Show what changed between two snapshots.
+fn diff(from: &str, to: &str) -> anyhow::Result<()> {
+    let z = new_zsnapmgr();
+    let mut table = new_table(&["change", "path"]);
+    for entry in z.diff(from, to)? {
+        let path = match entry.new_path {
+            Some(new_path) => format!("{} -> {}", entry.path, new_path),
+            None => entry.path,
+        };
+        table.push(vec![entry.change.symbol().to_owned(), path]);
+    }
+    print!("{}", table);
+    Ok(())
+}
+
+// The most of a snapshot's stream `benchmark` will read in as its sample.
+const MAX_BENCHMARK_SAMPLE: u64 = 4 << 30;
+
+// Try compressing a sample of a snapshot's send stream with several compressors.
+fn benchmark(snapshot: &str, sample_size: u64, compressors: &[&str]) -> anyhow::Result<()> {
+    let z = new_zsnapmgr();
+    let (sample_size, results) = z.benchmark_compression(snapshot, sample_size, compressors)?;
+    println!("Sample: {}B", human_number(sample_size, 1));
+    let mut table = new_table(&["compressor", "output", "ratio", "speed"]);
+    for benchmark in results {
+        let (output, ratio, speed) = match benchmark.result {
+            Ok((output_size, duration)) => {
+                let ratio = if output_size == 0 {
+                    String::new()
+                } else {
+                    format!("{:.2}x", sample_size as f64 / output_size as f64)
+                };
+                let speed = duration.as_secs_f64();
+                let speed = if speed > 0. {
+                    format!("{}B/s", human_number((sample_size as f64 / speed) as u64, 1))
+                } else {
+                    String::new()
+                };
+                (format!("{}B", human_number(output_size, 1)), ratio, speed)
+            }
+            Err(e) => (format!("failed: {}", e), String::new(), String::new()),
+        };
+        table.push(vec![benchmark.compressor, output, ratio, speed]);
+    }
+    print!("{}", table);
+    Ok(())
+}
+
+// Restore a backup, by default to the dataset it was made from (if the manifest says).
+fn restore(backup_path: &Path, target: Option<RestoreTarget>, force: bool,
+           lock_options: &LockOptions)
+    -> anyhow::Result<()>
+{
+    // An encrypted manifest (see --encrypt-metadata) can only be read with the passphrase, so ask
+    // for it first.
+    let mut passphrase = None;
+    let manifest = manifest_path(backup_path);
+    if !manifest.exists() && encrypted_metadata_path(&manifest).exists() {
+        passphrase = Some(getpass("GPG passphrase: ")?);
+    }
+    let original = read_backup_manifest(backup_path, passphrase.as_deref())
+        .ok()
+        .and_then(|manifest| manifest.get("snapshot")
+            .and_then(|snapshot| snapshot.split('@').next())
+            .map(|volume| volume.to_owned()))
+        .or_else(|| {
+            // Without a manifest, a backup in the date-tree layout (see --layout) can still be
+            // matched up with its volume by its path.
+            let name = date_tree_flat_name(backup_path)?;
+            let volumes = new_zsnapmgr().get_volumes().ok()?;
+            match classify_backup_file(&name, &volumes) {
+                FileMatch::Matched { volume, .. } => Some(volume),
+                _ => None,
+            }
+        });
+    if let Some(ref original) = original {
+        println!("Backup is of {}", original);
+    }
+    let target = match (target, original.as_deref()) {
+        (Some(target), _) => target,
+        (None, Some(original)) => RestoreTarget::Dataset(original),
+        (None, None) => {
+            anyhow::bail!("can't tell which dataset {:?} came from; use --target or --under",
+                          backup_path);
+        }
+    };
+
+    let (RestoreTarget::Dataset(dataset)
+        | RestoreTarget::UnderWithPath(dataset)
+        | RestoreTarget::UnderWithName(dataset)) = target;
+    let _lock = take_lock(lock_options, Some(&[dataset]))?;
+
+    let z = new_zsnapmgr();
+
+    // Restoring a full backup over an existing dataset replaces it entirely, snapshots and all,
+    // so make sure.
+    let mut rollback = force;
+    if let RestoreTarget::Dataset(dataset) = target {
+        if !force && z.dataset_exists(dataset) {
+            let snapshots = z.get_snapshots(Some(dataset)).map(|s| s.len()).unwrap_or(0);
+            println!("{} already exists. Restoring over it will replace it with the backup, \
+                      destroying its current contents and all {} of its snapshots.",
+                     dataset, snapshots);
+            printf!("Destroy and overwrite {}? [y/N] ", dataset);
+            let mut input = String::new();
+            io::stdin().read_line(&mut input)?;
+            if !input.trim().eq_ignore_ascii_case("y") {
+                println!("Not restoring.");
+                return Ok(());
+            }
+            rollback = true;
+        }
+    }
+
+    let passphrase = match passphrase {
+        Some(passphrase) => passphrase,
+        None => getpass("GPG passphrase: ")?,
+    };
+    z.restore(backup_path, target, &passphrase, rollback)?;
+    Ok(())
+}
+
+// Delete backups of snapshots which no longer exist, after asking.
+fn prune_files(dir: &Path, dry_run: bool, yes: bool, lock_options: &LockOptions)
+    -> anyhow::Result<()>
+{
+    let _lock = if dry_run { None } else { take_lock(lock_options, None)? };
+    let z = new_zsnapmgr();
+    let orphans = z.orphan_backup_files(dir)?;
+    if orphans.is_empty() {
+        println!("No backups of deleted snapshots found.");
+        return Ok(());
+    }
+
+    println!("Backups of snapshots which no longer exist:");
+    for path in &orphans {
+        println!("\t{}", path.display());
+    }
+    if dry_run {
+        println!("dry run: not deleting them.");
+        return Ok(());
+    }
+
+    if !yes {
+        printf!("Delete these {} backup(s)? [y/N] ", orphans.len());
+        let mut input = String::new();
+        io::stdin().read_line(&mut input)?;
+        if !input.trim().eq_ignore_ascii_case("y") {
+            println!("Not deleting them.");
+            return Ok(());
+        }
+    }
+
+    let mut failures = 0;
+    for path in &orphans {
+        if let Err(e) = remove_backup_files(path) {
+            println!("{}: {}", path.display(), e);
+            failures += 1;
+        }
+    }
+    if failures > 0 {
+        anyhow::bail!("failed to delete {} backup(s)", failures);
+    }
+    println!("Deleted {} backup(s).", orphans.len());
+    Ok(())
+}
+
+// Do whatever a 'run' config says is due: manage snapshots, and/or back up every volume which has
+// been backed up before.
+fn run_scheduled(config_path: &Path, dry_run: bool, force: bool, lock_options: &LockOptions)
+    -> anyhow::Result<()>
+{
+    let config = RunConfig::read(config_path).map_err(anyhow::Error::msg)?;
+    let mut state = RunState::load(&config.state_file).map_err(|e| anyhow::Error::from(e)
+        .context(format!("failed to read {:?}", config.state_file)))?;
+    let today = Local::now().date_naive();
+    let due = |task: &str, every: Option<u32>| match every {
+        Some(every) => force || is_due(state.last(task), every, today),
+        None => false,
+    };
+    let snapshots_due = due("snapshots", config.snapshot_every);
+    let backups_due = due("backups", config.backup_every);
+
+    if dry_run {
+        for (task, is_due) in [("snapshots", snapshots_due), ("backups", backups_due)] {
+            let last = state.last(task)
+                .map(|date| date.format("%Y-%m-%d").to_string())
+                .unwrap_or_else(|| "never".to_owned());
+            println!("{}: {} (last done: {})", task, if is_due { "due" } else { "not due" }, last);
+        }
+        return Ok(());
+    }
+    if !snapshots_due && !backups_due {
+        println!("Nothing is due.");
+        return Ok(());
+    }
+
+    let _lock = take_lock(lock_options, None)?;
+    let mut record = |task: &str| {
+        if let Err(e) = state.record(task) {
+            eprintln!("warning: failed to record the time in {:?}: {}", config.state_file, e);
+        }
+    };
+
+    let mut snapshot_result = Ok(());
+    if snapshots_due {
+        println!("Managing snapshots.");
+        snapshot_result = snapshot_automanage(&config.automanage, false);
+        if snapshot_result.is_ok() {
+            record("snapshots");
+        }
+    }
+
+    let mut backup_result = Ok(());
+    if backups_due {
+        let dir = config.backup_dir.as_deref().unwrap();
+        println!("Backing up to {}.", dir.display());
+        let z = new_zsnapmgr();
+        let mut backups = gather_volumes(&z, dir);
+        for backup in &mut backups {
+            if backup.recipient.is_none() && backup.passphrase_file.is_none() {
+                backup.passphrase_file.clone_from(&config.passphrase_file);
+            }
+        }
+        let options = BatchOptions {
+            only_incremental: config.only_incremental,
+            ..BatchOptions::default()
+        };
+        backup_result = do_backups(&backups, dir, &options);
+        if backup_result.is_ok() {
+            record("backups");
+        }
+    }
+
+    match (snapshot_result, backup_result) {
+        (Err(e), Err(backup_e)) => {
+            eprintln!("Error: {:?}", e);
+            Err(backup_e)
+        }
+        (Err(e), Ok(())) | (Ok(()), Err(e)) => Err(e),
+        (Ok(()), Ok(())) => Ok(()),
+    }
+}
+
+// Rename a volume's manually-named snapshots so automanage takes them over.
+fn adopt(volume: &str, prefix: &str, dry_run: bool, yes: bool) -> anyhow::Result<()> {
+    let z = new_zsnapmgr();
+    let candidates = z.adoption_candidates(volume, prefix)?;
+    if candidates.is_empty() {
+        println!("{} has no unmanaged snapshots.", volume);
+        return Ok(());
+    }
+
+    let mut table = new_table(&["snapshot", "created", "new name"]);
+    for adoption in &candidates {
+        table.push(vec![adoption.snapshot.clone(),
+                        adoption.created.format("%Y-%m-%d").to_string(),
+                        adoption.new_name.clone()
+                            .unwrap_or_else(|| "(skipped: that day is taken)".to_owned())]);
+    }
+    print!("{}", table);
+
+    let renames: Vec<(&str, &str)> = candidates.iter()
+        .filter_map(|adoption| Some((adoption.snapshot.as_str(), adoption.new_name.as_deref()?)))
+        .collect();
+    if renames.is_empty() {
+        println!("Nothing can be renamed.");
+        return Ok(());
+    }
+    if dry_run {
+        println!("dry run: not renaming them.");
+        return Ok(());
+    }
+    if !yes {
+        printf!("Rename these {} snapshot(s)? [y/N] ", renames.len());
+        let mut input = String::new();
+        io::stdin().read_line(&mut input)?;
+        if !input.trim().eq_ignore_ascii_case("y") {
+            println!("Not renaming them.");
+            return Ok(());
+        }
+    }
+
+    let mut failures = 0;
+    for (snapshot, new_name) in &renames {
+        match z.rename_snapshot(snapshot, new_name) {
+            Ok(()) => println!("ZFS RENAME {} -> @{}", snapshot, new_name),
+            Err(e) => {
+                println!("{}: {}", snapshot, e);
+                failures += 1;
+            }
+        }
+    }
+    if failures > 0 {
+        anyhow::bail!("failed to rename {} snapshot(s)", failures);
+    }
+    Ok(())
+}
+
+// Regenerate the hash sidecars of a backup file, or of all the backup files in a directory.
+fn rehash(path: &Path, force: bool) -> anyhow::Result<()> {
+    let files = if path.is_dir() {
+        let mut files: Vec<_> = fs::read_dir(path)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().map(|t| t.is_file()).unwrap_or(false))
+            .filter(|entry| is_backup_data_file(&entry.file_name().to_string_lossy()))
+            .map(|entry| entry.path())
+            .collect();
+        files.sort();
+        files
+    } else {
+        vec![path.to_owned()]
+    };
+
+    let mut failures = 0;
+    for file in &files {
+        match rehash_file(file, force) {
+            Ok(Rehash::Written) => println!("{}: wrote sidecar", file.display()),
+            Ok(Rehash::Skipped) => {
+                println!("{}: already has a sidecar; skipped (use --force to redo it)",
+                         file.display());
+            }
+            Ok(Rehash::Encrypted) => {
+                println!("{}: has an encrypted sidecar; skipped", file.display());
+            }
+            Err(e) => {
+                println!("{}: {}", file.display(), e);
+                failures += 1;
+            }
+        }
+    }
+    if failures > 0 {
+        anyhow::bail!("failed to rehash {} file(s)", failures);
+    }
+    Ok(())
+}
+
+// Check a backup (or a split backup's chunks) against the hashes recorded when it was written.
+fn verify(backup_path: &Path, from_chunk: usize) -> anyhow::Result<()> {
+    // An encrypted manifest (see --encrypt-metadata) can only be read with the passphrase.
+    let manifest = manifest_path(backup_path);
+    let manifest = if manifest.exists() {
+        Some(read_backup_manifest(backup_path, None)?)
+    } else if encrypted_metadata_path(&manifest).exists() {
+        let passphrase = getpass("GPG passphrase: ")?;
+        Some(read_backup_manifest(backup_path, Some(&passphrase))?)
+    } else {
+        None
+    };
+
+    let last_line_length = AtomicUsize::new(0);
+    zsnapmgr::verify_backup(backup_path, manifest.as_ref(), from_chunk,
+                            &move |event| print_event(event, &last_line_length))?;
+    println!("{}: OK", backup_path.display());
+    Ok(())
+}
+
+/// How commands which make changes lock out other instances of zsnapmgr.
+#[derive(Debug)]
+struct LockOptions {
+    scope: LockScope,
+    /// Wait for other instances to finish, instead of bailing out.
+    wait: bool,
+}
+
+// Take the lock(s) needed to work on the pools of the given datasets (or all pools).
+fn take_lock(options: &LockOptions, datasets: Option<&[&str]>) -> anyhow::Result<Option<Lock>> {
+    let names: Vec<String> = match options.scope {
+        LockScope::Disabled => return Ok(None),
+        LockScope::Global => vec!["global".to_owned()],
+        LockScope::PerPool => {
+            let pool = |dataset: &str| dataset.split(['/', '@']).next().unwrap_or("").to_owned();
+            match datasets {
+                Some(datasets) => datasets.iter().map(|ds| pool(ds)).collect(),
+                None => new_zsnapmgr().get_volumes()?.iter().map(|vol| pool(vol)).collect(),
+            }
+        }
+    };
+
+    match Lock::acquire(&names, options.wait) {
+        Ok(lock) => Ok(Some(lock)),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+            anyhow::bail!("another zsnapmgr is running (use --wait-for-lock to wait for it)");
+        }
+        Err(e) => Err(anyhow::Error::from(e).context("failed to take lock")),
+    }
+}
+
+// What zsnapmgr's exit status means, so that scripts and cron jobs can tell kinds of failure
+// apart.
+const EXIT_FAILURE: i32 = 1; // Anything not covered below.
+const EXIT_USAGE: i32 = 2; // The command line was wrong.
+const EXIT_ZFS: i32 = 3; // Something done with ZFS (or backup files) failed.
+const EXIT_BACKUP: i32 = 4; // One or more backups failed.
+const EXIT_VERIFY: i32 = 5; // A backup file didn't match what was written to it.
+
+/// Failures which get their own exit code, beyond what the error itself says.
+#[derive(Debug)]
+enum Failure {
+    Backup(String),
+    Verification(String),
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Failure::Backup(message) | Failure::Verification(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for Failure {}
+
+fn exit_code(e: &anyhow::Error) -> i32 {
+    match e.downcast_ref::<Failure>() {
+        Some(Failure::Backup(_)) => EXIT_BACKUP,
+        Some(Failure::Verification(_)) => EXIT_VERIFY,
+        None => match e.chain().find_map(|cause| cause.downcast_ref::<ZfsError>()) {
+            Some(e) if e.is_verification_failure() => EXIT_VERIFY,
+            Some(_) => EXIT_ZFS,
+            None => EXIT_FAILURE,
+        },
+    }
+}
+
+#[test]
+fn test_exit_code() {
+    assert_eq!(exit_code(&anyhow::anyhow!("oops")), EXIT_FAILURE);
+    assert_eq!(exit_code(&ZfsError::from("no such dataset").into()), EXIT_ZFS);
+    let bad = ZfsError::verification_failed("bad".to_owned());
+    assert_eq!(exit_code(&anyhow::Error::from(bad).context("backing up")), EXIT_VERIFY);
+    let not_utf8 = io::Error::new(io::ErrorKind::InvalidData, "stream did not contain valid UTF-8");
+    assert_eq!(exit_code(&ZfsError::from(("failed to read manifest", not_utf8)).into()), EXIT_ZFS);
+    assert_eq!(exit_code(&Failure::Backup("1 of 2 backup(s) failed".to_owned()).into()),
+               EXIT_BACKUP);
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {:?}", e);
+        process::exit(exit_code(&e));
+    }
+}
+
+fn run() -> anyhow::Result<()> {
+    eprintln!("zsnapmgr3/{}", env!("GIT_HASH"));
+
+    let mut args: Vec<OsString> = env::args_os().collect();
+
+    // Options which apply to all commands come before the command.
+    let mut lock_options = LockOptions { scope: LockScope::Global, wait: false };
+    while args.len() > 1 {
+        match args[1].to_str() {
+            Some("--lock-scope") if args.len() > 2 => {
+                lock_options.scope = match args[2].to_str() {
+                    Some("global") => LockScope::Global,
+                    Some("pool") => LockScope::PerPool,
+                    Some("none") => LockScope::Disabled,
+                    _ => {
+                        println!("--lock-scope must be one of: global, pool, none");
+                        process::exit(EXIT_USAGE);
+                    }
+                };
+                args.drain(1..3);
+            }
+            Some("--wait-for-lock") => {
+                lock_options.wait = true;
+                args.remove(1);
+            }
+            Some("-v") | Some("--verbose") => {
+                VERBOSE.store(true, Ordering::Relaxed);
+                args.remove(1);
+            }
+            Some("--table-separator") => {
+                match args.get(2).and_then(|arg| arg.to_str()) {
+                    Some(separator) => { TABLE_SEPARATOR.set(separator.to_owned()).ok(); }
+                    None => {
+                        println!("--table-separator needs a separator, or \"box\"");
+                        process::exit(EXIT_USAGE);
+                    }
+                }
+                args.drain(1..3);
+            }
+            _ => break,
+        }
+    }
 
-    let args: Vec<OsString> = env::args_os().collect();
     let program_name = Path::new(&args[0]);
 
     let command = if args.len() < 2 {
@@ -497,22 +2624,825 @@ fn main() -> anyhow::Result<()> {
 
     match command.to_str() {
         Some("backup") => {
+            let usage = || -> ! {
+                println!("usage: {} backup [--status-file <path>] [--buffer-size <bytes>] \
+                          [--hash-stream] [--gpg-compress] [--list-only] [--from <file>] \
+                          [--force-full | --only-incremental] [--restart] [--split <bytes>] \
+                          [--stdin] [--sort <name | age | size>] [--skip-pool-roots] \
+                          [--progress-interval <seconds>] [--properties] \
+                          [--all-intermediates] \
+                          [--keep-going | --fail-fast] [--pre-backup-cmd <command>] \
+                          [--post-backup-cmd <command>] [--hook-errors-fatal] \
+                          [--notify-cmd <command>] [--notify-url <url>] \
+                          [--incremental-from-file <backup_file>] \
+                          [--output-name <template> | --layout <flat | date-tree>] \
+                          [--dry-run] [--keep-partial-on-error] [--sha512 | --no-hash] \
+                          [--cipher <algorithm>] [--force] [--compressed] [--large-blocks] \
+                          [--embed-data] [--no-zstd] \
+                          [--dest-keep-going] [--verify-after] [--encrypt-metadata] \
+                          <backups_location | --dest <backups_location> ...>",
+                         program_name.display());
+                process::exit(EXIT_USAGE);
+            };
+
+            let mut backups_dir = None;
+            let mut list_only = false;
+            let mut from_stdin = false;
+            let mut incremental_from_file = None;
+            let mut from_file = None;
+            let mut options = BatchOptions::default();
+            let mut iter = args[2..].iter();
+            while let Some(arg) = iter.next() {
+                match arg.to_str() {
+                    Some("--status-file") => {
+                        options.backup.send.status_file = Some(iter.next().unwrap_or_else(|| usage()).into());
+                    }
+                    Some("--buffer-size") => {
+                        let value = iter.next().unwrap_or_else(|| usage());
+                        match value.to_str().and_then(parse_size) {
+                            Some(n) if n > 0 => options.backup.send.buffer_size = n as usize,
+                            _ => {
+                                println!("invalid buffer size {:?}", value);
+                                usage();
+                            }
+                        }
+                    }
+                    Some("--progress-interval") => {
+                        let value = iter.next().unwrap_or_else(|| usage());
+                        match value.to_str().and_then(|secs| secs.parse::<f64>().ok()) {
+                            Some(secs) if secs > 0. => {
+                                options.backup.send.progress_interval =
+                                    Duration::from_secs_f64(secs);
+                            }
+                            _ => {
+                                println!("invalid progress interval {:?}", value);
+                                usage();
+                            }
+                        }
+                    }
+                    Some("--all-intermediates") => options.backup.send.all_intermediates = true,
+                    Some("--dry-run") => options.backup.send.dry_run = true,
+                    Some("--keep-partial-on-error") => {
+                        options.backup.send.keep_partial_on_error = true;
+                    }
+                    Some("--properties") => options.backup.send.send_properties = true,
+                    Some("--compressed") => options.backup.send.use_zfs_compression = true,
+                    Some("--large-blocks") => options.backup.send.large_blocks = true,
+                    Some("--embed-data") => options.backup.send.embedded_data = true,
+                    Some("--no-zstd") => options.backup.skip_zstd = true,
+                    Some("--hash-stream") => options.backup.send.hash_source_stream = true,
+                    Some("--sha512") => options.backup.send.sha512 = true,
+                    Some("--no-hash") => options.backup.send.no_hash = true,
+                    Some("--verify-after") => options.backup.send.verify_after = true,
+                    Some("--gpg-compress") => options.backup.gpg_compression = true,
+                    Some("--output-name") => {
+                        if options.backup.name_template.is_some() {
+                            println!("--output-name and --layout can't be used together");
+                            usage();
+                        }
+                        options.backup.name_template = Some(iter.next()
+                            .and_then(|template| template.to_str())
+                            .unwrap_or_else(|| usage())
+                            .to_owned());
+                    }
+                    Some("--layout") => {
+                        if options.backup.name_template.is_some() {
+                            println!("--output-name and --layout can't be used together");
+                            usage();
+                        }
+                        options.backup.name_template = match iter.next().and_then(|l| l.to_str()) {
+                            Some("flat") => None,
+                            Some("date-tree") => Some(DATE_TREE_TEMPLATE.to_owned()),
+                            _ => usage(),
+                        };
+                    }
+                    Some("--cipher") => {
+                        let name = iter.next()
+                            .and_then(|name| name.to_str())
+                            .unwrap_or_else(|| usage());
+                        match zsnapmgr::gpg_cipher(name) {
+                            Ok(cipher) => options.backup.cipher = Some(cipher.to_owned()),
+                            Err(e) => {
+                                println!("{}", e);
+                                usage();
+                            }
+                        }
+                    }
+                    Some("--force-full") => options.force_full = true,
+                    Some("--only-incremental") => options.only_incremental = true,
+                    Some("--restart") => options.restart = true,
+                    Some("--sort") => {
+                        options.sort = iter.next()
+                            .and_then(|s| s.to_str())
+                            .and_then(SortOrder::parse)
+                            .unwrap_or_else(|| usage());
+                    }
+                    Some("--force") => {
+                        options.backup.allow_same_pool = true;
+                        options.ignore_pool_health = true;
+                    }
+                    Some("--fail-fast") => options.fail_fast = true,
+                    Some("--skip-pool-roots") => options.skip_pool_roots = true,
+                    Some("--pre-backup-cmd") => {
+                        options.pre_backup_cmd = Some(iter.next()
+                            .and_then(|cmd| cmd.to_str())
+                            .unwrap_or_else(|| usage())
+                            .to_owned());
+                    }
+                    Some("--post-backup-cmd") => {
+                        options.post_backup_cmd = Some(iter.next()
+                            .and_then(|cmd| cmd.to_str())
+                            .unwrap_or_else(|| usage())
+                            .to_owned());
+                    }
+                    Some("--hook-errors-fatal") => options.hook_errors_fatal = true,
+                    Some("--notify-cmd") => {
+                        options.notify_cmd = Some(iter.next()
+                            .and_then(|cmd| cmd.to_str())
+                            .unwrap_or_else(|| usage())
+                            .to_owned());
+                    }
+                    Some("--notify-url") => {
+                        options.notify_url = Some(iter.next()
+                            .and_then(|url| url.to_str())
+                            .unwrap_or_else(|| usage())
+                            .to_owned());
+                    }
+                    Some("--keep-going") => options.fail_fast = false,
+                    Some("--stdin") => from_stdin = true,
+                    Some("--incremental-from-file") => {
+                        incremental_from_file =
+                            Some(Path::new(iter.next().unwrap_or_else(|| usage())));
+                    }
+                    Some("--dest") => {
+                        let dir = Path::new(iter.next().unwrap_or_else(|| usage()));
+                        if backups_dir.is_none() {
+                            backups_dir = Some(dir);
+                        } else {
+                            options.backup.extra_dirs.push(dir.to_owned());
+                        }
+                    }
+                    Some("--encrypt-metadata") => options.backup.encrypt_metadata = true,
+                    Some("--dest-keep-going") => {
+                        options.backup.send.keep_going_on_destination_error = true;
+                    }
+                    Some("--split") => {
+                        let value = iter.next().unwrap_or_else(|| usage());
+                        match value.to_str().and_then(parse_size) {
+                            Some(n) if n > 0 => options.backup.send.split_size = Some(n),
+                            _ => {
+                                println!("invalid split size {:?}", value);
+                                usage();
+                            }
+                        }
+                    }
+                    Some("--list-only") => list_only = true,
+                    Some("--from") => {
+                        from_file = Some(Path::new(iter.next().unwrap_or_else(|| usage())));
+                    }
+                    _ if backups_dir.is_none() => backups_dir = Some(Path::new(arg)),
+                    _ => usage(),
+                }
+            }
+
+            if options.force_full && options.only_incremental {
+                println!("--force-full and --only-incremental can't be used together");
+                usage();
+            }
+            if options.force_full && incremental_from_file.is_some() {
+                println!("--force-full and --incremental-from-file can't be used together");
+                usage();
+            }
+            if options.backup.send.no_hash
+                && (options.backup.send.sha512 || options.backup.send.split_size.is_some()
+                    || options.backup.send.verify_after)
+            {
+                println!("--no-hash can't be used with --sha512, --split, or --verify-after");
+                usage();
+            }
+            if !options.backup.extra_dirs.is_empty() && options.backup.send.split_size.is_some() {
+                println!("--split can't be used with more than one --dest");
+                usage();
+            }
+
+            let _lock = if list_only { None } else { take_lock(&lock_options, None)? };
+            match backups_dir {
+                Some(dir) if from_file.is_some() => {
+                    let mut backups = load_backups(from_file.unwrap())?;
+                    if options.force_full {
+                        for backup in &mut backups {
+                            backup.start_snapshot = None;
+                        }
+                    }
+                    do_backups(&backups, dir, &options)?;
+                }
+                Some(dir) if from_stdin => backup_from_stdin(dir, &options)?,
+                Some(dir) if incremental_from_file.is_some() => {
+                    backup_incremental_from_file(dir, incremental_from_file.unwrap(), &options)?;
+                }
+                Some(dir) if list_only => {
+                    let z = new_zsnapmgr();
+                    list_backup_files(&z, dir)?;
+                }
+                Some(dir) => interactive_backup(dir, &options)?,
+                None => usage(),
+            }
+        }
+        Some("backup-snapshot") => {
+            let usage = || -> ! {
+                println!("usage: {} backup-snapshot [--incremental-from <snapshot>] \
+                          [--passphrase-file <path>] [--dry-run] <snapshot> <backups_location>",
+                         program_name.display());
+                process::exit(EXIT_USAGE);
+            };
+
+            let mut incremental_from = None;
+            let mut passphrase_file = None;
+            let mut options = BackupOptions::default();
+            let mut positional = vec![];
+            let mut iter = args[2..].iter();
+            while let Some(arg) = iter.next() {
+                match arg.to_str() {
+                    Some("--incremental-from") => {
+                        incremental_from = Some(iter.next()
+                            .and_then(|start| start.to_str())
+                            .unwrap_or_else(|| usage()));
+                    }
+                    Some("--passphrase-file") => {
+                        passphrase_file = Some(Path::new(iter.next().unwrap_or_else(|| usage())));
+                    }
+                    Some("--dry-run") => options.send.dry_run = true,
+                    Some(arg) if !arg.starts_with("--") => positional.push(arg),
+                    _ => usage(),
+                }
+            }
+
+            match positional[..] {
+                [snapshot, dir] => {
+                    let _lock = take_lock(&lock_options, Some(&[snapshot]))?;
+                    backup_single_snapshot(snapshot, Path::new(dir), incremental_from,
+                                           passphrase_file, &options)?;
+                }
+                _ => usage(),
+            }
+        }
+        Some("status") => {
             if args.len() == 3 {
-                interactive_backup(Path::new(&args[2]));
+                backup_status(Path::new(&args[2]))?;
             } else {
-                println!("usage: {} backup <backups_location>", program_name.display());
-                process::exit(-1);
+                println!("usage: {} status <backups_location>", program_name.display());
+                process::exit(EXIT_USAGE);
+            }
+        }
+        Some("ls") => {
+            let usage = || -> ! {
+                println!("usage: {} ls [--parsable] [--sort <name | age>] [--skip-pool-roots]",
+                         program_name.display());
+                process::exit(EXIT_USAGE);
+            };
+
+            let mut parsable = false;
+            let mut sort = SortOrder::Name;
+            let mut skip_pool_roots = false;
+            let mut iter = args[2..].iter();
+            while let Some(arg) = iter.next() {
+                match arg.to_str() {
+                    Some("--parsable") | Some("-H") => parsable = true,
+                    Some("--skip-pool-roots") => skip_pool_roots = true,
+                    Some("--sort") => {
+                        let order = iter.next().and_then(|s| s.to_str()).and_then(SortOrder::parse);
+                        sort = match order {
+                            // There are no size estimates to go by here.
+                            Some(SortOrder::Size) | None => usage(),
+                            Some(order) => order,
+                        };
+                    }
+                    _ => usage(),
+                }
+            }
+            list_volumes(parsable, sort, skip_pool_roots)?;
+        }
+        Some("snapshot") => {
+            let mut recursive = false;
+            let mut positional = vec![];
+            for arg in &args[2..] {
+                if arg == "-r" {
+                    recursive = true;
+                } else {
+                    positional.extend(arg.to_str());
+                }
+            }
+
+            if positional.is_empty() || positional.len() > 2 {
+                println!("usage: {} snapshot [-r] <dataset> [name]", program_name.display());
+                process::exit(EXIT_USAGE);
+            }
+
+            let _lock = take_lock(&lock_options, Some(&positional[..1]))?;
+            let z = new_zsnapmgr();
+            for snapshot in z.snapshot(positional[0], positional.get(1).copied(), recursive)? {
+                println!("ZFS SNAPSHOT {}", snapshot);
+            }
+        }
+        Some("destroy") => {
+            let usage = || -> ! {
+                println!("usage: {} destroy [--yes] [--force] [--backups <backups_location>] \
+                          <snapshot>",
+                         program_name.display());
+                process::exit(EXIT_USAGE);
+            };
+
+            let mut yes = false;
+            let mut force = false;
+            let mut backups_dir = None;
+            let mut snapshot = None;
+            let mut iter = args[2..].iter();
+            while let Some(arg) = iter.next() {
+                match arg.to_str() {
+                    Some("--yes") | Some("-y") => yes = true,
+                    Some("--force") => force = true,
+                    Some("--backups") => {
+                        backups_dir = Some(Path::new(iter.next().unwrap_or_else(|| usage())));
+                    }
+                    Some(name) if snapshot.is_none() => snapshot = Some(name),
+                    _ => usage(),
+                }
+            }
+
+            match snapshot {
+                Some(snapshot) => {
+                    let _lock = take_lock(&lock_options, Some(&[snapshot]))?;
+                    destroy_snapshot(snapshot, backups_dir, yes, force)?;
+                }
+                None => usage(),
+            }
+        }
+        Some(cmd @ "hold") | Some(cmd @ "release") => {
+            let snapshot = match args.get(2).and_then(|arg| arg.to_str()) {
+                Some(snapshot) if args.len() <= 4 => snapshot,
+                _ => {
+                    println!("usage: {} {} <snapshot> [tag]", program_name.display(), cmd);
+                    process::exit(EXIT_USAGE);
+                }
+            };
+            let tag = args.get(3).and_then(|arg| arg.to_str()).unwrap_or("zsnapmgr");
+
+            let _lock = take_lock(&lock_options, Some(&[snapshot]))?;
+            let z = new_zsnapmgr();
+            if cmd == "hold" {
+                z.hold(snapshot, tag)?;
+            } else {
+                z.release(snapshot, tag)?;
+            }
+        }
+        Some("restore") => {
+            let usage = || -> ! {
+                println!("usage: {} restore [--target <dataset> | --under <dataset> [--keep-path]] \
+                          [--force] <backup_file>",
+                         program_name.display());
+                process::exit(EXIT_USAGE);
+            };
+
+            let mut target = None;
+            let mut under = None;
+            let mut keep_path = false;
+            let mut force = false;
+            let mut file = None;
+            let mut iter = args[2..].iter();
+            while let Some(arg) = iter.next() {
+                match arg.to_str() {
+                    Some("--target") if under.is_none() => {
+                        target = Some(iter.next()
+                            .and_then(|target| target.to_str())
+                            .unwrap_or_else(|| usage()));
+                    }
+                    Some("--under") if target.is_none() => {
+                        under = Some(iter.next()
+                            .and_then(|under| under.to_str())
+                            .unwrap_or_else(|| usage()));
+                    }
+                    Some("--keep-path") => keep_path = true,
+                    Some("--force") => force = true,
+                    _ if file.is_none() => file = Some(Path::new(arg)),
+                    _ => usage(),
+                }
+            }
+
+            if keep_path && under.is_none() {
+                println!("--keep-path can only be used with --under");
+                usage();
+            }
+
+            let target = match (target, under) {
+                (Some(target), None) => Some(RestoreTarget::Dataset(target)),
+                (None, Some(under)) if keep_path => Some(RestoreTarget::UnderWithPath(under)),
+                (None, Some(under)) => Some(RestoreTarget::UnderWithName(under)),
+                _ => None,
+            };
+
+            match file {
+                Some(file) => restore(file, target, force, &lock_options)?,
+                None => usage(),
+            }
+        }
+        Some("rehash") => {
+            let mut force = false;
+            let mut paths = vec![];
+            for arg in &args[2..] {
+                if arg == "--force" {
+                    force = true;
+                } else {
+                    paths.push(Path::new(arg));
+                }
+            }
+
+            if paths.len() != 1 {
+                println!("usage: {} rehash [--force] <backup_file | backups_location>",
+                         program_name.display());
+                process::exit(EXIT_USAGE);
+            }
+            let _lock = take_lock(&lock_options, None)?;
+            rehash(paths[0], force)?;
+        }
+        Some("verify") => {
+            let usage = || -> ! {
+                println!("usage: {} verify [--from-chunk <n>] <backup_file>",
+                         program_name.display());
+                process::exit(EXIT_USAGE);
+            };
+
+            let mut from_chunk = 0;
+            let mut file = None;
+            let mut iter = args[2..].iter();
+            while let Some(arg) = iter.next() {
+                match arg.to_str() {
+                    Some("--from-chunk") => {
+                        from_chunk = iter.next()
+                            .and_then(|n| n.to_str())
+                            .and_then(|n| n.parse::<usize>().ok())
+                            .unwrap_or_else(|| usage());
+                    }
+                    _ if file.is_none() => file = Some(Path::new(arg)),
+                    _ => usage(),
+                }
+            }
+
+            match file {
+                Some(file) => verify(file, from_chunk)?,
+                None => usage(),
+            }
+        }
+        Some("prune-files") => {
+            let usage = || -> ! {
+                println!("usage: {} prune-files [--dry-run] [--yes] <backups_location>",
+                         program_name.display());
+                process::exit(EXIT_USAGE);
+            };
+
+            let mut dry_run = false;
+            let mut yes = false;
+            let mut dir = None;
+            for arg in &args[2..] {
+                match arg.to_str() {
+                    Some("--dry-run") => dry_run = true,
+                    Some("--yes") | Some("-y") => yes = true,
+                    _ if dir.is_none() => dir = Some(Path::new(arg)),
+                    _ => usage(),
+                }
+            }
+
+            match dir {
+                Some(dir) => prune_files(dir, dry_run, yes, &lock_options)?,
+                None => usage(),
+            }
+        }
+        Some("clean") => {
+            let usage = || -> ! {
+                println!("usage: {} clean [--recursive] [--dry-run] [--yes] <backups_location>",
+                         program_name.display());
+                process::exit(EXIT_USAGE);
+            };
+
+            let mut recursive = false;
+            let mut dry_run = false;
+            let mut yes = false;
+            let mut dir = None;
+            for arg in &args[2..] {
+                match arg.to_str() {
+                    Some("--recursive") | Some("-r") => recursive = true,
+                    Some("--dry-run") => dry_run = true,
+                    Some("--yes") | Some("-y") => yes = true,
+                    _ if dir.is_none() => dir = Some(Path::new(arg)),
+                    _ => usage(),
+                }
+            }
+
+            match dir {
+                Some(dir) => {
+                    // Keep backups from starting (and making partial files of their own) while
+                    // these are looked at and deleted.
+                    let _lock = if dry_run { None } else { take_lock(&lock_options, None)? };
+                    clean_partials(dir, recursive, dry_run, yes)?;
+                }
+                None => usage(),
+            }
+        }
+        Some("browse") => {
+            let usage = || -> ! {
+                println!("usage: {} browse [--pool <pool>] <backup_file>", program_name.display());
+                process::exit(EXIT_USAGE);
+            };
+
+            let mut pool = None;
+            let mut file = None;
+            let mut iter = args[2..].iter();
+            while let Some(arg) = iter.next() {
+                match arg.to_str() {
+                    Some("--pool") => {
+                        pool = Some(iter.next()
+                            .and_then(|pool| pool.to_str())
+                            .unwrap_or_else(|| usage()));
+                    }
+                    _ if file.is_none() => file = Some(Path::new(arg)),
+                    _ => usage(),
+                }
+            }
+
+            match file {
+                Some(file) => browse(file, pool, &lock_options)?,
+                None => usage(),
+            }
+        }
+        Some("adopt") => {
+            let usage = || -> ! {
+                println!("usage: {} adopt [--prefix <prefix>] [--dry-run] [--yes] <volume>",
+                         program_name.display());
+                process::exit(EXIT_USAGE);
+            };
+
+            let mut prefix = "";
+            let mut dry_run = false;
+            let mut yes = false;
+            let mut volume = None;
+            let mut iter = args[2..].iter();
+            while let Some(arg) = iter.next() {
+                match arg.to_str() {
+                    Some("--prefix") => {
+                        prefix = iter.next().and_then(|s| s.to_str()).unwrap_or_else(|| usage());
+                    }
+                    Some("--dry-run") => dry_run = true,
+                    Some("--yes") => yes = true,
+                    Some(name) if volume.is_none() => volume = Some(name),
+                    _ => usage(),
+                }
+            }
+
+            match volume {
+                Some(volume) => {
+                    let _lock = if dry_run {
+                        None
+                    } else {
+                        Some(take_lock(&lock_options, Some(&[volume]))?)
+                    };
+                    adopt(volume, prefix, dry_run, yes)?;
+                }
+                None => usage(),
+            }
+        }
+        Some("diff") => {
+            match (args.get(2).and_then(|arg| arg.to_str()),
+                   args.get(3).and_then(|arg| arg.to_str()),
+                   args.len())
+            {
+                (Some(from), Some(to), 4) => diff(from, to)?,
+                _ => {
+                    println!("usage: {} diff <snapshot> <later snapshot | volume>",
+                             program_name.display());
+                    process::exit(EXIT_USAGE);
+                }
+            }
+        }
+        Some("run") => {
+            let usage = || -> ! {
+                println!("usage: {} run [--dry-run] [--force] <config_file>",
+                         program_name.display());
+                process::exit(EXIT_USAGE);
+            };
+
+            let mut dry_run = false;
+            let mut force = false;
+            let mut config = None;
+            for arg in &args[2..] {
+                match arg.to_str() {
+                    Some("--dry-run") => dry_run = true,
+                    Some("--force") => force = true,
+                    _ if config.is_none() => config = Some(Path::new(arg)),
+                    _ => usage(),
+                }
+            }
+
+            match config {
+                Some(config) => run_scheduled(config, dry_run, force, &lock_options)?,
+                None => usage(),
+            }
+        }
+        Some("benchmark") => {
+            let usage = || -> ! {
+                println!("usage: {} benchmark [--sample-size <bytes>] [--compressor <command>]... \
+                          <snapshot>",
+                         program_name.display());
+                process::exit(EXIT_USAGE);
+            };
+
+            let mut sample_size = 256 << 20;
+            let mut compressors = vec![];
+            let mut snapshot = None;
+            let mut iter = args[2..].iter();
+            while let Some(arg) = iter.next() {
+                match arg.to_str() {
+                    Some("--sample-size") => {
+                        sample_size = iter.next()
+                            .and_then(|s| s.to_str())
+                            .and_then(parse_size)
+                            .filter(|&n| n > 0)
+                            .unwrap_or_else(|| usage());
+                        // The sample is kept in memory while each compressor is run on it.
+                        if sample_size > MAX_BENCHMARK_SAMPLE {
+                            println!("--sample-size can be at most {}B",
+                                     human_number(MAX_BENCHMARK_SAMPLE, 1));
+                            usage();
+                        }
+                    }
+                    Some("--compressor") => {
+                        compressors.push(iter.next()
+                            .and_then(|s| s.to_str())
+                            .unwrap_or_else(|| usage()));
+                    }
+                    Some(name) if snapshot.is_none() && name.contains('@') => snapshot = Some(name),
+                    _ => usage(),
+                }
+            }
+            if compressors.is_empty() {
+                compressors.extend_from_slice(BENCHMARK_COMPRESSORS);
+            }
+
+            match snapshot {
+                Some(snapshot) => benchmark(snapshot, sample_size, &compressors)?,
+                None => usage(),
             }
         }
         Some("automanage") => {
-            snapshot_automanage()?;
+            let usage = || -> ! {
+                println!("usage: {} automanage [--prefix <prefix>] [--post-snapshot-cmd <command>] \
+                          [--warn-snapshots <count>] [--min-age <days>] [--skip-unchanged] \
+                          [--skip-canmount-off | --mounted-only] \
+                          [--schedule <max_age>:<interval>,...] \
+                          [--backups <backups_location>] [--changes-only] [--confirm-each] \
+                          [--dry-run [--plan-from <snapshot_list_file>] | --emit-script | \
+                          --emit-delete-list <file> | --delete-from <file>]",
+                         program_name.display());
+                process::exit(EXIT_USAGE);
+            };
+
+            let mut options = AutomanageOptions::default();
+            let mut dry_run = false;
+            let mut emit_script = false;
+            let mut changes_only = false;
+            let mut emit_delete_list = None;
+            let mut delete_from = None;
+            let mut plan_from = None;
+            let mut iter = args[2..].iter();
+            while let Some(arg) = iter.next() {
+                match arg.to_str() {
+                    Some("--prefix") => {
+                        options.prefix = iter.next()
+                            .and_then(|prefix| prefix.to_str())
+                            .unwrap_or_else(|| usage())
+                            .to_owned();
+                    }
+                    Some("--post-snapshot-cmd") => {
+                        options.post_snapshot_cmd = Some(iter.next()
+                            .and_then(|cmd| cmd.to_str())
+                            .unwrap_or_else(|| usage())
+                            .to_owned());
+                    }
+                    Some("--warn-snapshots") => {
+                        let value = iter.next().unwrap_or_else(|| usage());
+                        match value.to_str().and_then(|n| n.parse::<usize>().ok()) {
+                            Some(n) => options.snapshot_count_warning = Some(n),
+                            None => {
+                                println!("invalid snapshot count {:?}", value);
+                                usage();
+                            }
+                        }
+                    }
+                    Some("--skip-unchanged") => options.skip_unchanged = true,
+                    Some("--skip-canmount-off") if options.mount_filter == MountFilter::All => {
+                        options.mount_filter = MountFilter::SkipCanmountOff;
+                    }
+                    Some("--mounted-only") if options.mount_filter == MountFilter::All => {
+                        options.mount_filter = MountFilter::MountedOnly;
+                    }
+                    Some("--schedule") => {
+                        let spec = iter.next().and_then(|s| s.to_str()).unwrap_or_else(|| usage());
+                        match zsnapmgr::parse_schedule(spec) {
+                            Ok(schedule) => options.schedule = Some(schedule),
+                            Err(e) => {
+                                println!("invalid schedule {:?}: {}", spec, e);
+                                usage();
+                            }
+                        }
+                    }
+                    Some("--dry-run") => dry_run = true,
+                    Some("--emit-script") => emit_script = true,
+                    Some("--changes-only") => changes_only = true,
+                    Some("--backups") => {
+                        options.backups_dir =
+                            Some(PathBuf::from(iter.next().unwrap_or_else(|| usage())));
+                    }
+                    Some("--confirm-each") => {
+                        options.confirm = Some(Confirmation(Arc::new(|change| {
+                            match change {
+                                AutomanageChange::Create(snap) => {
+                                    printf!("Create {}? [y/N] ", snap);
+                                }
+                                AutomanageChange::Delete(snap) => {
+                                    printf!("Delete {}? [y/N] ", snap);
+                                }
+                            }
+                            let mut input = String::new();
+                            io::stdin().read_line(&mut input).is_ok()
+                                && input.trim().eq_ignore_ascii_case("y")
+                        })));
+                    }
+                    Some("--emit-delete-list") => {
+                        emit_delete_list = Some(Path::new(iter.next().unwrap_or_else(|| usage())));
+                    }
+                    Some("--delete-from") => {
+                        delete_from = Some(Path::new(iter.next().unwrap_or_else(|| usage())));
+                    }
+                    Some("--plan-from") => {
+                        plan_from = Some(Path::new(iter.next().unwrap_or_else(|| usage())));
+                    }
+                    Some("--min-age") => {
+                        let value = iter.next().unwrap_or_else(|| usage());
+                        match value.to_str().and_then(|n| n.parse::<i64>().ok()) {
+                            Some(n) if n >= 0 => options.min_age_days = n,
+                            _ => {
+                                println!("invalid number of days {:?}", value);
+                                usage();
+                            }
+                        }
+                    }
+                    _ => usage(),
+                }
+            }
+
+            let modes = [dry_run, emit_script, emit_delete_list.is_some(), delete_from.is_some()];
+            if modes.iter().filter(|&&mode| mode).count() > 1 {
+                println!("only one of --dry-run, --emit-script, --emit-delete-list, and \
+                          --delete-from can be used at a time");
+                usage();
+            } else if options.confirm.is_some() && (dry_run || emit_script
+                                                     || emit_delete_list.is_some())
+            {
+                println!("--confirm-each can't be used with --dry-run, --emit-script, or \
+                          --emit-delete-list, which don't change anything");
+                usage();
+            } else if dry_run {
+                plan_automanage(&options, plan_from, changes_only)?;
+            } else if plan_from.is_some() {
+                println!("--plan-from can only be used with --dry-run");
+                usage();
+            } else if emit_script {
+                let z = new_zsnapmgr();
+                let plan = z.plan_automanage(&options)?;
+                print!("{}", z.retention_script(&plan, &options));
+            } else if let Some(path) = emit_delete_list {
+                emit_delete_list_file(&options, path)?;
+            } else if let Some(path) = delete_from {
+                let _lock = take_lock(&lock_options, None)?;
+                delete_from_list(&options, path)?;
+            } else {
+                let _lock = take_lock(&lock_options, None)?;
+                snapshot_automanage(&options, changes_only)?;
+            }
+        }
+        Some("version") | Some("--version") => {
+            println!("zsnapmgr {} ({})", env!("CARGO_PKG_VERSION"), env!("GIT_HASH"));
         }
         _ => {
             if command != "help" {
                 println!("unknown command \"{}\"", command.to_str().unwrap_or("[bad utf8]"));
             }
-            println!("usage: {} <backup | automanage> [options]", program_name.display());
-            process::exit(-1);
+            println!("usage: {} [--lock-scope <global | pool | none>] [--wait-for-lock] [-v] \
+                      [--table-separator <separator | box>] \
+                      <backup | backup-snapshot | status | ls | snapshot | destroy | hold | \
+                      release | restore | rehash | verify | prune-files | clean | browse | diff | \
+                      adopt | benchmark | automanage | run | version> \
+                      [options]",
+                     program_name.display());
+            process::exit(EXIT_USAGE);
         }
     }
 
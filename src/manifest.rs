@@ -0,0 +1,93 @@
+// Manifest :: Metadata describing a backup file, stored alongside it.
+//
+// Copyright (c) 2021 by William R. Fraser
+//
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// The metadata recorded for a backup, as ordered `key=value` lines in a `.manifest` file next to
+/// it. Keys are plain identifiers; values run to the end of the line.
+#[derive(Debug, Default, Clone)]
+pub struct Manifest {
+    entries: Vec<(String, String)>,
+}
+
+/// The path of the manifest for the given backup file.
+pub fn manifest_path(backup_path: &Path) -> PathBuf {
+    let mut filename = backup_path.file_name().unwrap_or_default().to_os_string();
+    filename.push(".manifest");
+    backup_path.with_file_name(filename)
+}
+
+impl Manifest {
+    pub fn new() -> Manifest {
+        Manifest::default()
+    }
+
+    /// Set a value, replacing any existing value for the key.
+    pub fn set(&mut self, key: &str, value: impl Into<String>) {
+        let value = value.into().replace('\n', " ");
+        match self.entries.iter_mut().find(|(k, _)| k == key) {
+            Some(entry) => entry.1 = value,
+            None => self.entries.push((key.to_owned(), value)),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn parse(text: &str) -> Manifest {
+        let mut manifest = Manifest::new();
+        for line in text.lines() {
+            if let Some((key, value)) = line.split_once('=') {
+                manifest.set(key.trim(), value);
+            }
+        }
+        manifest
+    }
+
+    pub fn read(path: &Path) -> io::Result<Manifest> {
+        fs::read_to_string(path).map(|text| Manifest::parse(&text))
+    }
+
+    /// Write the manifest to a temporary file and rename it into place.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let mut tmp_filename = path.file_name().unwrap_or_default().to_os_string();
+        tmp_filename.push(".tmp");
+        let tmp_path = path.with_file_name(tmp_filename);
+
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(self.to_string().as_bytes())?;
+        drop(file);
+        fs::rename(&tmp_path, path)
+    }
+}
+
+impl std::fmt::Display for Manifest {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (key, value) in &self.entries {
+            writeln!(f, "{}={}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_manifest_roundtrip() {
+    let mut manifest = Manifest::new();
+    manifest.set("snapshot", "tank/data@2021-01-02");
+    manifest.set("incremental_from", "2021-01-01");
+    manifest.set("snapshot", "tank/data@2021-01-03");
+    let text = manifest.to_string();
+    assert_eq!(text, "snapshot=tank/data@2021-01-03\nincremental_from=2021-01-01\n");
+
+    let parsed = Manifest::parse(&text);
+    assert_eq!(parsed.get("snapshot"), Some("tank/data@2021-01-03"));
+    assert_eq!(parsed.get("incremental_from"), Some("2021-01-01"));
+    assert_eq!(parsed.get("sha256"), None);
+}
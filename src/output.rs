@@ -0,0 +1,97 @@
+// Output :: Things zsnapmgr has to say while it works.
+//
+// Copyright (c) 2021 by William R. Fraser
+//
+
+use std::path::Path;
+
+use crate::status::Progress;
+
+/// Something that happened while zsnapmgr was working, which a user might want to see. The
+/// library never prints these itself; they are passed to the callback given to
+/// `ZSnapMgr::set_output`, which can render them however it likes.
+#[derive(Debug)]
+pub enum Event<'a> {
+    /// An external command (or pipeline) is about to be run.
+    Running(&'a str),
+
+    /// This is a dry run, so the command just reported wasn't actually run, but would have
+    /// written to the given path.
+    DryRun(&'a Path),
+
+    /// `zfs send` has said how big the stream will be. A size of zero means the snapshot is empty
+    /// and nothing will be sent.
+    SendSize(u64),
+
+    /// A `zfs send` has made progress. The compression percentage is smoothed over time, and is
+    /// negative if the output is bigger than the input.
+    SendProgress {
+        progress: &'a Progress<'a>,
+        compression: Option<f64>,
+    },
+
+    /// The `zfs send` pipeline has finished, successfully or not.
+    SendFinished,
+
+    /// A backup file is being fed into 'zfs receive', and this much of it has been read so far.
+    ReceiveProgress {
+        source: &'a Path,
+        total_size: u64,
+        read: u64,
+        elapsed_seconds: u64,
+    },
+
+    /// The 'zfs receive' pipeline has finished, successfully or not.
+    ReceiveFinished,
+
+    /// A file just written is being read back to check it (see `SendOptions::verify_after`).
+    Verifying(&'a Path),
+
+    /// What `snapshot_automanage` decided to do with one snapshot.
+    Retention(&'a RetentionDecision<'a>),
+
+    /// A snapshot is about to be destroyed.
+    Destroying(&'a str),
+
+    /// A snapshot is about to be created.
+    Creating(&'a str),
+
+    /// A libzfs call (or 'zfs' command) is about to be made. There are a lot of these, and they're
+    /// only interesting for seeing exactly what is being done to the pools.
+    ZfsCall(&'a str),
+
+    /// Something went wrong, but not badly enough to stop.
+    Warning(&'a str),
+}
+
+/// One line of `snapshot_automanage`'s plan.
+#[derive(Debug)]
+pub struct RetentionDecision<'a> {
+    pub volume: &'a str,
+    /// The snapshot name, without the volume.
+    pub name: &'a str,
+    pub days_old: i64,
+    /// Position of the snapshot counting back from the newest, starting at 1.
+    pub number: usize,
+    pub action: RetentionAction,
+}
+
+#[derive(Debug, Clone)]
+pub enum RetentionAction {
+    /// The snapshot will be created.
+    New,
+    Keep,
+    /// The snapshot will be destroyed, for the given reason.
+    Delete(String),
+    /// The snapshot would have been destroyed for the given reason, but it has a hold on it.
+    Held(String),
+    /// The snapshot would have been destroyed for the given reason, but it's younger than
+    /// `AutomanageOptions::min_age_days`.
+    TooYoung(String),
+    /// The snapshot would have been destroyed, but `ZSnapMgr::destroy_guards` protects it. This
+    /// gives both reasons.
+    Protected(String),
+}
+
+/// The type of callback which receives `Event`s.
+pub type Output = dyn Fn(&Event) + Send + Sync;
@@ -0,0 +1,220 @@
+// RunConfig :: What 'zsnapmgr run' does, and when it last did it.
+//
+// Copyright (c) 2021 by William R. Fraser
+//
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::prelude::*;
+use zsnapmgr::{parse_schedule, AutomanageOptions, Manifest, MountFilter};
+
+/// The config for 'zsnapmgr run', read from a file of `key=value` lines (blank lines and lines
+/// starting with `#` are ignored):
+///
+/// ```text
+/// # Manage snapshots (see 'zsnapmgr automanage') once a day...
+/// snapshot_every=1d
+/// prefix=
+/// schedule=14d:1d,26w:1w,forever:1m
+/// min_age=7
+/// skip_unchanged=yes
+/// mount_filter=skip-canmount-off
+/// post_snapshot_cmd=/usr/local/bin/after-snapshot
+///
+/// # ...and back up every volume already backed up in backup_dir once a week.
+/// backup_every=1w
+/// backup_dir=/mnt/backups
+/// passphrase_file=/root/backup-passphrase
+/// only_incremental=yes
+///
+/// # Where the time of each last run is kept. Defaults to the config file's path plus ".state".
+/// state_file=/var/lib/zsnapmgr/run.state
+/// ```
+#[derive(Debug)]
+pub struct RunConfig {
+    /// How many days apart to manage snapshots, if at all.
+    pub snapshot_every: Option<u32>,
+    pub automanage: AutomanageOptions,
+
+    /// How many days apart to run backups, if at all.
+    pub backup_every: Option<u32>,
+    pub backup_dir: Option<PathBuf>,
+    pub passphrase_file: Option<PathBuf>,
+    pub only_incremental: bool,
+
+    pub state_file: PathBuf,
+}
+
+// Parse how often to do something: a number of days or weeks, like "1d" or "2w".
+fn parse_period(s: &str) -> Option<u32> {
+    let (number, multiplier) = match s.chars().last()? {
+        'd' => (&s[.. s.len() - 1], 1),
+        'w' => (&s[.. s.len() - 1], 7),
+        _ => return None,
+    };
+    number.parse::<u32>().ok().filter(|&n| n > 0)?.checked_mul(multiplier)
+}
+
+fn parse_bool(s: &str) -> Option<bool> {
+    match s {
+        "yes" | "true" => Some(true),
+        "no" | "false" => Some(false),
+        _ => None,
+    }
+}
+
+impl RunConfig {
+    pub fn read(path: &Path) -> Result<RunConfig, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {:?}: {}", path, e))?;
+        RunConfig::parse(&text, path).map_err(|e| format!("{:?}: {}", path, e))
+    }
+
+    pub fn parse(text: &str, path: &Path) -> Result<RunConfig, String> {
+        let mut state_file = path.as_os_str().to_owned();
+        state_file.push(".state");
+        let mut config = RunConfig {
+            snapshot_every: None,
+            automanage: AutomanageOptions::default(),
+            backup_every: None,
+            backup_dir: None,
+            passphrase_file: None,
+            only_incremental: false,
+            state_file: PathBuf::from(state_file),
+        };
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let bad = |what: &str| format!("line {}: {}", i + 1, what);
+            let (key, value) = line.split_once('=').ok_or_else(|| bad("expected key=value"))?;
+            let (key, value) = (key.trim(), value.trim());
+            let invalid = || bad(&format!("invalid {} {:?}", key, value));
+            match key {
+                "snapshot_every" => {
+                    config.snapshot_every = Some(parse_period(value).ok_or_else(invalid)?);
+                }
+                "prefix" => config.automanage.prefix = value.to_owned(),
+                "schedule" => {
+                    let schedule = parse_schedule(value).map_err(|e| bad(&e))?;
+                    config.automanage.schedule = Some(schedule);
+                }
+                "min_age" => {
+                    config.automanage.min_age_days = value.parse::<i64>().ok()
+                        .filter(|&n| n >= 0)
+                        .ok_or_else(invalid)?;
+                }
+                "skip_unchanged" => {
+                    config.automanage.skip_unchanged = parse_bool(value).ok_or_else(invalid)?;
+                }
+                "mount_filter" => {
+                    config.automanage.mount_filter = match value {
+                        "all" => MountFilter::All,
+                        "skip-canmount-off" => MountFilter::SkipCanmountOff,
+                        "mounted-only" => MountFilter::MountedOnly,
+                        _ => return Err(invalid()),
+                    };
+                }
+                "post_snapshot_cmd" => {
+                    config.automanage.post_snapshot_cmd = Some(value.to_owned());
+                }
+                "backup_every" => {
+                    config.backup_every = Some(parse_period(value).ok_or_else(invalid)?);
+                }
+                "backup_dir" => config.backup_dir = Some(PathBuf::from(value)),
+                "passphrase_file" => config.passphrase_file = Some(PathBuf::from(value)),
+                "only_incremental" => {
+                    config.only_incremental = parse_bool(value).ok_or_else(invalid)?;
+                }
+                "state_file" => config.state_file = PathBuf::from(value),
+                _ => return Err(bad(&format!("unknown setting {:?}", key))),
+            }
+        }
+
+        if config.snapshot_every.is_none() && config.backup_every.is_none() {
+            return Err("nothing to do: set snapshot_every and/or backup_every".to_owned());
+        }
+        // Backups run unattended, so there's nobody to ask for a passphrase.
+        if config.backup_every.is_some()
+            && (config.backup_dir.is_none() || config.passphrase_file.is_none())
+        {
+            return Err("backup_every needs backup_dir and passphrase_file".to_owned());
+        }
+        Ok(config)
+    }
+}
+
+#[test]
+fn test_run_config() {
+    let path = Path::new("/etc/zsnapmgr.conf");
+    let config = RunConfig::parse(
+        "# comment\n\nsnapshot_every=1d\nschedule=14d:1d,forever:1m\nbackup_every=2w\n\
+         backup_dir=/mnt/backups\npassphrase_file=/root/pass\npost_snapshot_cmd=echo a=b\n",
+        path).unwrap();
+    assert_eq!(config.snapshot_every, Some(1));
+    assert_eq!(config.backup_every, Some(14));
+    assert_eq!(config.automanage.schedule.map(|rules| rules.len()), Some(2));
+    assert_eq!(config.automanage.post_snapshot_cmd.as_deref(), Some("echo a=b"));
+    assert_eq!(config.state_file, Path::new("/etc/zsnapmgr.conf.state"));
+
+    assert!(RunConfig::parse("", path).is_err());
+    assert!(RunConfig::parse("snapshot_every=1h\n", path).is_err());
+    assert!(RunConfig::parse("snapshot_every=1d\nbogus=1\n", path).is_err());
+    assert!(RunConfig::parse("backup_every=1d\nbackup_dir=/mnt\n", path).is_err());
+}
+
+/// When each of the things 'zsnapmgr run' does was last done, kept as `key=value` lines of
+/// timestamps.
+pub struct RunState {
+    path: PathBuf,
+    times: Manifest,
+}
+
+impl RunState {
+    /// Read the state. If there is none yet, nothing has been done.
+    pub fn load(path: &Path) -> io::Result<RunState> {
+        let times = match Manifest::read(path) {
+            Ok(times) => times,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Manifest::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(RunState { path: path.to_owned(), times })
+    }
+
+    /// The day something was last done.
+    pub fn last(&self, task: &str) -> Option<NaiveDate> {
+        self.times.get(task)
+            .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+            .map(|time| time.with_timezone(&Local).date_naive())
+    }
+
+    /// Note that something was just done.
+    pub fn record(&mut self, task: &str) -> io::Result<()> {
+        self.times.set(task, Local::now().to_rfc3339());
+        self.times.write(&self.path)
+    }
+}
+
+/// Whether something done every `every_days` days, and last done on `last`, should be done again
+/// today. This goes by days rather than exact times, so that a daily cron job starting a few
+/// seconds earlier than yesterday's still counts as a day later.
+pub fn is_due(last: Option<NaiveDate>, every_days: u32, today: NaiveDate) -> bool {
+    match last {
+        Some(last) => today.signed_duration_since(last).num_days() >= i64::from(every_days),
+        None => true,
+    }
+}
+
+#[test]
+fn test_is_due() {
+    let date = |s: &str| NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap();
+    assert!(is_due(None, 1, date("2021-01-01")));
+    assert!(!is_due(Some(date("2021-01-01")), 1, date("2021-01-01")));
+    assert!(is_due(Some(date("2021-01-01")), 1, date("2021-01-02")));
+    assert!(!is_due(Some(date("2021-01-01")), 7, date("2021-01-07")));
+    assert!(is_due(Some(date("2021-01-01")), 7, date("2021-01-08")));
+}
@@ -0,0 +1,279 @@
+// Status :: Machine-readable progress reporting for in-flight backups.
+//
+// Copyright (c) 2021 by William R. Fraser
+//
+
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::prelude::*;
+
+/// A snapshot of the progress of a single `zfs send` pipeline.
+#[derive(Debug)]
+pub struct Progress<'a> {
+    pub snapshot: &'a str,
+    pub destination: &'a Path,
+    pub total_size: u64,
+    pub sent: u64,
+    pub written: u64,
+    pub elapsed_seconds: u64,
+}
+
+/// A JSON status file which is atomically replaced on each update, and removed when dropped. If
+/// it can't be removed, it's left behind, and `status` shows it as from an interrupted backup.
+pub struct StatusFile {
+    path: PathBuf,
+}
+
+/// Quote and escape a string for use in JSON.
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl StatusFile {
+    pub fn new(path: &Path) -> StatusFile {
+        StatusFile { path: path.to_owned() }
+    }
+
+    pub fn update(&self, progress: &Progress) -> io::Result<()> {
+        let json = format!(
+            "{{\"pid\": {}, \"snapshot\": {}, \"destination\": {}, \"total_size\": {}, \
+             \"sent\": {}, \"written\": {}, \"elapsed_seconds\": {}, \"updated\": {}}}\n",
+            std::process::id(),
+            json_string(progress.snapshot),
+            json_string(&progress.destination.to_string_lossy()),
+            progress.total_size,
+            progress.sent,
+            progress.written,
+            progress.elapsed_seconds,
+            json_string(&Local::now().to_rfc3339()));
+
+        // Write to a temporary file alongside and rename it into place, so readers never see a
+        // partially-written file.
+        let mut tmp_filename = self.path.file_name().unwrap_or_default().to_os_string();
+        tmp_filename.push(".tmp");
+        let tmp_path = self.path.with_file_name(tmp_filename);
+
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(json.as_bytes())?;
+        drop(file);
+        fs::rename(&tmp_path, &self.path)
+    }
+}
+
+impl Drop for StatusFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[test]
+fn test_json_string() {
+    assert_eq!(json_string("tank/data@2021-01-01"), "\"tank/data@2021-01-01\"");
+    assert_eq!(json_string("a\"b\\c\n"), "\"a\\\"b\\\\c\\n\"");
+    assert_eq!(json_string("\x01"), "\"\\u0001\"");
+}
+
+/// The contents of a status file written by an in-progress (or interrupted) backup.
+#[derive(Debug)]
+pub struct StatusInfo {
+    pub pid: u32,
+    pub running: bool,
+    pub snapshot: String,
+    pub destination: PathBuf,
+    pub total_size: u64,
+    pub sent: u64,
+    pub written: u64,
+}
+
+/// Something left in a backup directory by a backup which is either still going or was
+/// interrupted: a `_partial` file, or a status file.
+#[derive(Debug)]
+pub struct BackupState {
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified: Option<DateTime<Local>>,
+    /// Set if this is a status file rather than a partial file.
+    pub status: Option<StatusInfo>,
+}
+
+// Finds the raw value of a top-level key in the flat JSON objects written by `StatusFile`.
+// This is not a general JSON parser; it only needs to understand what we write.
+fn json_field<'a>(json: &'a str, key: &str) -> Option<&'a str> {
+    let needle = format!("{}: ", json_string(key));
+    let start = json.find(&needle)? + needle.len();
+    let rest = &json[start..];
+    if rest.starts_with('"') {
+        let mut escaped = false;
+        for (i, c) in rest.char_indices().skip(1) {
+            match c {
+                '\\' if !escaped => escaped = true,
+                '"' if !escaped => return Some(&rest[..= i]),
+                _ => escaped = false,
+            }
+        }
+        None
+    } else {
+        let end = rest.find([',', '}']).unwrap_or(rest.len());
+        Some(rest[..end].trim())
+    }
+}
+
+fn json_unescape(quoted: &str) -> Option<String> {
+    let inner = quoted.strip_prefix('"')?.strip_suffix('"')?;
+    let mut out = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next()? {
+            'n' => out.push('\n'),
+            'r' => out.push('\r'),
+            't' => out.push('\t'),
+            'u' => {
+                let hex: String = chars.by_ref().take(4).collect();
+                out.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+            }
+            other => out.push(other),
+        }
+    }
+    Some(out)
+}
+
+fn process_is_running(pid: u32) -> bool {
+    // Signal 0 does no harm; it just checks whether the process exists and could be signalled.
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    result == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Parse a status file written by `StatusFile`, returning None if it isn't one.
+pub fn read_status_file(path: &Path) -> io::Result<Option<StatusInfo>> {
+    let json = fs::read_to_string(path)?;
+    let parse = || -> Option<StatusInfo> {
+        let pid = json_field(&json, "pid")?.parse().ok()?;
+        Some(StatusInfo {
+            pid,
+            running: process_is_running(pid),
+            snapshot: json_unescape(json_field(&json, "snapshot")?)?,
+            destination: PathBuf::from(json_unescape(json_field(&json, "destination")?)?),
+            total_size: json_field(&json, "total_size")?.parse().ok()?,
+            sent: json_field(&json, "sent")?.parse().ok()?,
+            written: json_field(&json, "written")?.parse().ok()?,
+        })
+    };
+    Ok(parse())
+}
+
+#[test]
+fn test_json_field() {
+    let json = "{\"pid\": 42, \"snapshot\": \"tank/a\\\"b@x\", \"sent\": 10}\n";
+    assert_eq!(json_field(json, "pid"), Some("42"));
+    assert_eq!(json_field(json, "sent"), Some("10"));
+    assert_eq!(json_unescape(json_field(json, "snapshot").unwrap()).unwrap(), "tank/a\"b@x");
+    assert_eq!(json_field(json, "missing"), None);
+}
+
+/// The name of the file in a backup directory where `BatchProgress` is kept.
+pub const BATCH_PROGRESS_FILE: &str = "zsnapmgr-batch.jsonl";
+
+/// A record of which snapshots a batch of backups has finished, so that if the batch is
+/// interrupted, running it again can skip them. It's kept as one JSON object per line, each
+/// appended when a backup finishes, so an interruption can at worst leave a partial last line,
+/// which is ignored.
+pub struct BatchProgress {
+    path: PathBuf,
+}
+
+impl BatchProgress {
+    pub fn new(path: &Path) -> Self {
+        Self { path: path.to_owned() }
+    }
+
+    /// The snapshots recorded as done. If there is no record, this is empty.
+    pub fn done(&self) -> io::Result<HashSet<String>> {
+        let text = match fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashSet::new()),
+            Err(e) => return Err(e),
+        };
+        Ok(text.lines()
+            .filter_map(|line| json_unescape(json_field(line, "snapshot")?))
+            .collect())
+    }
+
+    pub fn record(&self, snapshot: &str) -> io::Result<()> {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&self.path)?;
+        let mut line = String::new();
+        // If an earlier run was interrupted partway through a line, start on a new one rather
+        // than spoil this record too.
+        if file.seek(SeekFrom::End(0))? > 0 {
+            file.seek(SeekFrom::End(-1))?;
+            let mut last = [0];
+            file.read_exact(&mut last)?;
+            if last[0] != b'\n' {
+                line.push('\n');
+            }
+        }
+        line += &format!("{{\"snapshot\": {}, \"finished\": {}}}\n",
+                         json_string(snapshot),
+                         json_string(&Local::now().to_rfc3339()));
+        file.write_all(line.as_bytes())
+    }
+
+    /// Forget everything recorded, when the batch is finished or is to be started over.
+    pub fn clear(&self) -> io::Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+}
+
+#[test]
+fn test_batch_progress() {
+    let dir = crate::TestDir::new("batch");
+    let path = dir.join("progress.jsonl");
+    let progress = BatchProgress::new(&path);
+    assert!(progress.done().unwrap().is_empty());
+
+    progress.record("tank/a@2021-01-02").unwrap();
+    progress.record("tank/\"b\"@2021-01-02").unwrap();
+    // As if interrupted partway through writing a line.
+    fs::OpenOptions::new().append(true).open(&path).unwrap()
+        .write_all(b"{\"snapshot\": \"tank/c@20").unwrap();
+
+    let done = progress.done().unwrap();
+    assert_eq!(done.len(), 2);
+    assert!(done.contains("tank/a@2021-01-02"));
+    assert!(done.contains("tank/\"b\"@2021-01-02"));
+
+    progress.record("tank/d@2021-01-02").unwrap();
+    let done = progress.done().unwrap();
+    assert_eq!(done.len(), 3);
+    assert!(done.contains("tank/d@2021-01-02"));
+
+    progress.clear().unwrap();
+    assert!(!path.exists());
+}
@@ -5,11 +5,23 @@
 
 use std::fmt;
 
+#[derive(Debug)]
+enum Row {
+    Cells(Vec<String>),
+    /// A heading for the rows after it, drawn across the whole table.
+    Section(String),
+}
+
 #[derive(Debug)]
 pub struct Table {
     headers: Vec<String>,
     pad_left: Vec<bool>,
-    items: Vec<Vec<String>>,
+    items: Vec<Row>,
+    separator: String,
+    // The line under the headers: the character it's made of, and what goes where it crosses a
+    // separator.
+    rule: char,
+    rule_separator: String,
 }
 
 impl Table {
@@ -18,6 +30,9 @@ impl Table {
             headers: Vec::new(),
             pad_left: Vec::new(),
             items: Vec::new(),
+            separator: " | ".to_owned(),
+            rule: '-',
+            rule_separator: "---".to_owned(),
         };
 
         for heading in headers {
@@ -33,19 +48,58 @@ impl Table {
         table
     }
 
+    /// Put something other than " | " between columns.
+    pub fn with_separator(mut self, separator: &str) -> Table {
+        self.rule_separator = self.rule.to_string().repeat(width(separator));
+        self.separator = separator.to_owned();
+        self
+    }
+
+    /// Draw the column separators and header line with Unicode box-drawing characters.
+    pub fn box_drawing(mut self) -> Table {
+        self.separator = " \u{2502} ".to_owned();
+        self.rule = '\u{2500}';
+        self.rule_separator = "\u{2500}\u{253c}\u{2500}".to_owned();
+        self
+    }
+
     pub fn push(&mut self, row: Vec<String>) {
         if row.len() != self.headers.len() {
             panic!("not enough values");
         }
 
-        self.items.push(row);
+        self.items.push(Row::Cells(row));
+    }
+
+    /// Start a group of rows, with a labeled divider above them.
+    pub fn push_section(&mut self, label: &str) {
+        self.items.push(Row::Section(label.to_owned()));
+    }
+
+    /// Render the rows (without the header) as tab-separated lines, for consumption by scripts.
+    /// Unlike the `Display` form, this doesn't depend on the contents of other rows. Sections are
+    /// left out.
+    pub fn to_tsv(&self) -> String {
+        let mut out = String::new();
+        for row in &self.items {
+            if let Row::Cells(row) = row {
+                out += &row.join("\t");
+                out.push('\n');
+            }
+        }
+        out
     }
 }
 
+// Width in characters, which is what the formatter pads to.
+fn width(s: &str) -> usize {
+    s.chars().count()
+}
+
 fn measure(measures: &mut Vec<usize>, row: &[String]) {
     for i in 0..measures.len() {
-        if row[i].len() > measures[i] {
-            measures[i] = row[i].len();
+        if width(&row[i]) > measures[i] {
+            measures[i] = width(&row[i]);
         }
     }
 }
@@ -53,7 +107,8 @@ fn measure(measures: &mut Vec<usize>, row: &[String]) {
 fn write_measured(f: &mut fmt::Formatter,
                   row: &[String],
                   measures: &[usize],
-                  pad_left: &[bool])
+                  pad_left: &[bool],
+                  separator: &str)
                   -> fmt::Result {
     for i in 0..measures.len() {
         if pad_left[i] {
@@ -62,7 +117,7 @@ fn write_measured(f: &mut fmt::Formatter,
             write!(f, "{:<1$}", row[i], measures[i])?;
         }
         if i != measures.len() - 1 {
-            write!(f, " | ")?;
+            write!(f, "{}", separator)?;
         }
     }
     Ok(())
@@ -74,26 +129,76 @@ impl fmt::Display for Table {
 
         measure(&mut measures, &self.headers);
         for row in &self.items {
-            measure(&mut measures, row);
+            if let Row::Cells(row) = row {
+                measure(&mut measures, row);
+            }
         }
 
-        write_measured(f, &self.headers, &measures, &self.pad_left)?;
+        write_measured(f, &self.headers, &measures, &self.pad_left, &self.separator)?;
         writeln!(f)?;
 
-        let mut total_measure = 0_usize;
-        for (i, measure) in measures.iter().enumerate() {
-            total_measure += *measure;
-            if i != measures.len() - 1 {
-                total_measure += 3;
-            }
-        }
-        writeln!(f, "{:-<1$}", "-", total_measure)?;
+        let rule: Vec<String> = measures.iter()
+            .map(|measure| self.rule.to_string().repeat(*measure))
+            .collect();
+        let rule = rule.join(&self.rule_separator);
+        writeln!(f, "{}", rule)?;
 
         for row in &self.items {
-            write_measured(f, row, &measures, &self.pad_left)?;
-            writeln!(f)?;
+            match row {
+                Row::Cells(row) => {
+                    write_measured(f, row, &measures, &self.pad_left, &self.separator)?;
+                    writeln!(f)?;
+                }
+                Row::Section(label) => {
+                    // Fill out the rest of the line with the rule, if it's long enough.
+                    let fill: String = rule.chars().skip(width(label) + 1).collect();
+                    if fill.is_empty() {
+                        writeln!(f, "{}", label)?;
+                    } else {
+                        writeln!(f, "{} {}", label, fill)?;
+                    }
+                }
+            }
         }
 
         writeln!(f)
     }
 }
+
+#[test]
+fn test_to_tsv() {
+    let mut table = Table::new(&["volume", "_count"]);
+    table.push(vec!["tank/data".to_owned(), "12".to_owned()]);
+    table.push(vec!["tank".to_owned(), "3".to_owned()]);
+    assert_eq!(table.to_tsv(), "tank/data\t12\ntank\t3\n");
+}
+
+#[test]
+fn test_separator() {
+    let table = |mut table: Table| {
+        table.push(vec!["tank".to_owned(), "3".to_owned()]);
+        table.to_string()
+    };
+    let headers = &["volume", "_count"];
+    assert_eq!(table(Table::new(headers)), "volume | count\n--------------\ntank   |     3\n\n");
+    assert_eq!(table(Table::new(headers).with_separator("  ")),
+               "volume  count\n-------------\ntank        3\n\n");
+    assert_eq!(table(Table::new(headers).box_drawing()),
+               "volume │ count\n───────┼──────\ntank   │     3\n\n");
+}
+
+#[test]
+fn test_sections() {
+    let mut table = Table::new(&["snapshot", "_days"]);
+    table.push_section("tank");
+    table.push(vec!["2021-01-02".to_owned(), "3".to_owned()]);
+    table.push_section("tank/some/long/volume");
+    table.push(vec!["2021-01-01".to_owned(), "4".to_owned()]);
+    assert_eq!(table.to_string(), "snapshot   | days\n\
+                                   -----------------\n\
+                                   tank ------------\n\
+                                   2021-01-02 |    3\n\
+                                   tank/some/long/volume\n\
+                                   2021-01-01 |    4\n\n");
+    assert_eq!(table.to_tsv(), "2021-01-02\t3\n2021-01-01\t4\n");
+}
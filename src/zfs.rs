@@ -3,30 +3,246 @@
 // Copyright (c) 2016-2021 by William R. Fraser
 //
 
-use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::ffi::OsStr;
+use std::fmt;
 use std::fs;
+use std::iter;
 use std::process::{Child, Command, Stdio};
-use std::io::{stdout, Error, Read, Write};
+use std::io::{Error, Read};
 use std::os::unix::ffi::OsStrExt;
-use std::path::Path;
-use std::sync::Arc;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::hash_stream;
+use crate::manifest::{self, Manifest};
+use crate::output::{Event, Output};
+use crate::status::{Progress, StatusFile};
 use crate::zfs_error::ZfsError;
 
 use chrono::prelude::*;
-use ring::digest::SHA256;
+use ring::digest::{Algorithm, SHA256, SHA512};
 
 use libzfs::{DatasetType, DatasetTypeMask, LibZfs};
 
+/// Volume names, each with whether it's a filesystem or a zvol.
+pub type TypedVolumes = Vec<(String, DatasetType)>;
+
 pub struct Zfs {
     client: LibZfs,
     pub use_sudo: bool,
+
+    /// The programs run for 'zfs', 'zpool', and 'sudo'. These default to the `ZSNAPMGR_ZFS_BIN`,
+    /// `ZSNAPMGR_ZPOOL_BIN`, and `ZSNAPMGR_SUDO_BIN` environment variables, or failing that, to
+    /// just looking them up in PATH.
+    pub zfs_program: String,
+    pub zpool_program: String,
+    pub sudo_program: String,
+
+    // Listing volumes and snapshots means walking every dataset in the pool(s), which is slow when
+    // there are a lot of them, so the results are kept (keyed by the pool or dataset asked about)
+    // until something is created or destroyed.
+    volumes_cache: Mutex<HashMap<Option<String>, TypedVolumes>>,
+    snapshots_cache: Mutex<HashMap<Option<String>, Vec<String>>>,
+
+    // Where `Event::ZfsCall`s go.
+    output: Arc<Output>,
+}
+
+/// Options controlling how `Zfs::send` runs and writes its output.
+#[derive(Debug, Clone)]
+pub struct SendOptions {
+    /// Periodically write progress as JSON to this path; it is removed when the send finishes.
+    pub status_file: Option<PathBuf>,
+
+    /// Size in bytes of the buffer used to copy the stream into the destination file. Larger
+    /// buffers mean fewer read and write syscalls, which matters on fast destinations.
+    pub buffer_size: usize,
+
+    /// Also hash the raw `zfs send` stream, before it goes through the filter program, and record
+    /// it in the manifest. This lets corruption of the source stream be told apart from corruption
+    /// introduced by compression or encryption.
+    pub hash_source_stream: bool,
+
+    /// Split the output into numbered chunk files of at most this many bytes each, named like
+    /// `<destination>.000`, each with its own hash sidecar. The chunks just need to be
+    /// concatenated in order to get the original stream back.
+    pub split_size: Option<u64>,
+
+    /// Include the dataset's properties in the stream ('zfs send -p'), so that receiving it
+    /// restores settings like compression and recordsize too. (A replication stream made with
+    /// 'zfs send -R' always includes properties, but zsnapmgr doesn't make those.)
+    pub send_properties: bool,
+
+    /// Send blocks which are compressed on disk as they are, rather than decompressing them
+    /// ('zfs send --compressed'). This saves CPU, and if the dataset is well compressed, makes
+    /// compressing the stream again mostly pointless. The stream then contains compressed records,
+    /// so the pool it's received into must support the same compression algorithm (the
+    /// `lz4_compress` feature, for example). The manifest records this as `zfs_compressed=true`.
+    pub use_zfs_compression: bool,
+
+    /// Send blocks bigger than 128K as they are ('zfs send --large-block'), rather than splitting
+    /// them up. Without this, a dataset with a recordsize over 128K can't be received with its
+    /// blocks intact. The pool it's received into must have the `large_blocks` feature enabled.
+    /// The manifest records this as `large_blocks=true`.
+    pub large_blocks: bool,
+
+    /// Send blocks whose data is embedded in their block pointers as they are ('zfs send
+    /// --embed'), which makes for smaller streams of highly compressible data. The pool it's
+    /// received into must have the `embedded_data` feature enabled. The manifest records this as
+    /// `embedded_data=true`.
+    pub embedded_data: bool,
+
+    /// For incremental sends, include all the snapshots between the start and end ('zfs send -I')
+    /// rather than just the difference between the two ('-i'). Receiving the stream then
+    /// recreates every intermediate snapshot as well. The manifest records this as
+    /// `intermediates=true`.
+    pub all_intermediates: bool,
+
+    /// If the send fails, leave its `_partial` output file(s) and hash sidecar behind for
+    /// inspection, instead of removing them.
+    pub keep_partial_on_error: bool,
+
+    /// Only report the pipeline that would be run and where it would write, without running it.
+    pub dry_run: bool,
+
+    /// Also hash the output with SHA-512, in the same pass, writing a `.sha512sum` sidecar next
+    /// to the `.sha256sum` one and recording it in the manifest as `sha512`. Chunks of a split
+    /// backup still only get SHA-256 sidecars.
+    pub sha512: bool,
+
+    /// Don't hash the output at all: no `.sha256sum` sidecar is written, and the manifest has no
+    /// `sha256`. This saves some CPU for backups to trusted local storage, but there's then
+    /// nothing to check the file against later (short of `rehash`). Can't be used with
+    /// `split_size`, whose chunks are always hashed.
+    pub no_hash: bool,
+
+    /// Also write the backup to each of these paths, from the same 'zfs send' (and filter
+    /// program), so it only has to run once. Each copy gets its own sidecars and manifest. Can't
+    /// be used with `split_size`.
+    pub extra_destinations: Vec<PathBuf>,
+
+    /// When writing to more than one destination, if one of them fails, carry on with the rest
+    /// (with a warning) rather than failing the whole send.
+    pub keep_going_on_destination_error: bool,
+
+    /// Once the output is written, read it back and check it against the hashes made as it was
+    /// written, before putting it in place. This is done with the file dropped from the page cache
+    /// (as far as the OS allows), so it catches data that didn't make it to the disk intact. It
+    /// takes as long again as writing did. Can't be used with `no_hash`.
+    pub verify_after: bool,
+
+    /// Write the hash sidecars and manifest with this instead of as plain files, so that they can
+    /// be encrypted, for example. Each is given the path it would have been written to and its
+    /// contents. (The hashes of a send in progress are still written to plain `_partial`
+    /// sidecars, which are removed when it's done.)
+    pub metadata_writer: Option<MetadataWriter>,
+
+    /// Extra entries to record in the manifest, describing things about the filter program's
+    /// output that can't be worked out from the send itself (such as how it was encrypted).
+    pub manifest_entries: Vec<(String, String)>,
+
+    /// How often to refresh the progress display (and status file) when 'zfs send' hasn't
+    /// reported anything new.
+    pub progress_interval: Duration,
+}
+
+/// The type of function which writes a backup's sidecars and manifest; see
+/// `SendOptions::metadata_writer`.
+pub type WriteMetadata = dyn Fn(&Path, &[u8]) -> Result<(), Error> + Send + Sync;
+
+#[derive(Clone)]
+pub struct MetadataWriter(pub Arc<WriteMetadata>);
+
+impl fmt::Debug for MetadataWriter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("MetadataWriter")
+    }
+}
+
+impl Default for SendOptions {
+    fn default() -> Self {
+        Self {
+            status_file: None,
+            buffer_size: hash_stream::DEFAULT_BUFFER_SIZE,
+            hash_source_stream: false,
+            split_size: None,
+            send_properties: false,
+            use_zfs_compression: false,
+            large_blocks: false,
+            embedded_data: false,
+            all_intermediates: false,
+            dry_run: false,
+            sha512: false,
+            no_hash: false,
+            extra_destinations: vec![],
+            keep_going_on_destination_error: false,
+            verify_after: false,
+            metadata_writer: None,
+            manifest_entries: vec![],
+            keep_partial_on_error: false,
+            progress_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+// How many lines of 'zfs send' output, besides its progress reports, to keep for error messages.
+const STDERR_TAIL_LINES: usize = 5;
+
+// Parse a progress line from 'zfs send --parsable --verbose', like "12:34:56\t1234\ttank@snap",
+// giving the number of bytes sent so far.
+fn parse_send_progress(line: &str) -> Option<u64> {
+    let parts: Vec<&str> = line.split('\t').collect();
+    if parts.len() != 3 {
+        return None;
+    }
+    parts[1].parse::<u64>().ok()
+}
+
+// An error for 'zfs send' exiting unsuccessfully, with the last things it said, which usually
+// tell why (e.g. "permission denied").
+fn send_failed(code: i32, stderr_tail: &VecDeque<String>) -> ZfsError {
+    let msg = format!("'zfs send' returned nonzero exit code: {}", code);
+    if stderr_tail.is_empty() {
+        return ZfsError::from(msg);
+    }
+    let lines: Vec<&str> = stderr_tail.iter().map(String::as_str).collect();
+    ZfsError::from((msg.as_str(), &lines.join("\n").into_bytes()))
+}
+
+#[test]
+fn test_send_failure_output() {
+    assert_eq!(parse_send_progress("12:34:56\t1234\ttank@snap"), Some(1234));
+    assert_eq!(parse_send_progress("cannot open 'tank@snap': dataset does not exist"), None);
+    assert_eq!(parse_send_progress("a\tb\tc"), None);
+
+    let tail = VecDeque::from(vec!["warning: something".to_owned(),
+                                   "cannot send 'tank@snap': permission denied".to_owned()]);
+    assert_eq!(send_failed(1, &tail).to_string(),
+               "'zfs send' returned nonzero exit code: 1: warning: something\n\
+                cannot send 'tank@snap': permission denied");
+    assert_eq!(send_failed(1, &VecDeque::new()).to_string(),
+               "'zfs send' returned nonzero exit code: 1");
+}
+
+// Ends a send's progress display when it's dropped, so that it's ended even if the send fails
+// partway through.
+struct SendFinishedGuard<'a>(&'a Output);
+
+impl Drop for SendFinishedGuard<'_> {
+    fn drop(&mut self) {
+        (self.0)(&Event::SendFinished);
+    }
 }
 
 fn read_line<R: Read>(r: &mut R) -> Result<Option<String>, Error> {
-    let mut line = String::new();
+    // Accumulate bytes and decode the whole line at once, so multi-byte UTF-8 characters (in
+    // dataset names, for example) come out intact.
+    let mut line = Vec::new();
     loop {
         let mut buf = [0u8; 1];
         match r.read(&mut buf) {
@@ -35,12 +251,12 @@ fn read_line<R: Read>(r: &mut R) -> Result<Option<String>, Error> {
                     if line.is_empty() {
                         return Ok(None);
                     } else {
-                        return Ok(Some(line));
+                        return Ok(Some(String::from_utf8_lossy(&line).into_owned()));
                     }
                 } else if buf[0] == b'\n' {
-                    return Ok(Some(line));
+                    return Ok(Some(String::from_utf8_lossy(&line).into_owned()));
                 } else {
-                    line.push(buf[0] as char);   // we're assuming pure ASCII here.
+                    line.push(buf[0]);
                 }
             }
             Err(e) => return Err(e),
@@ -48,7 +264,16 @@ fn read_line<R: Read>(r: &mut R) -> Result<Option<String>, Error> {
     }
 }
 
-fn human_number(n: u64, decimals: usize) -> String {
+#[test]
+fn test_read_line_utf8() {
+    let mut input: &[u8] = "size\t123\ncannot send 'tank/caf\u{e9}@1'\nlast".as_bytes();
+    assert_eq!(read_line(&mut input).unwrap().as_deref(), Some("size\t123"));
+    assert_eq!(read_line(&mut input).unwrap().as_deref(), Some("cannot send 'tank/caf\u{e9}@1'"));
+    assert_eq!(read_line(&mut input).unwrap().as_deref(), Some("last"));
+    assert_eq!(read_line(&mut input).unwrap(), None);
+}
+
+pub fn human_number(n: u64, decimals: usize) -> String {
     if n == 0 {
         return "0".to_string();
     }
@@ -81,39 +306,479 @@ fn test_human_number() {
     assert_eq!(human_number(1000000000000, 1), "1.0 T");
 }
 
+// How much weight each new compression ratio sample gets in the displayed (moving average) ratio.
+const RATIO_SMOOTHING: f64 = 0.3;
+
+// How often 'zfs receive' progress is reported.
+const RECEIVE_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+// The percentage by which the input has been shrunk by compression. This is negative if the output
+// is bigger than the input.
+fn compression_ratio(input_size: u64, output_size: u64) -> Option<f64> {
+    if input_size == 0 {
+        return None;
+    }
+    let ratio = 100. - (output_size as f64) / (input_size as f64) * 100.;
+    Some(ratio.min(100.))
+}
+
+#[test]
+fn test_compression_ratio() {
+    assert_eq!(compression_ratio(0, 0), None);
+    assert_eq!(compression_ratio(0, 100), None);
+    assert_eq!(compression_ratio(1000, 250), Some(75.));
+    assert_eq!(compression_ratio(1000, 0), Some(100.));
+    assert_eq!(compression_ratio(1000, 2000), Some(-100.));
+}
+
+// Batch operations on snapshots have to be done one pool at a time.
+fn group_by_pool<I, T>(names: I) -> BTreeMap<String, Vec<String>>
+    where I: Iterator<Item=T>,
+          T: AsRef<str>,
+{
+    let mut groups = BTreeMap::<String, Vec<String>>::new();
+    for name in names {
+        let name = name.as_ref();
+        let pool = name.split(['/', '@']).next().unwrap_or(name);
+        groups.entry(pool.to_owned()).or_default().push(name.to_owned());
+    }
+    groups
+}
+
+#[test]
+fn test_group_by_pool() {
+    let names = (0..100).map(|i| format!("tank/fs{}@2021-01-01", i))
+        .chain((0..50).map(|i| format!("backup/fs{}@2021-01-01", i)))
+        .chain(std::iter::once("tank@2021-01-01".to_owned()));
+    let groups = group_by_pool(names);
+    assert_eq!(groups.len(), 2);
+    assert_eq!(groups["tank"].len(), 101);
+    assert_eq!(groups["backup"].len(), 50);
+}
+
+/// How far down its pool's tree a dataset is: 0 for a pool's root dataset, 1 for its children,
+/// and so on.
+pub fn dataset_depth(name: &str) -> usize {
+    let dataset = name.split('@').next().unwrap_or(name);
+    dataset.matches('/').count()
+}
+
+#[test]
+fn test_dataset_depth() {
+    assert_eq!(dataset_depth("tank"), 0);
+    assert_eq!(dataset_depth("tank@2021-01-01"), 0);
+    assert_eq!(dataset_depth("tank/home/user"), 2);
+}
+
+// Quote a string for use as a single word in a shell command line, if it needs it.
+pub(crate) fn shell_quote(s: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "/._-+=:,@".contains(c);
+    if !s.is_empty() && s.chars().all(safe) {
+        s.to_owned()
+    } else {
+        format!("'{}'", s.replace('\'', "'\\''"))
+    }
+}
+
+#[test]
+fn test_shell_quote() {
+    assert_eq!(shell_quote("zfs"), "zfs");
+    assert_eq!(shell_quote("/usr/local/sbin/zfs"), "/usr/local/sbin/zfs");
+    assert_eq!(shell_quote("/opt/my tools/zfs"), "'/opt/my tools/zfs'");
+    assert_eq!(shell_quote("it's"), "'it'\\''s'");
+    assert_eq!(shell_quote(""), "''");
+}
+
+// Write a hash sidecar in the format the `sha*sum` tools use. It's written to a temporary file
+// and renamed into place, so it never exists without the file name in it.
+pub(crate) fn write_sidecar(path: &Path, hash: &str, filename: &OsStr,
+                            writer: Option<&MetadataWriter>)
+    -> Result<(), Error>
+{
+    let mut contents = format!("{} *", hash).into_bytes();
+    contents.extend_from_slice(filename.as_bytes());
+    contents.push(b'\n');
+    write_metadata(path, &contents, writer)
+}
+
+// Write a sidecar or manifest, with the given writer if there is one, or else to a temporary file
+// which is renamed into place.
+fn write_metadata(path: &Path, contents: &[u8], writer: Option<&MetadataWriter>)
+    -> Result<(), Error>
+{
+    if let Some(writer) = writer {
+        return (writer.0)(path, contents);
+    }
+    let mut tmp_filename = path.file_name().unwrap_or_default().to_os_string();
+    tmp_filename.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_filename);
+    fs::write(&tmp_path, contents)?;
+    fs::rename(&tmp_path, path)
+}
+
+// Remove the in-progress output of a send to the given destination: `<destination>_partial` and its
+// sidecar, or `<destination>.NNN_partial` chunk files.
+fn remove_partial_files(destination_path: &Path) -> Result<(), Error> {
+    let filename = destination_path.file_name().unwrap().to_string_lossy().into_owned();
+    let dir = match destination_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let single = format!("{}_partial", filename);
+    let sidecars = [format!("{}_partial.sha256sum", filename),
+                    format!("{}_partial.sha512sum", filename)];
+    let chunk_prefix = format!("{}.", filename);
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let is_chunk = name.strip_prefix(&chunk_prefix)
+            .and_then(|rest| rest.strip_suffix("_partial"))
+            .map(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+            .unwrap_or(false);
+        if name == single || sidecars.contains(&name) || is_chunk {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_remove_partial_files() {
+    let dir = crate::TestDir::new("partial");
+    let names = ["a.gpg_partial", "a.gpg_partial.sha256sum", "a.gpg_partial.sha512sum",
+                 "a.gpg.000_partial",
+                 "a.gpg.001_partial", "a.gpg", "a.gpg.000", "a.gpg.x_partial", "b.gpg_partial"];
+    for name in &names {
+        fs::write(dir.join(name), b"").unwrap();
+    }
+
+    remove_partial_files(&dir.join("a.gpg")).unwrap();
+    let mut left: Vec<String> = fs::read_dir(&*dir).unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    left.sort();
+    assert_eq!(left, ["a.gpg", "a.gpg.000", "a.gpg.x_partial", "b.gpg_partial"]);
+}
+
+// Given the output of 'zfs list -H -o name,mountpoint', find the filesystem whose mountpoint is the
+// deepest one containing the given (canonical) path.
+fn containing_mountpoint(list: &str, path: &Path) -> Option<(String, PathBuf)> {
+    list.lines()
+        .filter_map(|line| line.split_once('\t'))
+        .filter(|(_, mountpoint)| mountpoint.starts_with('/'))
+        .map(|(name, mountpoint)| (name.to_owned(), PathBuf::from(mountpoint)))
+        .filter(|(_, mountpoint)| path.starts_with(mountpoint))
+        .max_by_key(|(_, mountpoint)| mountpoint.components().count())
+}
+
+#[test]
+fn test_containing_mountpoint() {
+    let list = "tank\t/tank\ntank/backup\t/tank/backup\ntank/legacy\tlegacy\n\
+                tank/hidden\tnone\nbpool\t/boot\n";
+    let found = |path: &str| containing_mountpoint(list, Path::new(path)).map(|(name, _)| name);
+    assert_eq!(found("/tank/backup/daily").as_deref(), Some("tank/backup"));
+    assert_eq!(found("/tank/backups").as_deref(), Some("tank"));
+    assert_eq!(found("/tank").as_deref(), Some("tank"));
+    assert_eq!(found("/mnt/usb"), None);
+}
+
+// Find the total size in the output of 'zfs send -nP'.
+fn parse_send_estimate(output: &str) -> Option<u64> {
+    output.lines()
+        .filter_map(|line| line.strip_prefix("size\t"))
+        .find_map(|size| size.trim().parse::<u64>().ok())
+}
+
+#[test]
+fn test_parse_send_estimate() {
+    assert_eq!(parse_send_estimate("full\ttank@2021-01-02\t123456\nsize\t123456\n"), Some(123456));
+    assert_eq!(parse_send_estimate(
+        "incremental\t2021-01-01\ttank@2021-01-02\t789\nsize\t789\n"), Some(789));
+    assert_eq!(parse_send_estimate("cannot open 'tank@nope'\n"), None);
+}
+
+/// Where a dataset's property value comes from, as 'zfs get' reports it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PropertySource {
+    /// Set on the dataset itself.
+    Local,
+    /// Not set on the dataset itself, but on the named ancestor.
+    Inherited(String),
+    /// Set by a 'zfs receive'.
+    Received,
+    /// Not set anywhere, so it has the default value.
+    Default,
+}
+
+/// A property's value for each dataset which has one, with where it came from.
+pub type PropertyValues = HashMap<String, (String, PropertySource)>;
+
+// Parse the output of 'zfs get -H -o name,value,source'. Datasets which don't have the property at
+// all (which is how user properties show up when they aren't set anywhere) are left out.
+fn parse_property_values(output: &str) -> PropertyValues {
+    output.lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, '\t');
+            let name = fields.next()?;
+            let value = fields.next()?;
+            let source = match fields.next()?.trim_end() {
+                "local" => PropertySource::Local,
+                "received" => PropertySource::Received,
+                "default" => PropertySource::Default,
+                source => PropertySource::Inherited(source.strip_prefix("inherited from ")?
+                                                    .to_owned()),
+            };
+            Some((name.to_owned(), (value.to_owned(), source)))
+        })
+        .collect()
+}
+
+#[test]
+fn test_parse_property_values() {
+    let values = parse_property_values("tank\tkeep\tlocal\n\
+                                        tank/a\tkeep\tinherited from tank\n\
+                                        tank/b\toff\tlocal\n\
+                                        other\t-\t-\n");
+    assert_eq!(values.len(), 3);
+    assert_eq!(values["tank/a"], ("keep".to_owned(), PropertySource::Inherited("tank".to_owned())));
+    assert_eq!(values["tank/b"], ("off".to_owned(), PropertySource::Local));
+    assert!(!values.contains_key("other"));
+}
+
+/// The health of a pool, as 'zpool status' reports it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PoolState {
+    Online,
+    /// Some redundancy has been lost, but all the data can still be read.
+    Degraded,
+    Faulted,
+    Offline,
+    Removed,
+    Unavail,
+    Suspended,
+    Other(String),
+}
+
+impl PoolState {
+    fn parse(health: &str) -> PoolState {
+        match health {
+            "ONLINE" => PoolState::Online,
+            "DEGRADED" => PoolState::Degraded,
+            "FAULTED" => PoolState::Faulted,
+            "OFFLINE" => PoolState::Offline,
+            "REMOVED" => PoolState::Removed,
+            "UNAVAIL" => PoolState::Unavail,
+            "SUSPENDED" => PoolState::Suspended,
+            other => PoolState::Other(other.to_owned()),
+        }
+    }
+}
+
+impl fmt::Display for PoolState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            PoolState::Online => "ONLINE",
+            PoolState::Degraded => "DEGRADED",
+            PoolState::Faulted => "FAULTED",
+            PoolState::Offline => "OFFLINE",
+            PoolState::Removed => "REMOVED",
+            PoolState::Unavail => "UNAVAIL",
+            PoolState::Suspended => "SUSPENDED",
+            PoolState::Other(other) => other,
+        })
+    }
+}
+
+#[test]
+fn test_pool_state() {
+    assert_eq!(PoolState::parse("DEGRADED"), PoolState::Degraded);
+    assert_eq!(PoolState::parse("weird"), PoolState::Other("weird".to_owned()));
+    assert_eq!(PoolState::Faulted.to_string(), "FAULTED");
+}
+
+/// How a file changed between two snapshots, as reported by `zfs diff`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DiffChange {
+    Modified,
+    Added,
+    Removed,
+    Renamed,
+}
+
+impl DiffChange {
+    /// The symbol `zfs diff` uses for this kind of change.
+    pub fn symbol(self) -> &'static str {
+        match self {
+            DiffChange::Modified => "M",
+            DiffChange::Added => "+",
+            DiffChange::Removed => "-",
+            DiffChange::Renamed => "R",
+        }
+    }
+}
+
+/// One changed path between two snapshots.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiffEntry {
+    pub change: DiffChange,
+    pub path: String,
+    /// Where the file was renamed to, for `DiffChange::Renamed`.
+    pub new_path: Option<String>,
+}
+
+// 'zfs diff' escapes unusual characters in paths (including spaces and tabs) as a backslash
+// followed by four octal digits, giving the byte value.
+fn unescape_diff_path(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut out = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 5 <= bytes.len() {
+            let octal = std::str::from_utf8(&bytes[i + 1 .. i + 5]).ok()
+                .and_then(|digits| u8::from_str_radix(digits, 8).ok());
+            if let Some(byte) = octal {
+                out.push(byte);
+                i += 5;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// Parse the output of 'zfs diff -H'.
+fn parse_diff(output: &str) -> Result<Vec<DiffEntry>, ZfsError> {
+    output.lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let bad_line = || ZfsError::from(format!("unexpected 'zfs diff' output: {:?}", line));
+            let mut fields = line.split('\t');
+            let change = match fields.next() {
+                Some("M") => DiffChange::Modified,
+                Some("+") => DiffChange::Added,
+                Some("-") => DiffChange::Removed,
+                Some("R") => DiffChange::Renamed,
+                _ => return Err(bad_line()),
+            };
+            let path = unescape_diff_path(fields.next().ok_or_else(bad_line)?);
+            let new_path = fields.next().map(unescape_diff_path);
+            Ok(DiffEntry { change, path, new_path })
+        })
+        .collect()
+}
+
+#[test]
+fn test_parse_diff() {
+    let output = "M\t/tank/home/\n\
+                  +\t/tank/home/new\\0040file\n\
+                  -\t/tank/home/old\n\
+                  R\t/tank/home/a\t/tank/home/b\n";
+    let entry = |change, path: &str, new_path: Option<&str>| DiffEntry {
+        change,
+        path: path.to_owned(),
+        new_path: new_path.map(str::to_owned),
+    };
+    assert_eq!(parse_diff(output).unwrap(), vec![
+        entry(DiffChange::Modified, "/tank/home/", None),
+        entry(DiffChange::Added, "/tank/home/new file", None),
+        entry(DiffChange::Removed, "/tank/home/old", None),
+        entry(DiffChange::Renamed, "/tank/home/a", Some("/tank/home/b")),
+    ]);
+    assert!(parse_diff("cannot open\n").is_err());
+}
+
 fn exclude_dataset(_ds: &libzfs::Dataset) -> bool {
-    // TODO: exclude ones with the 'zsnapmgr:noautosnap' property
+    // TODO: exclude ones with the 'zsnapmgr:noautosnap' property. The libzfs bindings can't read
+    // user properties, so this can't go by the `zsnapmgr:retention` property either; that's only
+    // read (with 'zfs get') for automanage. See `RetentionPolicy`.
     false
 }
 
 impl Zfs {
     pub fn new(use_sudo: bool) -> Result<Self, ZfsError> {
         let client = libzfs::LibZfs::new()?;
+        let program = |var: &str, default: &str| {
+            std::env::var(var).ok()
+                .filter(|path| !path.is_empty())
+                .unwrap_or_else(|| default.to_owned())
+        };
         Ok(Self {
             client,
             use_sudo,
+            zfs_program: program("ZSNAPMGR_ZFS_BIN", "zfs"),
+            zpool_program: program("ZSNAPMGR_ZPOOL_BIN", "zpool"),
+            sudo_program: program("ZSNAPMGR_SUDO_BIN", "sudo"),
+            volumes_cache: Mutex::new(HashMap::new()),
+            snapshots_cache: Mutex::new(HashMap::new()),
+            output: Arc::new(|_| ()),
         })
     }
 
+    /// Set a callback to receive an `Event::ZfsCall` for each libzfs call and 'zfs' command made.
+    /// By default, they're discarded.
+    pub fn set_output(&mut self, output: Arc<Output>) {
+        self.output = output;
+    }
+
+    fn trace(&self, call: &str) {
+        (self.output)(&Event::ZfsCall(call));
+    }
+
+    /// Forget the cached volume and snapshot lists, so they'll be read again from ZFS next time.
+    pub fn invalidate_cache(&self) {
+        self.volumes_cache.lock().unwrap().clear();
+        self.snapshots_cache.lock().unwrap().clear();
+    }
+
     pub fn volumes(&self, pool: Option<&str>) -> Result<Vec<String>, ZfsError> {
+        Ok(self.volumes_typed(pool)?.into_iter().map(|(name, _)| name).collect())
+    }
+
+    /// Like `volumes`, but also say whether each one is a filesystem or a zvol
+    /// (`DatasetType::Filesystem` or `DatasetType::Volume`).
+    pub fn volumes_typed(&self, pool: Option<&str>) -> Result<TypedVolumes, ZfsError> {
+        let key = pool.map(str::to_owned);
+        if let Some(volumes) = self.volumes_cache.lock().unwrap().get(&key) {
+            return Ok(volumes.clone());
+        }
+        let volumes = self.list_volumes(pool)?;
+        self.volumes_cache.lock().unwrap().insert(key, volumes.clone());
+        Ok(volumes)
+    }
+
+    pub fn snapshots(&self, dataset: Option<&str>) -> Result<Vec<String>, ZfsError> {
+        let key = dataset.map(str::to_owned);
+        if let Some(snapshots) = self.snapshots_cache.lock().unwrap().get(&key) {
+            return Ok(snapshots.clone());
+        }
+        let snapshots = self.list_snapshots(dataset)?;
+        self.snapshots_cache.lock().unwrap().insert(key, snapshots.clone());
+        Ok(snapshots)
+    }
+
+    fn list_volumes(&self, pool: Option<&str>) -> Result<TypedVolumes, ZfsError> {
         // for purposes of this program, "volumes" is defined as filesystems + zvols
         let mut volumes = vec![];
         let pool_names = if let Some(name) = pool {
             vec![name.into()]
         } else {
+            self.trace("get_zpools");
             self.client.get_zpools()?
                 .into_iter()
                 .map(|pool| pool.get_name()).collect()
         };
         for pool_name in pool_names {
+            self.trace(&format!("get_all_dependents {}", pool_name.to_string_lossy()));
             let pool_dataset = self.client.dataset_by_name(&pool_name, DatasetTypeMask::all())?;
             for dataset in pool_dataset.get_all_dependents()? {
                 if exclude_dataset(&dataset) {
                     continue;
                 }
                 match dataset.get_type() {
-                    DatasetType::Filesystem | DatasetType::Volume => {
-                        volumes.push(dataset.get_name().to_string());
+                    kind @ DatasetType::Filesystem | kind @ DatasetType::Volume => {
+                        volumes.push((dataset.get_name().to_string(), kind));
                     },
                     _ => (),
                 }
@@ -122,9 +787,32 @@ impl Zfs {
         Ok(volumes)
     }
 
-    pub fn snapshots(&self, dataset: Option<&str>) -> Result<Vec<String>, ZfsError> {
+    /// Check whether a dataset is a zvol.
+    pub fn is_zvol(&self, name: &str) -> Result<bool, ZfsError> {
+        self.trace(&format!("dataset_by_name {}", name));
+        let dataset = self.client.dataset_by_name(&name.into(), DatasetTypeMask::all())?;
+        Ok(dataset.get_type() == DatasetType::Volume)
+    }
+
+    /// Check whether a dataset (of any type) exists.
+    pub fn dataset_exists(&self, name: &str) -> bool {
+        self.trace(&format!("dataset_by_name {}", name));
+        self.client.dataset_by_name(&name.into(), DatasetTypeMask::all()).is_ok()
+    }
+
+    /// Check whether a snapshot exists and can be opened, and so can be used as a send source.
+    pub fn snapshot_exists(&self, snapshot: &str) -> bool {
+        self.trace(&format!("dataset_by_name {}", snapshot));
+        match self.client.dataset_by_name(&snapshot.into(), DatasetTypeMask::all()) {
+            Ok(ds) => ds.get_type() == DatasetType::Snapshot,
+            Err(_) => false,
+        }
+    }
+
+    fn list_snapshots(&self, dataset: Option<&str>) -> Result<Vec<String>, ZfsError> {
         match dataset {
             Some(name) => {
+                self.trace(&format!("get_snapshots {}", name));
                 let ds = self.client.dataset_by_name(&name.into(), DatasetTypeMask::all())?;
                 Ok(ds.get_snapshots()?
                     .into_iter()
@@ -139,8 +827,11 @@ impl Zfs {
             }
             None => {
                 let mut snapshots = vec![];
+                self.trace("get_zpools");
                 for pool in self.client.get_zpools()? {
-                    let pool_ds = self.client.dataset_by_name(&pool.get_name(), DatasetTypeMask::all())?;
+                    let pool_name = pool.get_name();
+                    self.trace(&format!("get_all_dependents {}", pool_name.to_string_lossy()));
+                    let pool_ds = self.client.dataset_by_name(&pool_name, DatasetTypeMask::all())?;
                     snapshots.extend(
                         pool_ds.get_all_dependents()?
                             .into_iter()
@@ -161,46 +852,447 @@ impl Zfs {
         }
     }
 
+    /// Create snapshots, with one libzfs call per pool. All the snapshots in a pool are created
+    /// atomically.
     pub fn create_snapshots<I: Iterator<Item=T>, T: AsRef<str>>(&self, names: I) -> Result<(), ZfsError> {
-        self.client.create_snapshots(names)?;
+        self.invalidate_cache();
+        for (_pool, names) in group_by_pool(names) {
+            self.trace(&format!("create_snapshots {}", names.join(" ")));
+            self.client.create_snapshots(names.iter())?;
+        }
         Ok(())
     }
 
+    /// Destroy snapshots, with one libzfs call per pool.
     pub fn destroy_snapshots<I, T>(&self, names: I) -> Result<(), ZfsError>
         where I: Iterator<Item=T>,
               T: AsRef<str>,
     {
-        self.client.destroy_snapshots(names)?;
+        self.invalidate_cache();
+        for (_pool, names) in group_by_pool(names) {
+            self.trace(&format!("destroy_snapshots {}", names.join(" ")));
+            self.client.destroy_snapshots(names.iter())?;
+        }
         Ok(())
     }
 
+    // A command to run the 'zfs' program, with sudo if needed.
+    fn zfs_command(&self) -> Command {
+        self.command(&self.zfs_program)
+    }
+
+    // A command to run one of the ZFS programs, with sudo if needed. The libzfs bindings only cover
+    // listing datasets and creating and destroying snapshots, so anything else, like sends, holds,
+    // properties and pool health, has to go through 'zfs' or 'zpool' instead.
+    fn command(&self, program: &str) -> Command {
+        if self.use_sudo {
+            let mut cmd = Command::new(&self.sudo_program);
+            cmd.arg(program);
+            cmd
+        } else {
+            Command::new(program)
+        }
+    }
+
+    // How to run the 'zfs' program (with sudo if needed) in a shell command line.
+    pub(crate) fn zfs_shell_command(&self) -> String {
+        if self.use_sudo {
+            format!("{} {}", shell_quote(&self.sudo_program), shell_quote(&self.zfs_program))
+        } else {
+            shell_quote(&self.zfs_program)
+        }
+    }
+
+    // Run a 'zfs' subcommand, returning its stdout. This is for what the libzfs bindings can't do
+    // (see `command`).
+    fn run_zfs(&self, args: &[&str]) -> Result<String, ZfsError> {
+        self.trace(&format!("zfs {}", args.join(" ")));
+        let output = zfstry!(self.zfs_command().args(args).stdin(Stdio::null()).output(),
+            or format!("failed to run 'zfs {}'", args[0]));
+        if !output.status.success() {
+            return Err(ZfsError::from((&*format!("'zfs {}' failed", args[0]), &output.stderr)));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// Get a property of every filesystem and zvol. ZFS works out inheritance itself: a dataset
+    /// without the property set on it gets the value from its nearest ancestor which has it set.
+    pub fn property_values(&self, property: &str) -> Result<PropertyValues, ZfsError> {
+        let output = self.run_zfs(&["get", "-H", "-p", "-o", "name,value,source",
+                                    "-t", "filesystem,volume", property])?;
+        Ok(parse_property_values(&output))
+    }
+
+    /// Get the snapshots of one dataset (not its children), with when each was created, as a Unix
+    /// time.
+    pub fn snapshot_creation_times(&self, dataset: &str) -> Result<Vec<(String, i64)>, ZfsError> {
+        let output = self.run_zfs(&["list", "-H", "-p", "-o", "name,creation", "-t", "snapshot",
+                                    "-d", "1", dataset])?;
+        Ok(output.lines()
+            .filter_map(|line| line.split_once('\t'))
+            .filter_map(|(name, time)| Some((name.to_owned(), time.trim().parse().ok()?)))
+            .collect())
+    }
+
+    /// Give a snapshot a new name (the part after the '@').
+    pub fn rename_snapshot(&self, snapshot: &str, new_name: &str) -> Result<(), ZfsError> {
+        self.invalidate_cache();
+        self.run_zfs(&["rename", snapshot, &format!("@{}", new_name)]).map(|_| ())
+    }
+
+    /// Find out how healthy a pool is.
+    pub fn pool_health(&self, pool: &str) -> Result<PoolState, ZfsError> {
+        self.trace(&format!("zpool list -H -o health {}", pool));
+        let output = zfstry!(self.command(&self.zpool_program)
+                .args(["list", "-H", "-o", "health", pool])
+                .stdin(Stdio::null())
+                .output(),
+            or "failed to run 'zpool list'");
+        if !output.status.success() {
+            return Err(ZfsError::from(("'zpool list' failed", &output.stderr)));
+        }
+        Ok(PoolState::parse(String::from_utf8_lossy(&output.stdout).trim()))
+    }
+
+    /// Find out whether a pool feature (like `large_blocks`) is enabled or active on a pool.
+    /// Features the pool's version of zfs doesn't know about at all count as not enabled.
+    pub fn pool_feature_enabled(&self, pool: &str, feature: &str) -> Result<bool, ZfsError> {
+        let property = format!("feature@{}", feature);
+        self.trace(&format!("zpool get -H -o value {} {}", property, pool));
+        let output = zfstry!(self.command(&self.zpool_program)
+                .args(["get", "-H", "-o", "value", &property, pool])
+                .stdin(Stdio::null())
+                .output(),
+            or "failed to run 'zpool get'");
+        if !output.status.success() {
+            return Err(ZfsError::from(("'zpool get' failed", &output.stderr)));
+        }
+        Ok(matches!(String::from_utf8_lossy(&output.stdout).trim(), "enabled" | "active"))
+    }
+
+    /// List the files which changed between two snapshots of the same volume. `to` may also be
+    /// the volume itself, to compare against its current contents.
+    pub fn diff(&self, from: &str, to: &str) -> Result<Vec<DiffEntry>, ZfsError> {
+        parse_diff(&self.run_zfs(&["diff", "-H", from, to])?)
+    }
+
+    /// Place a hold with the given tag on a snapshot, preventing it from being destroyed.
+    pub fn hold(&self, snapshot: &str, tag: &str) -> Result<(), ZfsError> {
+        self.run_zfs(&["hold", tag, snapshot]).map(|_| ())
+    }
+
+    /// Release a hold with the given tag from a snapshot.
+    pub fn release(&self, snapshot: &str, tag: &str) -> Result<(), ZfsError> {
+        self.run_zfs(&["release", tag, snapshot]).map(|_| ())
+    }
+
+    /// Get the names of all snapshots which have at least one hold on them.
+    pub fn held_snapshots(&self) -> Result<HashSet<String>, ZfsError> {
+        let output = self.run_zfs(&["get", "-H", "-p", "-o", "name,value", "-t", "snapshot",
+                                    "userrefs"])?;
+        Ok(output.lines()
+            .filter_map(|line| line.split_once('\t'))
+            .filter(|(_, refs)| refs.trim().parse::<u64>().map(|n| n > 0).unwrap_or(false))
+            .map(|(name, _)| name.to_owned())
+            .collect())
+    }
+
+    /// How many bytes have been written to a dataset since its most recent snapshot (its `written`
+    /// property). If it has no snapshots, this is everything it references.
+    pub fn written_since_last_snapshot(&self, dataset: &str) -> Result<u64, ZfsError> {
+        let output = self.run_zfs(&["get", "-H", "-p", "-o", "value", "written", dataset])?;
+        output.trim().parse::<u64>().map_err(|e| ZfsError::from(format!(
+            "unexpected 'written' property value {:?} for {}: {}", output.trim(), dataset, e)))
+    }
+
+    /// Ask 'zfs send' how big the stream for a snapshot would be, without sending anything
+    /// ('zfs send -nP'). Options which change what's sent are taken into account.
+    pub fn estimate_send_size(&self, snapshot: &str, incremental: Option<&str>,
+                              options: &SendOptions)
+        -> Result<u64, ZfsError>
+    {
+        let incremental = incremental.map(|start| format!("@{}", start));
+        let mut args = vec!["send", "-n", "-P"];
+        if options.send_properties {
+            args.push("-p");
+        }
+        if options.use_zfs_compression {
+            args.push("--compressed");
+        }
+        if options.large_blocks {
+            args.push("--large-block");
+        }
+        if options.embedded_data {
+            args.push("--embed");
+        }
+        if let Some(ref start) = incremental {
+            args.push(if options.all_intermediates { "-I" } else { "-i" });
+            args.push(start);
+        }
+        args.push(snapshot);
+
+        self.trace(&format!("zfs {}", args.join(" ")));
+        let output = zfstry!(self.zfs_command().args(&args).stdin(Stdio::null()).output(),
+            or "failed to run 'zfs send'");
+        if !output.status.success() {
+            return Err(ZfsError::from(("'zfs send -n' failed", &output.stderr)));
+        }
+        // Older versions of zfs print the estimate to stderr rather than stdout.
+        parse_send_estimate(&String::from_utf8_lossy(&output.stdout))
+            .or_else(|| parse_send_estimate(&String::from_utf8_lossy(&output.stderr)))
+            .ok_or_else(|| ZfsError::from("'zfs send -n' didn't give a size estimate"))
+    }
+
+    /// Get up to `max_bytes` from the start of a snapshot's 'zfs send' stream, to try compressors
+    /// out on.
+    pub fn send_sample(&self, snapshot: &str, max_bytes: u64, output: &Output)
+        -> Result<Vec<u8>, ZfsError>
+    {
+        // "$0" is an additional argument passed to sh, as in send.
+        let cmdline = format!("{} send \"$0\"", self.zfs_shell_command());
+        output(&Event::Running(&cmdline.replace("$0", snapshot)));
+        let mut child = zfstry!(Command::new("sh")
+            .arg("-c")
+            .arg(&cmdline)
+            .arg(snapshot)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn(), or "failed to run 'zfs send'");
+
+        let mut sample = vec![];
+        let mut stdout = child.stdout.take().unwrap();
+        let read_result = stdout.by_ref().take(max_bytes).read_to_end(&mut sample);
+        // Closing the pipe stops 'zfs send' (with SIGPIPE) if there's more than we want, in which
+        // case its exit status doesn't mean anything. Otherwise it has to have succeeded, or the
+        // sample may have been cut short.
+        drop(stdout);
+        let full = sample.len() as u64 == max_bytes;
+        let exit_status = zfstry!(child.wait(), or "failed to wait for 'zfs send'");
+        zfstry!(read_result, or "failed to read from 'zfs send'");
+        if !full && !exit_status.success() {
+            let code = exit_status.code().unwrap_or(0);
+            return Err(ZfsError::from(format!("'zfs send' returned nonzero exit code: {}", code)));
+        }
+        if sample.is_empty() {
+            return Err(ZfsError::from("'zfs send' produced no output"));
+        }
+        Ok(sample)
+    }
+
+    /// Receive a stream from a backup file into a new dataset, first running it through the given
+    /// filter program (to decrypt and decompress it). Extra arguments are passed to
+    /// 'zfs receive'.
+    ///
+    /// 'zfs receive' doesn't report its progress the way 'zfs send' does, so the file is fed into
+    /// the pipeline from here instead, and progress is reported as how much of it has been read.
+    pub fn receive(&self,
+                   source_path: &Path,
+                   target: &str,
+                   filter_program: Option<&str>,
+                   receive_args: &[&str],
+                   output: &Output)
+                   -> Result<(), ZfsError> {
+        self.invalidate_cache();
+
+        // As with send, "$0" is an additional argument passed to sh.
+        let receive_cmdline = format!("{} receive {} \"$0\"",
+            self.zfs_shell_command(),
+            receive_args.join(" "));
+        let cmdline = match filter_program {
+            Some(filter) => format!("{} | {}", filter, receive_cmdline),
+            None => receive_cmdline,
+        };
+
+        let mut source = zfstry!(fs::File::open(source_path),
+                                 or format!("failed to open {:?}", source_path));
+        let total_size = zfstry!(source.metadata(),
+                                 or format!("failed to read metadata of {:?}", source_path)).len();
+
+        output(&Event::Running(&format!("{} < {}",
+            cmdline.replace("$0", target),
+            source_path.to_string_lossy())));
+        let mut child = zfstry!(Command::new("sh")
+            .arg("-c")
+            .arg(&cmdline)
+            .arg(target)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit())
+            .spawn(), or "failed to run 'zfs receive'");
+
+        let read_progress = Arc::new(hash_stream::AtomicU64::new(0));
+        let read_progress_thread = Arc::clone(&read_progress);
+        let pipeline_in = child.stdin.take().unwrap();
+        let (done_tx, done_rx) = mpsc::channel();
+        thread::spawn(move || {
+            // The pipeline's stdin is closed when this returns, letting it finish.
+            let result = hash_stream::copy_hashed_multi(
+                &mut source,
+                pipeline_in,
+                &[],
+                hash_stream::DEFAULT_BUFFER_SIZE,
+                &read_progress_thread);
+            let _ = done_tx.send(result);
+        });
+
+        let start_time = Instant::now();
+        let copy_result = loop {
+            match done_rx.recv_timeout(RECEIVE_PROGRESS_INTERVAL) {
+                Ok(result) => break result.map(|_| ()),
+                Err(RecvTimeoutError::Disconnected) => {
+                    break Err("reading thread died".to_owned());
+                }
+                Err(RecvTimeoutError::Timeout) => (),
+            }
+            output(&Event::ReceiveProgress {
+                source: source_path,
+                total_size,
+                read: read_progress.load(::std::sync::atomic::Ordering::Relaxed),
+                elapsed_seconds: start_time.elapsed().as_secs(),
+            });
+        };
+        output(&Event::ReceiveFinished);
+
+        // If the pipeline fails, writing to it fails too, but the pipeline's exit code is the
+        // more useful thing to report.
+        let exit_status = child.wait()?;
+        if !exit_status.success() {
+            let code = exit_status.code().unwrap_or(0);
+            return Err(ZfsError::from(
+                format!("'zfs receive' returned nonzero exit code: {}", code)));
+        }
+        if let Err(e) = copy_result {
+            return Err(ZfsError::from(
+                format!("error feeding {:?} into 'zfs receive': {}", source_path, e)));
+        }
+        Ok(())
+    }
+
+    /// Mount a dataset, returning where it was mounted.
+    pub fn mount(&self, dataset: &str) -> Result<PathBuf, ZfsError> {
+        self.run_zfs(&["mount", dataset])?;
+        let mountpoint = self.run_zfs(&["get", "-H", "-o", "value", "mountpoint", dataset])?;
+        Ok(PathBuf::from(mountpoint.trim_end()))
+    }
+
+    /// Find the mounted ZFS filesystem a path is on, if it is on one.
+    pub fn dataset_containing(&self, path: &Path) -> Result<Option<String>, ZfsError> {
+        let path = zfstry!(fs::canonicalize(path), or format!("failed to resolve {:?}", path));
+        let list = self.run_zfs(&["list", "-H", "-o", "name,mountpoint", "-t", "filesystem"])?;
+        let (name, mountpoint) = match containing_mountpoint(&list, &path) {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        // Something else could be mounted somewhere under a ZFS filesystem's mountpoint, in which
+        // case the path isn't really on that filesystem.
+        let same_device = match (fs::metadata(&path), fs::metadata(&mountpoint)) {
+            (Ok(a), Ok(b)) => a.dev() == b.dev(),
+            _ => false,
+        };
+        Ok(Some(name).filter(|_| same_device))
+    }
+
+    /// Destroy a dataset along with all its snapshots, unmounting it first if needed.
+    pub fn destroy_dataset(&self, dataset: &str) -> Result<(), ZfsError> {
+        self.invalidate_cache();
+        self.run_zfs(&["destroy", "-r", "-f", dataset]).map(|_| ())
+    }
+
+    /// Send a snapshot through a filter program to a file. The output is written to `_partial`
+    /// file(s) which are renamed into place when the send is done. If it fails, they're removed,
+    /// unless `options.keep_partial_on_error` is set.
+    ///
+    /// Returns the destinations which were written, which with
+    /// `options.keep_going_on_destination_error` may not be all of them, or even include
+    /// `destination_path`.
     pub fn send(&self,
                 snapshot: &str,
                 destination_path: &Path,
                 incremental: Option<&str>,
-                filter_program: Option<&str>)
-                -> Result<(), ZfsError> {
+                filter_program: Option<&str>,
+                options: &SendOptions,
+                output: &Output)
+                -> Result<Vec<PathBuf>, ZfsError> {
+        let result = self.send_inner(
+            snapshot, destination_path, incremental, filter_program, options, output);
+        if result.is_err() && !options.keep_partial_on_error && !options.dry_run {
+            for path in iter::once(destination_path).chain(options.extra_destinations.iter()
+                                                           .map(PathBuf::as_path))
+            {
+                if let Err(e) = remove_partial_files(path) {
+                    output(&Event::Warning(&format!("failed to remove partial files: {}", e)));
+                }
+            }
+        }
+        result
+    }
+
+    fn send_inner(&self,
+                  snapshot: &str,
+                  destination_path: &Path,
+                  incremental: Option<&str>,
+                  filter_program: Option<&str>,
+                  options: &SendOptions,
+                  output: &Output)
+                  -> Result<Vec<PathBuf>, ZfsError> {
 
         // This uses 'sh -c' to run the pipeline because it's less work for us.
         // The "$0" and "$1" are replaced by the additional arguments passed to sh.
         // This is nice because it means they can contain any characters and require no escaping.
 
-        let cmdline = format!("{} send --parsable --verbose {} $1 {}{}",
-            if self.use_sudo { "sudo zfs" } else { "zfs" },
-            if incremental.is_some() { "-i @$0" } else { "" },
-            if filter_program.is_some() { " | " } else { "" },
-            filter_program.unwrap_or("")
+        let send_cmdline = format!("{} send --parsable --verbose {}{}{}{}{} $1",
+            self.zfs_shell_command(),
+            if options.send_properties { "-p " } else { "" },
+            if options.use_zfs_compression { "--compressed " } else { "" },
+            if options.large_blocks { "--large-block " } else { "" },
+            if options.embedded_data { "--embed " } else { "" },
+            match incremental {
+                Some(_) if options.all_intermediates => "-I @$0",
+                Some(_) => "-i @$0",
+                None => "",
+            },
         );
 
-        let mut partial_filename = destination_path.file_name().unwrap().to_os_string();
-        partial_filename.push("_partial");
-        let partial_path = destination_path.with_file_name(&partial_filename);
+        // To hash the raw stream, we need to sit between 'zfs send' and the filter program, so
+        // they have to be run as separate processes.
+        let tap_filter = if options.hash_source_stream { filter_program } else { None };
+
+        let cmdline = match filter_program {
+            Some(filter) if tap_filter.is_none() => format!("{} | {}", send_cmdline, filter),
+            _ => send_cmdline,
+        };
+
+        if options.no_hash && (options.split_size.is_some() || options.sha512) {
+            return Err(ZfsError::from("hashing can't be turned off for split or SHA-512 backups"));
+        }
+        if options.no_hash && options.verify_after {
+            return Err(ZfsError::from("can't verify a backup without hashing it"));
+        }
+        if options.split_size.is_some() && !options.extra_destinations.is_empty() {
+            return Err(ZfsError::from("split backups can only be written to one destination"));
+        }
+
+        let destinations: Vec<&Path> = iter::once(destination_path)
+            .chain(options.extra_destinations.iter().map(PathBuf::as_path))
+            .collect();
+        let with_suffix = |path: &Path, suffix: &str| {
+            let mut filename = path.file_name().unwrap().to_os_string();
+            filename.push(suffix);
+            path.with_file_name(filename)
+        };
 
-        println!("running: {}",
+        output(&Event::Running(&format!("{}{}",
             cmdline
                 .replace("$0", incremental.unwrap_or(""))
-                .replace("$1", snapshot)
-        );
+                .replace("$1", snapshot),
+            tap_filter.map(|filter| format!(" | [sha256] | {}", filter)).unwrap_or_default())));
+        if options.dry_run {
+            for path in &destinations {
+                output(&Event::DryRun(path));
+            }
+            return Ok(destinations.iter().map(|path| path.to_path_buf()).collect());
+        }
         let mut child: Child = zfstry!(Command::new("sh")
             .arg("-c")
             .arg(&cmdline)
@@ -211,133 +1303,363 @@ impl Zfs {
             .stderr(Stdio::piped())
             .spawn(), or "failed to run 'zfs send'");
 
-        let mut backup_out = child.stdout.take();
-        let partial_path2 = partial_path.clone();
+        let buffer_size = options.buffer_size;
 
-        let mut sidecar_filename = partial_filename;
-        sidecar_filename.push(".sha256sum");
-        let partial_sidecar_path = partial_path.with_file_name(sidecar_filename);
-        let partial_sidecar_path2 = partial_sidecar_path.clone();
+        let mut filter_child: Option<Child> = None;
+        let mut tap_thread = None;
+        if let Some(filter) = tap_filter {
+            let mut filter = zfstry!(Command::new("sh")
+                .arg("-c")
+                .arg(filter)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::inherit())
+                .spawn(), or "failed to run filter program");
 
-        let mut destination_sidecar_filename = destination_path.file_name().unwrap().to_os_string();
-        destination_sidecar_filename.push(".sha256sum");
-        let destination_sidecar_path = destination_path.with_file_name(destination_sidecar_filename);
+            let mut send_out = child.stdout.take().unwrap();
+            let filter_in = filter.stdin.take().unwrap();
+            tap_thread = Some(thread::spawn(move || {
+                // The filter's stdin is closed when this returns, letting it finish.
+                hash_stream::copy_hashed(
+                    &mut send_out,
+                    filter_in,
+                    &SHA256,
+                    buffer_size,
+                    &hash_stream::AtomicU64::new(0))
+            }));
+            filter_child = Some(filter);
+        }
+
+        let mut backup_out = match filter_child {
+            Some(ref mut filter) => filter.stdout.take(),
+            None => child.stdout.take(),
+        };
+
+        let mut algos = vec![&SHA256];
+        let mut sidecar_suffixes = vec![".sha256sum"];
+        if options.no_hash {
+            algos.clear();
+            sidecar_suffixes.clear();
+        }
+        if options.sha512 {
+            algos.push(&SHA512);
+            sidecar_suffixes.push(".sha512sum");
+        }
+        let algos_hashthread = algos.clone();
+
+        // The partial file for each destination, and its partial sidecars.
+        let partial_files: Vec<(PathBuf, Vec<PathBuf>)> = destinations.iter()
+            .map(|path| {
+                let partial_path = with_suffix(path, "_partial");
+                let sidecars = sidecar_suffixes.iter()
+                    .map(|suffix| with_suffix(&partial_path, suffix))
+                    .collect();
+                (partial_path, sidecars)
+            })
+            .collect();
+        let partial_files_hashthread = partial_files.clone();
+        let keep_going = options.keep_going_on_destination_error;
 
         let output_progress = Arc::new(hash_stream::AtomicU64::new(0));
         let output_progress_hashthread = Arc::clone(&output_progress);
+        let split_size = options.split_size;
+        let destination_filename =
+            destination_path.file_name().unwrap().to_string_lossy().into_owned();
+        let partial_chunk_path = {
+            let destination_path = destination_path.to_owned();
+            move |i: usize| destination_path.with_file_name(
+                format!("{}.{:03}_partial", destination_filename, i))
+        };
         let read_thread = thread::spawn(move || {
-            if let Err(e) = hash_stream::write_file_and_sidecar(
-                backup_out.as_mut().unwrap(),
-                &partial_path2,
-                &partial_sidecar_path2,
-                &SHA256,
-                &output_progress_hashthread)
-            {
-                let msg = format!("Error reading/writing 'zfs send' pipeline: {}", e);
-                println!("{}", msg);
-                panic!("{}", msg);
+            let result = match split_size {
+                Some(chunk_size) => hash_stream::write_split_files(
+                    backup_out.as_mut().unwrap(),
+                    partial_chunk_path,
+                    chunk_size,
+                    &algos_hashthread,
+                    buffer_size,
+                    &output_progress_hashthread)
+                    .map(|(hashes, chunks)| (hashes, chunks, vec![])),
+                None => hash_stream::write_files_and_sidecars(
+                    backup_out.as_mut().unwrap(),
+                    &partial_files_hashthread.iter()
+                        .map(|(path, sidecars)| {
+                            (path.as_path(), sidecars.iter().map(PathBuf::as_path).collect())
+                        })
+                        .collect::<Vec<_>>(),
+                    &algos_hashthread,
+                    keep_going,
+                    buffer_size,
+                    &output_progress_hashthread)
+                    .map(|(hashes, failed)| (hashes, vec![], failed)),
+            };
+            match result {
+                Ok(hashes) => hashes,
+                Err(e) => {
+                    panic!("Error reading/writing 'zfs send' pipeline: {}", e);
+                }
+            }
+        });
+
+        // Removed again when this goes out of scope, whether the send succeeds or fails.
+        let status = options.status_file.as_deref().map(StatusFile::new);
+
+        // The progress display is refreshed whenever 'zfs send' reports progress, and also on a
+        // timer, so that it keeps moving even if zfs goes quiet for a while (e.g. because the
+        // pipeline is backed up). Reading stderr blocks, so it's done on another thread.
+        let (line_tx, line_rx) = mpsc::channel();
+        let mut send_stderr = child.stderr.take().unwrap();
+        thread::spawn(move || loop {
+            let result = read_line(&mut send_stderr);
+            let done = !matches!(result, Ok(Some(_)));
+            if line_tx.send(result).is_err() || done {
+                break;
             }
         });
 
         let mut size: u64 = 0;
-        let mut last_line_length: isize = 0;
-        let start_time = Local::now();
+        let mut partial_size: Option<u64> = None;
+        let mut compratio: Option<f64> = None;
+        // Anything else 'zfs send' says is most likely why it failed, so the last few lines of it
+        // are kept to report.
+        let mut stderr_tail = VecDeque::new();
+        let start_time = Instant::now();
+        let send_finished = SendFinishedGuard(output);
         loop {
-            match read_line(child.stderr.as_mut().unwrap()) {
-                Ok(Some(line)) => {
+            // Whether zfs has just reported how much it has sent, rather than the timer going off.
+            let mut reported = false;
+            match line_rx.recv_timeout(options.progress_interval) {
+                Ok(Ok(Some(line))) => {
                     if line.starts_with("incremental\t") || line.starts_with("full\t") {
                         continue;
                     }
                     if line.starts_with("size\t") {
                         size = line.split_at(5).1.parse::<u64>().unwrap();
-                        println!("Full size: {}B", human_number(size, 1));
+                        output(&Event::SendSize(size));
                         if size == 0 {
-                            println!("Empty snapshot; skipping.");
                             break;
                         }
-                    } else {
-                        let parts: Vec<&str> = line.split('\t').collect();
-                        if parts.len() != 3 {
-                            let msg = format!("Unrecognized output: {}", line);
-                            return Err(ZfsError::from(msg));
-                        }
+                        continue;
+                    }
 
-                        let time_parts: Vec<u32> = parts[0].split(':')
-                                                           .filter_map(|x| x.parse::<u32>().ok())
-                                                           .collect();
-                        if time_parts.len() != 3 {
-                            let msg = format!("Unrecognized output: {}", line);
-                            return Err(ZfsError::from(msg));
+                    match parse_send_progress(&line) {
+                        Some(n) => {
+                            partial_size = Some(n);
+                            reported = true;
                         }
-                        let time = Local::now().date_naive()
-                            .and_hms_opt(time_parts[0], time_parts[1], time_parts[2])
-                            .unwrap()
-                            .and_local_timezone(Local)
-                            .unwrap();
-                        let elapsed = time.signed_duration_since(start_time);
-
-                        let partial_size: u64;
-                        if let Ok(n) = parts[1].parse::<u64>() {
-                            partial_size = n;
-                        } else {
-                            let msg = format!("Unrecognized output: {}", line);
-                            return Err(ZfsError::from(msg));
+                        None => {
+                            if stderr_tail.len() == STDERR_TAIL_LINES {
+                                stderr_tail.pop_front();
+                            }
+                            stderr_tail.push_back(line);
+                            continue;
                         }
-
-                        let output_size = output_progress.load(::std::sync::atomic::Ordering::Relaxed);
-                        let compratio: f64 = 100. - (output_size as f64) / (partial_size as f64) * 100.;
-
-                        let percent: f64 = (partial_size as f64) / (size as f64) * 100.;
-                        let outline = format!("{:02}:{:02}:{:02} {:.1}% {}B in {}B out ({:.1}% compressed)",
-                                              elapsed.num_hours(),
-                                              elapsed.num_minutes() % 60,
-                                              elapsed.num_seconds() % 60,
-                                              percent,
-                                              human_number(partial_size, 1),
-                                              human_number(output_size, 1),
-                                              compratio);
-                        let spacing =
-                            cmp::max(0, last_line_length - outline.len() as isize) as usize;
-                        print!("\r{}{}", outline, " ".repeat(spacing));
-                        zfstry!(stdout().flush(), or "failed to flush stdout?!");
-                        last_line_length = outline.len() as isize;
                     }
                 }
-                Ok(None) => break,
-                Err(e) => return Err(ZfsError::from(("error reading from 'zfs send' pipeline", e))),
+                Ok(Ok(None)) | Err(RecvTimeoutError::Disconnected) => break,
+                Ok(Err(e)) => {
+                    return Err(ZfsError::from(("error reading from 'zfs send' pipeline", e)));
+                }
+                Err(RecvTimeoutError::Timeout) => (),
             }
-        }
-        println!();
 
-        if let Err(e) = read_thread.join() {
-            println!("read thread died");
-            let msg: &str = e.downcast_ref::<String>().unwrap().as_str();
-            return Err(ZfsError::from(msg));
+            // Nothing to show until zfs has reported some progress.
+            let partial_size = match partial_size {
+                Some(n) => n,
+                None => continue,
+            };
+
+            // The time zfs prints is the wall-clock time of day, which isn't useful for working
+            // out how long the send has taken if it runs past midnight, so keep our own monotonic
+            // clock instead.
+            let elapsed = start_time.elapsed().as_secs();
+
+            let output_size = output_progress.load(::std::sync::atomic::Ordering::Relaxed);
+            // Early on, data may still be sitting in the compressor's buffers, which makes the
+            // instantaneous ratio jumpy, so smooth it out. It's only worked out when zfs reports
+            // its progress, since between reports the amount sent is out of date.
+            if let Some(ratio) = compression_ratio(partial_size, output_size).filter(|_| reported) {
+                compratio = match compratio {
+                    Some(prev) => Some(prev + RATIO_SMOOTHING * (ratio - prev)),
+                    None => Some(ratio),
+                };
+            }
+
+            let progress = Progress {
+                snapshot,
+                destination: destination_path,
+                total_size: size,
+                sent: partial_size,
+                written: output_size,
+                elapsed_seconds: elapsed,
+            };
+            output(&Event::SendProgress { progress: &progress, compression: compratio });
+
+            if let Some(ref status) = status {
+                if let Err(e) = status.update(&progress) {
+                    output(&Event::Warning(&format!("failed to update status file: {}", e)));
+                }
+            }
         }
+        drop(send_finished);
 
-        let exit_status = child.wait().unwrap();
+        let (output_hashes, chunks, failed) = match read_thread.join() {
+            Ok(hashes) => hashes,
+            Err(e) => {
+                let msg: &str = e.downcast_ref::<String>().unwrap().as_str();
+                return Err(ZfsError::from(msg));
+            }
+        };
+
+        let stream_hash = match tap_thread.map(|t| t.join()) {
+            Some(Ok(Ok(hash))) => Some(hash),
+            Some(Ok(Err(e))) => {
+                return Err(ZfsError::from(format!("Error hashing 'zfs send' stream: {}", e)));
+            }
+            Some(Err(_)) => return Err(ZfsError::from("stream hashing thread died")),
+            None => None,
+        };
+
+        let exit_status = child.wait()?;
         if !exit_status.success() {
-            let code = exit_status.code().unwrap_or(0);
-            return Err(ZfsError::from(format!("'zfs send' returned nonzero exit code: {}", code)));
+            return Err(send_failed(exit_status.code().unwrap_or(0), &stderr_tail));
+        }
+        for line in &stderr_tail {
+            output(&Event::Warning(&format!("'zfs send' said: {}", line)));
+        }
+
+        if let Some(mut filter) = filter_child {
+            let exit_status = zfstry!(filter.wait(), or "failed to wait for filter program");
+            if !exit_status.success() {
+                let code = exit_status.code().unwrap_or(0);
+                return Err(ZfsError::from(
+                    format!("filter program returned nonzero exit code: {}", code)));
+            }
+        }
+
+        // Destinations which failed have already been given up on.
+        for (i, msg) in &failed {
+            output(&Event::Warning(&format!("backup to {:?} failed: {}", destinations[*i], msg)));
+            if let Err(e) = remove_partial_files(destinations[*i]) {
+                output(&Event::Warning(&format!("failed to remove partial files: {}", e)));
+            }
+        }
+        let succeeded: Vec<usize> = (0 .. destinations.len())
+            .filter(|i| !failed.iter().any(|(failed, _)| failed == i))
+            .collect();
+
+        if options.verify_after && size != 0 {
+            let verify = |path: &Path, algos: &[&'static Algorithm], expected: &[String]| {
+                output(&Event::Verifying(path));
+                let hashes = hash_stream::hash_file_from_disk(path, algos, buffer_size)
+                    .map_err(|e| ZfsError::from(format!("failed to verify {:?}: {}", path, e)))?;
+                if hashes != expected {
+                    return Err(ZfsError::verification_failed(format!(
+                        "{:?} doesn't match what was written to it; the disk may be bad", path)));
+                }
+                Ok(())
+            };
+            if chunks.is_empty() {
+                for &i in &succeeded {
+                    verify(&partial_files[i].0, &algos, &output_hashes)?;
+                }
+            }
+            for (partial_chunk_path, hash) in &chunks {
+                verify(partial_chunk_path, &[&SHA256], std::slice::from_ref(hash))?;
+            }
         }
 
         if size == 0 {
-            zfstry!(fs::remove_file(&partial_path), or "failed to remove empty partial file");
+            if chunks.is_empty() {
+                for &i in &succeeded {
+                    zfstry!(fs::remove_file(&partial_files[i].0),
+                        or "failed to remove empty partial file");
+                }
+            }
+            for (chunk_path, _) in &chunks {
+                zfstry!(fs::remove_file(chunk_path), or "failed to remove empty partial chunk");
+            }
         } else {
-            zfstry!(fs::rename(&partial_path, destination_path),
-                or "failed to move partial file to destination");
-            zfstry!(fs::rename(&partial_sidecar_path, &destination_sidecar_path),
-                or "failed to move partial file sidecar to destination");
-            let mut sidecar = zfstry!(
-                fs::OpenOptions::new().append(true).open(&destination_sidecar_path),
-                    or "failed to update hash sidecar (1)");
+            if chunks.is_empty() {
+                for &i in &succeeded {
+                    let (ref partial_path, ref partial_sidecar_paths) = partial_files[i];
+                    zfstry!(fs::rename(partial_path, destinations[i]),
+                        or "failed to move partial file to destination");
+                    let filename = destinations[i].file_name().unwrap();
+                    for ((partial_sidecar_path, suffix), hash)
+                        in partial_sidecar_paths.iter().zip(&sidecar_suffixes).zip(&output_hashes)
+                    {
+                        zfstry!(write_sidecar(&with_suffix(destinations[i], suffix), hash,
+                                              filename, options.metadata_writer.as_ref()),
+                            or "failed to write hash sidecar");
+                        zfstry!(fs::remove_file(partial_sidecar_path),
+                            or "failed to remove partial file sidecar");
+                    }
+                }
+            }
 
-            let mut bytes = b" *".to_vec();
-            bytes.extend_from_slice(destination_path.file_name().unwrap().as_bytes());
-            bytes.extend_from_slice(b"\n");
-            zfstry!(sidecar.write_all(&bytes), or "failed to update hash sidecar (2)");
+            for (partial_chunk_path, hash) in &chunks {
+                let chunk_filename = partial_chunk_path.file_name().unwrap().to_string_lossy();
+                let chunk_filename = chunk_filename.trim_end_matches("_partial");
+                let chunk_path = destination_path.with_file_name(chunk_filename);
+                zfstry!(fs::rename(partial_chunk_path, &chunk_path),
+                    or "failed to move partial chunk to destination");
+                let chunk_sidecar_path =
+                    chunk_path.with_file_name(format!("{}.sha256sum", chunk_filename));
+                zfstry!(write_sidecar(&chunk_sidecar_path, hash, OsStr::new(chunk_filename),
+                                      options.metadata_writer.as_ref()),
+                    or "failed to write chunk hash sidecar");
+            }
+
+            let mut manifest = Manifest::new();
+            manifest.set("snapshot", snapshot);
+            if let Some(start) = incremental {
+                manifest.set("incremental_from", start);
+                if options.all_intermediates {
+                    manifest.set("intermediates", "true");
+                }
+            }
+            if options.use_zfs_compression {
+                manifest.set("zfs_compressed", "true");
+            }
+            if options.large_blocks {
+                manifest.set("large_blocks", "true");
+            }
+            if options.embedded_data {
+                manifest.set("embedded_data", "true");
+            }
+            manifest.set("created", Local::now().to_rfc3339());
+            manifest.set("stream_size", size.to_string());
+            let output_size = output_progress.load(::std::sync::atomic::Ordering::Relaxed);
+            manifest.set("size", output_size.to_string());
+            let mut output_hashes = output_hashes.into_iter();
+            if let Some(hash) = output_hashes.next() {
+                manifest.set("sha256", hash);
+            }
+            if let Some(hash) = output_hashes.next() {
+                manifest.set("sha512", hash);
+            }
+            if let Some(hash) = stream_hash {
+                manifest.set("stream_sha256", hash);
+            }
+            if !chunks.is_empty() {
+                manifest.set("chunks", chunks.len().to_string());
+                manifest.set("chunk_size", split_size.unwrap_or(0).to_string());
+                for (i, (_, hash)) in chunks.iter().enumerate() {
+                    manifest.set(&format!("chunk.{:03}.sha256", i), hash.as_str());
+                }
+            }
+            for (key, value) in &options.manifest_entries {
+                manifest.set(key, value.as_str());
+            }
+            for &i in &succeeded {
+                zfstry!(write_metadata(&manifest::manifest_path(destinations[i]),
+                                       manifest.to_string().as_bytes(),
+                                       options.metadata_writer.as_ref()),
+                    or "failed to write manifest");
+            }
         }
 
-        Ok(())
+        Ok(succeeded.iter().map(|&i| destinations[i].to_path_buf()).collect())
     }
 }
@@ -19,17 +19,53 @@ macro_rules! zfstry {
 pub struct ZfsError {
     pub descr: String,
     pub io_error: Option<Error>,
+    // Only set by `verification_failed`.
+    verification: bool,
 }
 
-impl error::Error for ZfsError {
-    fn description(&self) -> &str {
-        &self.descr
+impl ZfsError {
+    /// An error for when a program zsnapmgr needs isn't installed. The `io_error` has kind
+    /// `NotFound`, so callers can tell this case apart.
+    pub fn missing_tool(tool: &str, package: &str) -> ZfsError {
+        ZfsError {
+            descr: format!("required program '{}' was not found in PATH; install it (usually in \
+                            the '{}' package) and try again", tool, package),
+            io_error: Some(Error::new(ErrorKind::NotFound, format!("{} not found", tool))),
+            verification: false,
+        }
+    }
+
+    /// An error for when a file read back doesn't match what was written to it. Callers can tell
+    /// this case apart with `is_verification_failure`.
+    pub fn verification_failed(descr: String) -> ZfsError {
+        ZfsError {
+            descr,
+            io_error: Some(Error::new(ErrorKind::InvalidData, "verification failed")),
+            verification: true,
+        }
+    }
+
+    /// Whether this error came from `verification_failed`. Other errors never count, even ones
+    /// with an `InvalidData` I/O error, like reading text which isn't UTF-8.
+    pub fn is_verification_failure(&self) -> bool {
+        self.verification
     }
-    fn cause(&self) -> Option<&dyn error::Error> {
+}
+
+impl error::Error for ZfsError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         self.io_error.as_ref().map(|e| e as _)
     }
 }
 
+// ZfsErrors get passed between threads (e.g. out of the send pipeline's helper threads) and
+// wrapped in things like anyhow::Error, which need this.
+#[test]
+fn test_send_sync() {
+    fn assert_send_sync<T: Send + Sync + 'static>() {}
+    assert_send_sync::<ZfsError>();
+}
+
 impl fmt::Debug for ZfsError {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         if self.io_error.is_some() {
@@ -58,6 +94,7 @@ impl From<(String, Error)> for ZfsError {
         ZfsError {
             descr: args.0,
             io_error: Some(args.1),
+            verification: false,
         }
     }
 }
@@ -67,6 +104,7 @@ impl<'a> From<(&'a str, Error)> for ZfsError {
         ZfsError {
             descr: String::from(args.0),
             io_error: Some(args.1),
+            verification: false,
         }
     }
 }
@@ -77,6 +115,19 @@ impl<'a, 'b> From<(&'a str, &'b Vec<u8>)> for ZfsError {
             descr: String::from(args.0),
             io_error: Some(Error::new(ErrorKind::Other,
                                       (*String::from_utf8_lossy(args.1)).to_owned())),
+            verification: false,
+        }
+    }
+}
+
+/// For when there's nothing more useful to say than what the I/O error itself says. Use `zfstry!`
+/// to give a more specific description.
+impl From<Error> for ZfsError {
+    fn from(e: Error) -> ZfsError {
+        ZfsError {
+            descr: String::from("I/O error"),
+            io_error: Some(e),
+            verification: false,
         }
     }
 }
@@ -86,6 +137,7 @@ impl<'a> From<&'a str> for ZfsError {
         ZfsError {
             descr: String::from(descr),
             io_error: None,
+            verification: false,
         }
     }
 }
@@ -95,6 +147,7 @@ impl From<String> for ZfsError {
         ZfsError {
             descr,
             io_error: None,
+            verification: false,
         }
     }
 }
@@ -102,10 +155,15 @@ impl From<String> for ZfsError {
 impl From<libzfs::Error> for ZfsError {
     fn from(e: libzfs::Error) -> ZfsError {
         match e {
-            libzfs::Error::Sys(e) => ZfsError { io_error: Some(e), descr: "?".to_owned() }, // FIXME
+            libzfs::Error::Sys(e) => ZfsError {
+                io_error: Some(e),
+                descr: "?".to_owned(), // FIXME
+                verification: false,
+            },
             libzfs::Error::Zfs(zfs_error) => ZfsError {
                 descr: zfs_error.to_string(),
                 io_error: None,
+                verification: false,
             }
         }
     }